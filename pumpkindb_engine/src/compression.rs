@@ -0,0 +1,135 @@
+// Copyright (c) 2017, All Contributors (see CONTRIBUTORS file)
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! # Value compression
+//!
+//! Compressed values start with a header (`0xC0 'P' 'Z'` followed by the compression
+//! method) followed by the compressed data in zlib format. Values are only decompressed
+//! when read if [`Rules`](struct.Rules.html) apply to their keys, and within these
+//! keys values that start with the header are always stored compressed, so any other
+//! value can never be mistaken for a compressed one.
+//!
+//! A small compressed value can decompress into a huge one, so decompression
+//! is always limited (see [`MAX_SIZE`](constant.MAX_SIZE.html)).
+//!
+
+use std::io::{Read, Write};
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+use flate2::read::ZlibDecoder;
+
+/// Beginning of the header of a compressed value
+pub const HEADER: &'static [u8] = b"\xC0PZ";
+
+// Compression methods
+const ZLIB: u8 = 0x01;
+
+/// Maximum size of a decompressed value (64Mb)
+pub const MAX_SIZE: usize = 64 * 1024 * 1024;
+
+/// The value decompresses into more bytes than allowed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TooLarge;
+
+/// Compresses the value, prepending the header
+pub fn compress(value: &[u8]) -> Vec<u8> {
+    let mut compressed = Vec::from(HEADER);
+    compressed.push(ZLIB);
+    let mut encoder = ZlibEncoder::new(compressed, Compression::Default);
+    // writing into a vector never fails
+    encoder.write_all(value).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Compresses the value if that makes it any smaller
+pub fn compress_smaller(value: &[u8]) -> Option<Vec<u8>> {
+    let compressed = compress(value);
+    if compressed.len() < value.len() {
+        Some(compressed)
+    } else {
+        None
+    }
+}
+
+/// Decompresses the value, up to `limit` bytes. Returns `None` if it is not
+/// a compressed value and `TooLarge` if it decompresses into more than `limit` bytes.
+pub fn decompress(value: &[u8], limit: usize) -> Option<Result<Vec<u8>, TooLarge>> {
+    if value.len() <= HEADER.len() || !value.starts_with(HEADER) || value[HEADER.len()] != ZLIB {
+        return None;
+    }
+    // one byte past the limit is enough to tell that it's exceeded
+    let mut decoder = ZlibDecoder::new(&value[HEADER.len() + 1..]).take(limit as u64 + 1);
+    let mut decompressed = Vec::new();
+    match decoder.read_to_end(&mut decompressed) {
+        Ok(_) if decompressed.len() > limit => Some(Err(TooLarge)),
+        Ok(_) => Some(Ok(decompressed)),
+        Err(_) => None,
+    }
+}
+
+/// Selects values to be compressed transparently when they are written
+#[derive(Debug, Default, Clone)]
+pub struct Rules {
+    /// Names of keyspaces all values of which are compressed
+    pub keyspaces: Vec<Vec<u8>>,
+    /// Key prefixes (in any keyspace) values of which are compressed
+    pub prefixes: Vec<Vec<u8>>,
+}
+
+impl Rules {
+    /// Returns `true` if the value of the key in the keyspace
+    /// (empty for the default keyspace) should be compressed
+    pub fn applies(&self, keyspace: &[u8], key: &[u8]) -> bool {
+        self.keyspaces.iter().any(|name| name.as_slice() == keyspace) ||
+        self.prefixes.iter().any(|prefix| key.starts_with(prefix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let value = "{\"event\": \"created\", \"payload\": \"created\"}".repeat(10);
+        let compressed = compress(value.as_bytes());
+        assert!(compressed.starts_with(HEADER));
+        assert!(compressed.len() < value.len());
+        assert_eq!(decompress(&compressed, MAX_SIZE).unwrap().unwrap(), value.as_bytes());
+        assert_eq!(compress_smaller(value.as_bytes()).unwrap(), compressed);
+    }
+
+    #[test]
+    fn uncompressed() {
+        assert!(decompress(b"value", MAX_SIZE).is_none());
+        assert!(decompress(HEADER, MAX_SIZE).is_none());
+        assert!(decompress(b"\xC0PZ\x01not zlib", MAX_SIZE).is_none());
+        assert!(decompress(b"\xC0PZ\x02", MAX_SIZE).is_none());
+        assert!(compress_smaller(b"a").is_none());
+    }
+
+    #[test]
+    fn limit() {
+        let value = vec![0u8; 1024 * 1024];
+        let compressed = compress(&value);
+        assert!(compressed.len() < 2048);
+        assert_eq!(decompress(&compressed, value.len()).unwrap().unwrap(), value);
+        assert_eq!(decompress(&compressed, value.len() - 1).unwrap(), Err(TooLarge));
+    }
+
+    #[test]
+    fn rules() {
+        let rules = Rules {
+            keyspaces: vec![Vec::from("events")],
+            prefixes: vec![Vec::from("user/")],
+        };
+        assert!(rules.applies(b"events", b"key"));
+        assert!(rules.applies(b"", b"user/1"));
+        assert!(rules.applies(b"other", b"user/1"));
+        assert!(!rules.applies(b"", b"key"));
+        assert!(!Rules::default().applies(b"", b""));
+    }
+}
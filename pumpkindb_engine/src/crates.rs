@@ -36,6 +36,8 @@ extern crate config;
 extern crate lazy_static;
 
 extern crate crypto;
+extern crate ring;
+extern crate lru_cache;
 
 extern crate serde_json;
 extern crate serde_cbor;
@@ -47,6 +49,8 @@ extern crate log;
 
 extern crate memmap;
 
+extern crate flate2;
+
 #[macro_use]
 extern crate pumpkinscript;
 
@@ -0,0 +1,324 @@
+// Copyright (c) 2017, All Contributors (see CONTRIBUTORS file)
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! # Export and import
+//!
+//! Streams key/value pairs of a keyspace to and from a portable file.
+//!
+//! The file starts with [`MAGIC`](constant.MAGIC.html), followed by key/value
+//! pairs, each of them encoded the same way PumpkinScript encodes data in its
+//! binary form (a size header followed by the bytes). Pairs are written in the
+//! key order and the file ends after the last value.
+//!
+//! Values are exported as they are stored, so compressed values stay compressed
+//! and encrypted values can only be read with the key store they were encrypted with.
+//! Imported pairs are written directly into the keyspace, indexes are not updated
+//! and no changes are published.
+//!
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt};
+use lmdb;
+use lmdb::traits::LmdbResultExt;
+
+use pumpkinscript::Encodable;
+
+/// File header (format version 1)
+pub const MAGIC: &'static [u8] = b"PUMPKINDB-KV\x01";
+
+/// Default number of pairs to import in one write transaction
+pub const DEFAULT_BATCH_SIZE: usize = 10000;
+
+#[derive(Debug)]
+pub enum Error {
+    Storage(lmdb::Error),
+    Io(io::Error),
+    /// The file is not an export file or is truncated
+    Format(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Error::Storage(ref err) => err.fmt(f),
+            &Error::Io(ref err) => err.fmt(f),
+            &Error::Format(ref err) => write!(f, "invalid export file: {}", err),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        match self {
+            &Error::Storage(ref err) => err.description(),
+            &Error::Io(ref err) => err.description(),
+            &Error::Format(_) => "invalid export file",
+        }
+    }
+}
+
+impl From<lmdb::Error> for Error {
+    fn from(err: lmdb::Error) -> Self {
+        Error::Storage(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+/// Keys to export. All conditions are optional, everything
+/// is exported by default.
+#[derive(Debug, Default, Clone)]
+pub struct Range {
+    /// Only keys starting with the prefix
+    pub prefix: Option<Vec<u8>>,
+    /// Only keys greater than or equal to this one
+    pub from: Option<Vec<u8>>,
+    /// Only keys less than this one
+    pub to: Option<Vec<u8>>,
+}
+
+impl Range {
+    // Key to position the cursor at
+    fn start(&self) -> Option<&[u8]> {
+        match (self.prefix.as_ref(), self.from.as_ref()) {
+            (Some(prefix), Some(from)) => Some(if from > prefix { from } else { prefix }),
+            (Some(prefix), None) => Some(prefix),
+            (None, Some(from)) => Some(from),
+            (None, None) => None,
+        }
+    }
+
+    // Returns `false` once the key is past the end of the range
+    fn includes(&self, key: &[u8]) -> bool {
+        if let Some(ref prefix) = self.prefix {
+            if !key.starts_with(prefix) {
+                return false;
+            }
+        }
+        if let Some(ref to) = self.to {
+            if key >= &to[..] {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    /// Number of pairs written
+    pub imported: usize,
+    /// Keys that already existed and were left untouched
+    pub duplicates: Vec<Vec<u8>>,
+}
+
+impl fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "Imported: {}", self.imported));
+        try!(writeln!(f, "Duplicate keys: {}", self.duplicates.len()));
+        for key in self.duplicates.iter() {
+            try!(writeln!(f, " * {:?}", key));
+        }
+        Ok(())
+    }
+}
+
+/// Writes the pairs of the keyspace `db` within `range` into `writer`
+/// from a single read transaction. Returns the number of pairs written.
+pub fn export<W: Write>(env: &lmdb::Environment, db: &lmdb::Database, range: &Range, writer: &mut W)
+                        -> Result<usize, Error> {
+    let txn = try!(lmdb::ReadTransaction::new(env));
+    let access = txn.access();
+    let mut cursor = try!(txn.cursor(db));
+    try!(writer.write_all(MAGIC));
+    let mut count = 0;
+    let mut item = try!(match range.start() {
+        Some(key) => cursor.seek_range_k::<[u8], [u8]>(&access, key),
+        None => cursor.first::<[u8], [u8]>(&access),
+    }.to_opt());
+    while let Some((key, value)) = item {
+        if !range.includes(key) {
+            break;
+        }
+        try!(writer.write_all(&key.encode()));
+        try!(writer.write_all(&value.encode()));
+        count += 1;
+        item = try!(cursor.next::<[u8], [u8]>(&access).to_opt());
+    }
+    try!(writer.flush());
+    Ok(count)
+}
+
+/// Reads pairs from `reader` and writes them into the keyspace `db`, committing
+/// every `batch_size` pairs. Keys that already exist are not overwritten
+/// (`NOOVERWRITE`), they are listed in the report instead.
+///
+/// If an error occurs, pairs of the batches committed before it stay imported.
+pub fn import<R: Read>(env: &lmdb::Environment, db: &lmdb::Database, reader: &mut R, batch_size: usize)
+                       -> Result<ImportReport, Error> {
+    let mut magic = vec![0; MAGIC.len()];
+    try!(reader.read_exact(&mut magic).map_err(|_| Error::Format(String::from("no header"))));
+    if magic != MAGIC {
+        return Err(Error::Format(String::from("unknown header")));
+    }
+    let mut report = ImportReport::default();
+    let mut done = false;
+    while !done {
+        let txn = try!(lmdb::WriteTransaction::new(env));
+        {
+            let mut access = txn.access();
+            for _ in 0..batch_size {
+                let key = match try!(read_data(reader)) {
+                    Some(key) => key,
+                    None => {
+                        done = true;
+                        break;
+                    }
+                };
+                let value = match try!(read_data(reader)) {
+                    Some(value) => value,
+                    None => return Err(Error::Format(String::from("missing value of the last key"))),
+                };
+                match access.put(db, &key[..], &value[..], lmdb::put::NOOVERWRITE) {
+                    Ok(()) => report.imported += 1,
+                    Err(lmdb::Error::Code(code)) if code == lmdb::error::KEYEXIST =>
+                        report.duplicates.push(key),
+                    Err(err) => return Err(Error::Storage(err)),
+                }
+            }
+        }
+        try!(txn.commit());
+    }
+    Ok(report)
+}
+
+/// Reads one piece of data in PumpkinScript binary encoding.
+/// Returns `None` if the reader is at its end.
+pub fn read_data<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>, Error> {
+    let mut header = [0u8; 1];
+    loop {
+        match reader.read(&mut header) {
+            Ok(0) => return Ok(None),
+            Ok(_) => break,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(Error::Io(err)),
+        }
+    }
+    let size = match header[0] {
+        size @ 0...120 => Ok(size as usize),
+        121 => reader.read_u8().map(|size| size as usize),
+        122 => reader.read_u16::<BigEndian>().map(|size| size as usize),
+        123 => reader.read_u32::<BigEndian>().map(|size| size as usize),
+        tag => return Err(Error::Format(format!("unexpected tag {}", tag))),
+    };
+    let size = try!(size.map_err(truncated));
+    let mut data = vec![0; size];
+    try!(reader.read_exact(&mut data).map_err(truncated));
+    Ok(Some(data))
+}
+
+fn truncated(err: io::Error) -> Error {
+    match err.kind() {
+        io::ErrorKind::UnexpectedEof => Error::Format(String::from("truncated")),
+        _ => Error::Io(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use tempdir::TempDir;
+    use lmdb;
+
+    use super::*;
+
+    fn environment(dir: &TempDir) -> lmdb::Environment {
+        unsafe {
+            lmdb::EnvBuilder::new().expect("can't create env builder")
+                .open(dir.path().to_str().unwrap(), lmdb::open::NOTLS, 0o600).expect("can't open env")
+        }
+    }
+
+    fn put(env: &lmdb::Environment, db: &lmdb::Database, pairs: &[(&[u8], &[u8])]) {
+        let txn = lmdb::WriteTransaction::new(env).unwrap();
+        {
+            let mut access = txn.access();
+            for &(key, value) in pairs {
+                access.put(db, key, value, lmdb::put::Flags::empty()).unwrap();
+            }
+        }
+        txn.commit().unwrap();
+    }
+
+    fn get(env: &lmdb::Environment, db: &lmdb::Database, key: &[u8]) -> Option<Vec<u8>> {
+        let txn = lmdb::ReadTransaction::new(env).unwrap();
+        let access = txn.access();
+        access.get::<[u8], [u8]>(db, key).to_opt().unwrap().map(Vec::from)
+    }
+
+    #[test]
+    fn roundtrip() {
+        let source_dir = TempDir::new("pumpkindb").unwrap();
+        let source = environment(&source_dir);
+        let source_db = lmdb::Database::open(&source, None, &lmdb::DatabaseOptions::defaults()).unwrap();
+        let large = vec![1u8; 70000];
+        put(&source, &source_db, &[(b"a", b"1"), (b"b", &large[..]), (b"c", b"")]);
+
+        let mut file = Vec::new();
+        assert_eq!(export(&source, &source_db, &Range::default(), &mut file).unwrap(), 3);
+
+        let target_dir = TempDir::new("pumpkindb").unwrap();
+        let target = environment(&target_dir);
+        let target_db = lmdb::Database::open(&target, None, &lmdb::DatabaseOptions::defaults()).unwrap();
+        put(&target, &target_db, &[(b"a", b"existing")]);
+        let report = import(&target, &target_db, &mut Cursor::new(file), 2).unwrap();
+        assert_eq!(report.imported, 2);
+        assert_eq!(report.duplicates, vec![Vec::from(&b"a"[..])]);
+        assert_eq!(get(&target, &target_db, b"a"), Some(Vec::from(&b"existing"[..])));
+        assert_eq!(get(&target, &target_db, b"b"), Some(large));
+        assert_eq!(get(&target, &target_db, b"c"), Some(Vec::new()));
+    }
+
+    #[test]
+    fn range() {
+        let dir = TempDir::new("pumpkindb").unwrap();
+        let env = environment(&dir);
+        let db = lmdb::Database::open(&env, None, &lmdb::DatabaseOptions::defaults()).unwrap();
+        put(&env, &db, &[(b"a/1", b"1"), (b"b/1", b"2"), (b"b/2", b"3"), (b"b/3", b"4"), (b"c/1", b"5")]);
+
+        let mut file = Vec::new();
+        let range = Range { prefix: Some(Vec::from(&b"b/"[..])), ..Range::default() };
+        assert_eq!(export(&env, &db, &range, &mut file).unwrap(), 3);
+
+        let mut file = Vec::new();
+        let range = Range { from: Some(Vec::from(&b"b/2"[..])), to: Some(Vec::from(&b"c/1"[..])),
+                            ..Range::default() };
+        assert_eq!(export(&env, &db, &range, &mut file).unwrap(), 2);
+        let mut reader = Cursor::new(&file[MAGIC.len()..]);
+        assert_eq!(read_data(&mut reader).unwrap(), Some(Vec::from(&b"b/2"[..])));
+    }
+
+    #[test]
+    fn invalid() {
+        let dir = TempDir::new("pumpkindb").unwrap();
+        let env = environment(&dir);
+        let db = lmdb::Database::open(&env, None, &lmdb::DatabaseOptions::defaults()).unwrap();
+        assert_matches!(import(&env, &db, &mut Cursor::new(b"not an export"), 10), Err(Error::Format(_)));
+        let mut file = Vec::from(MAGIC);
+        file.extend_from_slice(b"\x01a");
+        assert_matches!(import(&env, &db, &mut Cursor::new(file), 10), Err(Error::Format(_)));
+        let mut file = Vec::from(MAGIC);
+        file.extend_from_slice(b"\x01a\x05bc");
+        assert_matches!(import(&env, &db, &mut Cursor::new(file), 10), Err(Error::Format(_)));
+    }
+}
@@ -0,0 +1,546 @@
+// Copyright (c) 2017, All Contributors (see CONTRIBUTORS file)
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! # Value encryption
+//!
+//! Values are encrypted with AES-256-GCM using a key of the stream they belong to.
+//! A stream is identified by a key prefix, typically one of an aggregate (such as
+//! `user/1`). Stream keys are kept in a [`KeyStore`] separate from the database, so
+//! destroying a stream's key (crypto-shredding) makes all of the stream's values
+//! unreadable even though they can't be removed from the database itself.
+//!
+//! Encrypted values start with a header (`0xC0 'P' 'E'` followed by the encryption
+//! method), the length of the stream identifier and the identifier itself, followed
+//! by the nonce, the ciphertext and the authentication tag. The header and the stream
+//! identifier are authenticated along with the ciphertext. Values are only decrypted
+//! when read if their keys belong to a stream (see [`Encryption`]) and only if they
+//! are encrypted for that very stream.
+//!
+//! [`KeyStore`]: struct.KeyStore.html
+//! [`Encryption`]: struct.Encryption.html
+//!
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use lru_cache::LruCache;
+use rand::{Rng, OsRng};
+use ring::aead;
+
+/// Beginning of the header of an encrypted value
+pub const HEADER: &'static [u8] = b"\xC0PE";
+
+/// Maximum length of a stream identifier
+pub const MAX_STREAM_LEN: usize = 255;
+
+// Encryption methods
+const AES256_GCM: u8 = 0x01;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Number of stream keys kept in memory
+pub const KEY_CACHE_SIZE: usize = 1024;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The stream's key has been destroyed,
+    /// contains the stream identifier
+    Shredded(Vec<u8>),
+    /// The stream has no key (it either never had one or it
+    /// has been lost), contains the stream identifier
+    MissingKey(Vec<u8>),
+    /// The value can't be authenticated with the stream's key,
+    /// contains the stream identifier
+    Authentication(Vec<u8>),
+    /// The stream identifier is empty or too long
+    InvalidStream,
+    /// Key store I/O error
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Error::Io(ref err) => write!(f, "key store error: {}", err),
+            _ => write!(f, "{}", self.description()),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        match self {
+            &Error::Shredded(_) => "stream has been shredded",
+            &Error::MissingKey(_) => "stream key is missing",
+            &Error::Authentication(_) => "value can't be authenticated",
+            &Error::InvalidStream => "invalid stream identifier",
+            &Error::Io(ref err) => err.description(),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+/// Whether a stream has a key
+#[derive(Debug, PartialEq)]
+pub enum KeyState {
+    Present,
+    Shredded,
+    /// The stream either never had a key or it has been lost,
+    /// which the key store alone can't tell apart
+    Missing,
+}
+
+/// Stream keys stored in a directory, one file per stream
+///
+/// Files are named after the SHA-256 hash of the stream identifier. Shredding
+/// a stream leaves a tombstone (a `.shredded` file) in place of its key, so that
+/// a shredded stream is told apart from one without a key and can't be written
+/// to again.
+///
+/// A new key is pending (kept in a `.pending` file) until it is either committed
+/// or discarded, along with the transaction that recorded its stream. Pending keys
+/// are used just like committed ones, so a key left pending by a crash is not lost.
+/// Up to [`KEY_CACHE_SIZE`](constant.KEY_CACHE_SIZE.html) recently used keys
+/// are kept in memory.
+pub struct KeyStore {
+    path: PathBuf,
+    // `None` if the stream has been shredded
+    keys: Mutex<LruCache<Vec<u8>, Option<Vec<u8>>>>,
+}
+
+impl KeyStore {
+    /// Opens a key store at `path`, creating the directory if necessary
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        try!(fs::create_dir_all(&path));
+        Ok(KeyStore::open_existing(path))
+    }
+
+    /// Opens a key store at `path` without creating the directory, so that
+    /// a missing key store is seen as one without any keys
+    pub fn open_existing<P: Into<PathBuf>>(path: P) -> Self {
+        KeyStore {
+            path: path.into(),
+            keys: Mutex::new(LruCache::new(KEY_CACHE_SIZE)),
+        }
+    }
+
+    fn key_path(&self, stream: &[u8]) -> PathBuf {
+        let mut sha = Sha256::new();
+        sha.input(stream);
+        self.path.join(sha.result_str())
+    }
+
+    fn tombstone_path(&self, stream: &[u8]) -> PathBuf {
+        self.key_path(stream).with_extension("shredded")
+    }
+
+    fn pending_path(&self, stream: &[u8]) -> PathBuf {
+        self.key_path(stream).with_extension("pending")
+    }
+
+    // Reads the key (committed or pending) from the key store. Returns `Ok(Some(None))`
+    // if the stream has been shredded and `Ok(None)` if there's no key.
+    fn load(&self, stream: &[u8]) -> io::Result<Option<Option<Vec<u8>>>> {
+        if self.tombstone_path(stream).exists() {
+            return Ok(Some(None));
+        }
+        for path in [self.key_path(stream), self.pending_path(stream)].iter() {
+            match File::open(path) {
+                Ok(mut file) => {
+                    let mut key = Vec::new();
+                    try!(file.read_to_end(&mut key));
+                    return if key.len() == KEY_LEN {
+                        Ok(Some(Some(key)))
+                    } else {
+                        Err(io::Error::new(io::ErrorKind::InvalidData, "malformed stream key"))
+                    };
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the stream's key, creating a pending one if the stream doesn't
+    /// have one and `create` is `true`
+    ///
+    /// A key must only be created for a stream that has never had one:
+    /// values encrypted with a lost key won't become readable with a new one.
+    pub fn key(&self, stream: &[u8], create: bool) -> Result<Vec<u8>, Error> {
+        if stream.len() == 0 || stream.len() > MAX_STREAM_LEN {
+            return Err(Error::InvalidStream);
+        }
+        let mut keys = self.keys.lock().unwrap();
+        match keys.get_mut(stream) {
+            Some(&mut Some(ref key)) => return Ok(key.clone()),
+            Some(&mut None) => return Err(Error::Shredded(Vec::from(stream))),
+            None => (),
+        }
+        let key = match try!(self.load(stream)) {
+            Some(key) => key,
+            None if create => {
+                let mut key = vec![0; KEY_LEN];
+                try!(OsRng::new()).fill_bytes(&mut key);
+                // the key is written to a temporary file first, so that
+                // a partially written key is never mistaken for a valid one
+                let tmp_path = self.key_path(stream).with_extension("tmp");
+                {
+                    let mut file = try!(File::create(&tmp_path));
+                    try!(file.write_all(&key));
+                    try!(file.sync_all());
+                }
+                try!(fs::rename(&tmp_path, self.pending_path(stream)));
+                Some(key)
+            }
+            None => return Err(Error::MissingKey(Vec::from(stream))),
+        };
+        keys.insert(Vec::from(stream), key.clone());
+        match key {
+            Some(key) => Ok(key),
+            None => Err(Error::Shredded(Vec::from(stream))),
+        }
+    }
+
+    /// Commits the stream's pending key, if there's one
+    pub fn commit(&self, stream: &[u8]) -> io::Result<()> {
+        match fs::rename(self.pending_path(stream), self.key_path(stream)) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Discards the stream's pending key, if there's one
+    pub fn discard(&self, stream: &[u8]) -> io::Result<()> {
+        let mut keys = self.keys.lock().unwrap();
+        match fs::remove_file(self.pending_path(stream)) {
+            Ok(()) => {
+                keys.remove(stream);
+                Ok(())
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Returns whether the stream has a key
+    pub fn state(&self, stream: &[u8]) -> io::Result<KeyState> {
+        match self.keys.lock().unwrap().get_mut(stream) {
+            Some(&mut Some(_)) => return Ok(KeyState::Present),
+            Some(&mut None) => return Ok(KeyState::Shredded),
+            None => (),
+        }
+        Ok(match try!(self.load(stream)) {
+            Some(Some(_)) => KeyState::Present,
+            Some(None) => KeyState::Shredded,
+            None => KeyState::Missing,
+        })
+    }
+
+    /// Destroys the stream's key. This can't be undone.
+    pub fn shred(&self, stream: &[u8]) -> Result<(), Error> {
+        if stream.len() == 0 || stream.len() > MAX_STREAM_LEN {
+            return Err(Error::InvalidStream);
+        }
+        let mut keys = self.keys.lock().unwrap();
+        // the tombstone is in place before the key is gone,
+        // so the stream can never end up without either
+        {
+            let tombstone = try!(File::create(self.tombstone_path(stream)));
+            try!(tombstone.sync_all());
+        }
+        for path in [self.key_path(stream), self.pending_path(stream)].iter() {
+            match OpenOptions::new().write(true).open(path) {
+                Ok(mut file) => {
+                    try!(file.write_all(&[0; KEY_LEN]));
+                    try!(file.sync_all());
+                    drop(file);
+                    try!(fs::remove_file(path));
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+                Err(err) => return Err(Error::Io(err)),
+            }
+        }
+        keys.insert(Vec::from(stream), None);
+        Ok(())
+    }
+
+    /// Encrypts the value with the stream's key, creating it if the stream
+    /// doesn't have one and `create` is `true` (see [`key`](#method.key))
+    pub fn encrypt(&self, stream: &[u8], value: &[u8], create: bool) -> Result<Vec<u8>, Error> {
+        let key = try!(self.key(stream, create));
+        let mut nonce = [0; NONCE_LEN];
+        try!(OsRng::new()).fill_bytes(&mut nonce);
+
+        let mut encrypted = Vec::with_capacity(HEADER.len() + 2 + stream.len() +
+                                               NONCE_LEN + value.len() + TAG_LEN);
+        encrypted.extend_from_slice(HEADER);
+        encrypted.push(AES256_GCM);
+        encrypted.push(stream.len() as u8);
+        encrypted.extend_from_slice(stream);
+        let aad_len = encrypted.len();
+        encrypted.extend_from_slice(&nonce);
+        let ciphertext_offset = encrypted.len();
+        encrypted.extend_from_slice(value);
+        encrypted.extend_from_slice(&[0; TAG_LEN]);
+
+        // keys are always KEY_LEN bytes long
+        let key = aead::SealingKey::new(&aead::AES_256_GCM, &key).unwrap();
+        let (aad, in_out) = encrypted.split_at_mut(ciphertext_offset);
+        // sealing fails only if there's no room for the tag
+        aead::seal_in_place(&key, &nonce, &aad[0..aad_len], in_out, TAG_LEN).unwrap();
+        Ok(encrypted)
+    }
+
+    /// Decrypts the value. Returns `None` if it is not an encrypted value.
+    ///
+    /// If the stream has been shredded, `Error::Shredded` is returned; if its key
+    /// is missing (since the value exists, it has been lost), `Error::MissingKey`;
+    /// if the value can't be authenticated with the key, `Error::Authentication`.
+    pub fn decrypt(&self, value: &[u8]) -> Option<Result<Vec<u8>, Error>> {
+        let stream = match stream(value) {
+            Some(stream) => stream,
+            None => return None,
+        };
+        let aad_len = HEADER.len() + 2 + stream.len();
+        if value.len() < aad_len + NONCE_LEN + TAG_LEN {
+            return None;
+        }
+        let key = match self.key(stream, false) {
+            Ok(key) => key,
+            Err(err) => return Some(Err(err)),
+        };
+        let (aad, rest) = value.split_at(aad_len);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        // keys are always KEY_LEN bytes long
+        let key = aead::OpeningKey::new(&aead::AES_256_GCM, &key).unwrap();
+        let mut decrypted = Vec::from(ciphertext);
+        let len = match aead::open_in_place(&key, nonce, aad, 0, &mut decrypted) {
+            Ok(plaintext) => plaintext.len(),
+            Err(_) => return Some(Err(Error::Authentication(Vec::from(stream)))),
+        };
+        decrypted.truncate(len);
+        Some(Ok(decrypted))
+    }
+}
+
+/// Returns the identifier of the stream the value is encrypted for,
+/// or `None` if it is not an encrypted value
+pub fn stream(value: &[u8]) -> Option<&[u8]> {
+    if value.len() < HEADER.len() + 2 || !value.starts_with(HEADER) ||
+       value[HEADER.len()] != AES256_GCM {
+        return None;
+    }
+    let len = value[HEADER.len() + 1] as usize;
+    let offset = HEADER.len() + 2;
+    if len == 0 || value.len() < offset + len {
+        return None;
+    }
+    Some(&value[offset..offset + len])
+}
+
+/// Encryption at rest
+pub struct Encryption {
+    /// Key prefixes values under which are encrypted (in any keyspace)
+    pub prefixes: Vec<Vec<u8>>,
+    /// Delimiter ending a stream identifier after the prefix, so that every
+    /// aggregate gets a key of its own: with the `user/` prefix, `user/1/name`
+    /// belongs to the `user/1` stream. Without it, the prefix is the stream.
+    pub delimiter: Option<u8>,
+    pub keys: KeyStore,
+}
+
+impl Encryption {
+    pub fn new(keys: KeyStore) -> Self {
+        Encryption {
+            prefixes: Vec::new(),
+            delimiter: None,
+            keys: keys,
+        }
+    }
+
+    /// Returns the stream the key belongs to, if any: the longest
+    /// matching prefix followed by whatever precedes the delimiter
+    pub fn stream<'k>(&self, key: &'k [u8]) -> Option<&'k [u8]> {
+        let prefix = match self.prefixes.iter()
+            .filter(|prefix| key.starts_with(prefix))
+            .max_by_key(|prefix| prefix.len()) {
+            Some(prefix) => prefix,
+            None => return None,
+        };
+        match self.delimiter {
+            Some(delimiter) => {
+                let len = key[prefix.len()..].iter().position(|&b| b == delimiter)
+                    .map_or(key.len(), |pos| prefix.len() + pos);
+                Some(&key[0..len])
+            },
+            None => Some(&key[0..prefix.len()]),
+        }
+    }
+
+    /// Returns `true` if `stream` is one of the configured streams
+    pub fn is_stream(&self, stream: &[u8]) -> bool {
+        self.stream(stream) == Some(stream)
+    }
+
+    /// Decrypts the value of the key. Returns `None` if the key doesn't belong
+    /// to a stream or the value is not encrypted, and `Error::Authentication`
+    /// if the value is encrypted for a stream other than the key's one.
+    pub fn decrypt(&self, key: &[u8], value: &[u8]) -> Option<Result<Vec<u8>, Error>> {
+        let expected = match self.stream(key) {
+            Some(expected) => expected,
+            None => return None,
+        };
+        match stream(value) {
+            Some(stream) if stream != expected => Some(Err(Error::Authentication(Vec::from(expected)))),
+            Some(_) => self.keys.decrypt(value),
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use tempdir::TempDir;
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let dir = TempDir::new("pumpkindb").unwrap();
+        let keys = KeyStore::open(dir.path()).unwrap();
+        assert_matches!(keys.encrypt(b"user/1", b"value", false), Err(Error::MissingKey(_)));
+        let encrypted = keys.encrypt(b"user/1", b"value", true).unwrap();
+        assert!(encrypted.starts_with(HEADER));
+        assert_eq!(stream(&encrypted), Some(&b"user/1"[..]));
+        assert_eq!(keys.decrypt(&encrypted).unwrap().unwrap(), b"value");
+        // the key is persisted
+        let keys = KeyStore::open(dir.path()).unwrap();
+        assert_eq!(keys.decrypt(&encrypted).unwrap().unwrap(), b"value");
+    }
+
+    #[test]
+    fn unencrypted() {
+        let dir = TempDir::new("pumpkindb").unwrap();
+        let keys = KeyStore::open(dir.path()).unwrap();
+        assert!(keys.decrypt(b"value").is_none());
+        assert!(keys.decrypt(HEADER).is_none());
+        assert!(keys.decrypt(b"\xC0PE\x01\x01u").is_none());
+        assert!(keys.encrypt(b"", b"value", true).is_err());
+    }
+
+    #[test]
+    fn tampered() {
+        let dir = TempDir::new("pumpkindb").unwrap();
+        let keys = KeyStore::open(dir.path()).unwrap();
+        let mut encrypted = keys.encrypt(b"user/1", b"value", true).unwrap();
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 1;
+        assert_matches!(keys.decrypt(&encrypted), Some(Err(Error::Authentication(_))));
+    }
+
+    #[test]
+    fn shred() {
+        let dir = TempDir::new("pumpkindb").unwrap();
+        let keys = KeyStore::open(dir.path()).unwrap();
+        let encrypted = keys.encrypt(b"user/1", b"value", true).unwrap();
+        let other = keys.encrypt(b"user/2", b"value", true).unwrap();
+        keys.shred(b"user/1").unwrap();
+        assert_matches!(keys.decrypt(&encrypted), Some(Err(Error::Shredded(_))));
+        assert_matches!(keys.encrypt(b"user/1", b"value", true), Err(Error::Shredded(_)));
+        assert_eq!(keys.decrypt(&other).unwrap().unwrap(), b"value");
+        // shredding survives reopening
+        let keys = KeyStore::open(dir.path()).unwrap();
+        assert_eq!(keys.state(b"user/1").unwrap(), KeyState::Shredded);
+        assert_matches!(keys.decrypt(&encrypted), Some(Err(Error::Shredded(_))));
+        assert_matches!(keys.encrypt(b"user/1", b"value", true), Err(Error::Shredded(_)));
+        // streams without a key can be shredded too
+        keys.shred(b"user/3").unwrap();
+        assert_eq!(keys.state(b"user/3").unwrap(), KeyState::Shredded);
+    }
+
+    #[test]
+    fn lost_key() {
+        let dir = TempDir::new("pumpkindb").unwrap();
+        let keys = KeyStore::open(dir.path()).unwrap();
+        let encrypted = keys.encrypt(b"user/1", b"value", true).unwrap();
+        keys.commit(b"user/1").unwrap();
+        fs::remove_file(keys.key_path(b"user/1")).unwrap();
+        let keys = KeyStore::open(dir.path()).unwrap();
+        assert_eq!(keys.state(b"user/1").unwrap(), KeyState::Missing);
+        assert_matches!(keys.decrypt(&encrypted), Some(Err(Error::MissingKey(_))));
+        assert_matches!(keys.encrypt(b"user/1", b"value", false), Err(Error::MissingKey(_)));
+    }
+
+    #[test]
+    fn pending() {
+        let dir = TempDir::new("pumpkindb").unwrap();
+        let keys = KeyStore::open(dir.path()).unwrap();
+        let encrypted = keys.encrypt(b"user/1", b"value", true).unwrap();
+        assert!(keys.pending_path(b"user/1").exists());
+        assert_eq!(keys.decrypt(&encrypted).unwrap().unwrap(), b"value");
+        keys.discard(b"user/1").unwrap();
+        assert_eq!(keys.state(b"user/1").unwrap(), KeyState::Missing);
+        assert_matches!(keys.decrypt(&encrypted), Some(Err(Error::MissingKey(_))));
+
+        let encrypted = keys.encrypt(b"user/1", b"value", true).unwrap();
+        keys.commit(b"user/1").unwrap();
+        assert!(!keys.pending_path(b"user/1").exists());
+        // committed keys are not discarded
+        keys.discard(b"user/1").unwrap();
+        let keys = KeyStore::open(dir.path()).unwrap();
+        assert_eq!(keys.decrypt(&encrypted).unwrap().unwrap(), b"value");
+    }
+
+    #[test]
+    fn streams() {
+        let dir = TempDir::new("pumpkindb").unwrap();
+        let mut encryption = Encryption::new(KeyStore::open(dir.path()).unwrap());
+        encryption.prefixes = vec![Vec::from("user/"), Vec::from("user/1/")];
+        assert_eq!(encryption.stream(b"user/1/name"), Some(&b"user/1/"[..]));
+        assert_eq!(encryption.stream(b"user/2/name"), Some(&b"user/"[..]));
+        assert_eq!(encryption.stream(b"order/1"), None);
+        assert!(encryption.is_stream(b"user/"));
+        assert!(!encryption.is_stream(b"user/2"));
+        // one stream per aggregate
+        encryption.delimiter = Some(b'/');
+        assert_eq!(encryption.stream(b"user/2/name"), Some(&b"user/2"[..]));
+        assert_eq!(encryption.stream(b"user/2"), Some(&b"user/2"[..]));
+        assert_eq!(encryption.stream(b"user/1/2/name"), Some(&b"user/1/2"[..]));
+        assert!(encryption.is_stream(b"user/2"));
+        assert!(!encryption.is_stream(b"user/2/"));
+        assert!(!encryption.is_stream(b"order/1"));
+    }
+
+    #[test]
+    fn decrypt_by_key() {
+        let dir = TempDir::new("pumpkindb").unwrap();
+        let mut encryption = Encryption::new(KeyStore::open(dir.path()).unwrap());
+        encryption.prefixes = vec![Vec::from("user/")];
+        encryption.delimiter = Some(b'/');
+        let encrypted = encryption.keys.encrypt(b"user/1", b"value", true).unwrap();
+        assert_eq!(encryption.decrypt(b"user/1/name", &encrypted).unwrap().unwrap(), b"value");
+        // values of other streams are rejected
+        assert_matches!(encryption.decrypt(b"user/2/name", &encrypted), Some(Err(Error::Authentication(_))));
+        // keys outside of streams are never decrypted
+        assert!(encryption.decrypt(b"order/1", &encrypted).is_none());
+        assert!(encryption.decrypt(b"user/1/name", b"value").is_none());
+    }
+}
@@ -0,0 +1,436 @@
+// Copyright (c) 2017, All Contributors (see CONTRIBUTORS file)
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! # Integrity check
+//!
+//! Checks a database directory without changing anything in it. The environment
+//! is opened read-only and without locking, so the check is meant to be done
+//! while the server is not running (after a crash or a copy, for example).
+//!
+//! Every key of every keyspace is checked against the maximum key size, and every
+//! value with a compression or encryption header is checked to be decompressable
+//! and to be authenticated with its stream's key. Just like when values are read,
+//! only values of keys compression rules apply to are considered compressed and
+//! only values of keys that belong to a stream are considered encrypted.
+//! Values of shredded streams are counted, but are not considered a problem,
+//! unlike values of streams without a key (for example, when a backup is restored
+//! next to a key store it doesn't match).
+//! The HLC timestamp kept in non-volatile memory is checked to be readable and
+//! not to be ahead of the wall clock.
+//!
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use lmdb;
+use lmdb::traits::LmdbResultExt;
+use hlc;
+
+use compression;
+use encryption::{self, Encryption};
+use storage;
+
+// Size of the HLC timestamp in non-volatile memory
+const HLC_SIZE: usize = 20;
+
+// Size of the records of named databases in the main LMDB database
+// (`MDB_db` on 64-bit platforms)
+const DB_RECORD_SIZE: usize = 48;
+
+#[derive(Debug)]
+pub enum Problem {
+    /// The environment can't be opened or read
+    Storage(String),
+    /// The key is longer than the maximum key size
+    KeyTooLong { keyspace: String, key: Vec<u8> },
+    /// The value has a compression header, but can't be decompressed
+    Decompression { keyspace: String, key: Vec<u8> },
+    /// The value is encrypted, but can't be authenticated with its stream's key
+    /// (or is encrypted for a stream other than the key's one)
+    Authentication { keyspace: String, key: Vec<u8>, stream: Vec<u8> },
+    /// The value is encrypted, but its stream's key is missing from the key store
+    /// (and the stream hasn't been shredded)
+    MissingKey { keyspace: String, key: Vec<u8>, stream: Vec<u8> },
+    /// The key store can't be read
+    KeyStore(String),
+    /// Non-volatile memory can't be read
+    Nvmem(String),
+    /// The HLC timestamp in non-volatile memory is ahead of the wall clock
+    FutureTimestamp(hlc::Timestamp<hlc::WallT>),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Problem::Storage(ref err) => write!(f, "storage can't be read: {}", err),
+            &Problem::KeyTooLong { ref keyspace, ref key } =>
+                write!(f, "key {:?} in keyspace {:?} is {} bytes long, longer than the maximum key size",
+                       key, keyspace, key.len()),
+            &Problem::Decompression { ref keyspace, ref key } =>
+                write!(f, "value of key {:?} in keyspace {:?} can't be decompressed", key, keyspace),
+            &Problem::Authentication { ref keyspace, ref key, ref stream } =>
+                write!(f, "value of key {:?} in keyspace {:?} can't be authenticated with the key of stream {:?}",
+                       key, keyspace, stream),
+            &Problem::MissingKey { ref keyspace, ref key, ref stream } =>
+                write!(f, "value of key {:?} in keyspace {:?} is encrypted, but the key of stream {:?} is missing",
+                       key, keyspace, stream),
+            &Problem::KeyStore(ref err) => write!(f, "key store can't be read: {}", err),
+            &Problem::Nvmem(ref err) => write!(f, "non-volatile memory can't be read: {}", err),
+            &Problem::FutureTimestamp(ref timestamp) =>
+                write!(f, "HLC timestamp in non-volatile memory ({:?}) is in the future", timestamp),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Number of keyspaces, including the default one
+    pub keyspaces: usize,
+    pub keys: usize,
+    pub compressed: usize,
+    pub encrypted: usize,
+    /// Number of encrypted values of shredded streams
+    pub shredded: usize,
+    /// HLC timestamp in non-volatile memory, if it's been initialized
+    pub hlc: Option<hlc::Timestamp<hlc::WallT>>,
+    pub problems: Vec<Problem>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "Keyspaces: {}", self.keyspaces));
+        try!(writeln!(f, "Keys: {}", self.keys));
+        try!(writeln!(f, "Compressed values: {}", self.compressed));
+        try!(writeln!(f, "Encrypted values: {} ({} shredded)", self.encrypted, self.shredded));
+        match self.hlc {
+            Some(ref timestamp) => try!(writeln!(f, "HLC: {:?}", timestamp)),
+            None => try!(writeln!(f, "HLC: not initialized")),
+        }
+        if self.problems.is_empty() {
+            writeln!(f, "No problems found")
+        } else {
+            try!(writeln!(f, "Problems found: {}", self.problems.len()));
+            for problem in self.problems.iter() {
+                try!(writeln!(f, " * {}", problem));
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Opens the environment at `path` read-only, without locking
+pub fn open_environment(path: &Path, maxdbs: Option<u32>) -> Result<lmdb::Environment, lmdb::Error> {
+    let path = path.to_string_lossy();
+    unsafe {
+        let mut builder = try!(lmdb::EnvBuilder::new());
+        try!(builder.set_maxdbs(maxdbs.unwrap_or(storage::DEFAULT_MAXDBS)));
+        builder.open(&path, lmdb::open::RDONLY | lmdb::open::NOLOCK | lmdb::open::NOTLS, 0o600)
+    }
+}
+
+/// Checks the database directory at `path` against compression rules and encryption
+/// configuration, if there's one (without it, no values are considered encrypted)
+pub fn check(path: &Path, maxdbs: Option<u32>, rules: &compression::Rules, encryption: Option<&Encryption>)
+             -> Report {
+    let mut report = Report::default();
+    match open_environment(path, maxdbs) {
+        Ok(env) => check_environment(&env, rules, encryption, &mut report),
+        Err(err) => report.problems.push(Problem::Storage(err.to_string())),
+    }
+    check_nvmem(&path.join(storage::NVMEM_FILE), &mut report);
+    report
+}
+
+/// Walks every keyspace of the environment
+pub fn check_environment(env: &lmdb::Environment, rules: &compression::Rules, encryption: Option<&Encryption>,
+                         report: &mut Report) {
+    let maxkeysize = env.maxkeysize() as usize;
+    let db = match lmdb::Database::open(env, None, &lmdb::DatabaseOptions::defaults()) {
+        Ok(db) => db,
+        Err(err) => return report.problems.push(Problem::Storage(err.to_string())),
+    };
+    report.keyspaces += 1;
+    // named databases (keyspaces) are recorded in the main database,
+    // so their records are checked as keys first and then opened
+    let candidates = match check_keyspace(env, &db, "", maxkeysize, rules, encryption, report) {
+        Ok(candidates) => candidates,
+        Err(err) => return report.problems.push(Problem::Storage(err.to_string())),
+    };
+    for name in candidates {
+        match lmdb::Database::open(env, Some(&name), &lmdb::DatabaseOptions::defaults()) {
+            Ok(keyspace) => {
+                report.keyspaces += 1;
+                report.keys -= 1;
+                if let Err(err) = check_keyspace(env, &keyspace, &name, maxkeysize, rules, encryption, report) {
+                    report.problems.push(Problem::Storage(err.to_string()));
+                }
+            }
+            // not a named database
+            Err(lmdb::Error::Code(code)) if code == lmdb::error::INCOMPATIBLE => (),
+            Err(err) => report.problems.push(Problem::Storage(err.to_string())),
+        }
+    }
+}
+
+// Checks every key/value pair of the keyspace, returns the keys that
+// might be the names of named databases
+fn check_keyspace(env: &lmdb::Environment, db: &lmdb::Database, name: &str, maxkeysize: usize,
+                  rules: &compression::Rules, encryption: Option<&Encryption>, report: &mut Report)
+                  -> Result<Vec<String>, lmdb::Error> {
+    // compression rules name the default keyspace with an empty name
+    let rules_name = if name == storage::DEFAULT_KEYSPACE { "" } else { name };
+    let txn = try!(lmdb::ReadTransaction::new(env));
+    let access = txn.access();
+    let mut cursor = try!(txn.cursor(db));
+    let mut candidates = Vec::new();
+    let mut item = try!(cursor.first::<[u8], [u8]>(&access).to_opt());
+    while let Some((key, value)) = item {
+        report.keys += 1;
+        if key.len() > maxkeysize {
+            report.problems.push(Problem::KeyTooLong {
+                keyspace: String::from(name),
+                key: Vec::from(key),
+            });
+        }
+        if name.len() == 0 && value.len() == DB_RECORD_SIZE {
+            if let Ok(candidate) = ::std::str::from_utf8(key) {
+                candidates.push(String::from(candidate));
+            }
+        }
+        let compressed = rules.applies(rules_name.as_bytes(), key);
+        let stream = encryption.and_then(|encryption| encryption.stream(key).map(|stream| (encryption, stream)));
+        check_value(name, key, value, compressed, stream, report);
+        item = try!(cursor.next::<[u8], [u8]>(&access).to_opt());
+    }
+    Ok(candidates)
+}
+
+// Unwraps compressed (if `compressed` is set) and encrypted (if the key belongs
+// to a stream) values, at most one layer of each, just like RETR does
+fn check_value(keyspace: &str, key: &[u8], value: &[u8], compressed: bool, stream: Option<(&Encryption, &[u8])>,
+               report: &mut Report) {
+    let mut decoded: Option<Vec<u8>> = None;
+    let mut decompressed = !compressed;
+    let mut decrypted = stream.is_none();
+    loop {
+        let next = {
+            let current = decoded.as_ref().map_or(value, |decoded| decoded.as_slice());
+            if !decompressed && current.starts_with(compression::HEADER) {
+                report.compressed += 1;
+                decompressed = true;
+                match compression::decompress(current, compression::MAX_SIZE) {
+                    Some(Ok(decompressed)) => decompressed,
+                    _ => {
+                        return report.problems.push(Problem::Decompression {
+                            keyspace: String::from(keyspace),
+                            key: Vec::from(key),
+                        })
+                    }
+                }
+            } else if !decrypted && encryption::stream(current).is_some() {
+                report.encrypted += 1;
+                decrypted = true;
+                // values are only considered encrypted if the key belongs to a stream
+                let (config, stream) = stream.unwrap();
+                let problem = Problem::MissingKey {
+                    keyspace: String::from(keyspace),
+                    key: Vec::from(key),
+                    stream: Vec::from(stream),
+                };
+                match config.decrypt(key, current) {
+                    Some(Ok(decrypted)) => decrypted,
+                    Some(Err(encryption::Error::Shredded(_))) => return report.shredded += 1,
+                    Some(Err(encryption::Error::MissingKey(_))) => return report.problems.push(problem),
+                    Some(Err(encryption::Error::Authentication(_))) => {
+                        return report.problems.push(Problem::Authentication {
+                            keyspace: String::from(keyspace),
+                            key: Vec::from(key),
+                            stream: Vec::from(stream),
+                        })
+                    }
+                    Some(Err(err)) => return report.problems.push(Problem::KeyStore(err.to_string())),
+                    // too short to be an encrypted value
+                    None => return,
+                }
+            } else {
+                return;
+            }
+        };
+        decoded = Some(next);
+    }
+}
+
+/// Checks the HLC timestamp in non-volatile memory at `path`
+pub fn check_nvmem(path: &Path, report: &mut Report) {
+    let mut data = Vec::new();
+    match File::open(path).and_then(|mut file| file.read_to_end(&mut data)) {
+        Ok(_) => (),
+        // the server hasn't been started yet
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return,
+        Err(err) => return report.problems.push(Problem::Nvmem(err.to_string())),
+    }
+    if data.len() < HLC_SIZE {
+        return report.problems.push(Problem::Nvmem(format!("{} bytes long, expected {}",
+                                                           data.len(), HLC_SIZE)));
+    }
+    if data[0..HLC_SIZE].iter().all(|byte| *byte == 0) {
+        return;
+    }
+    let timestamp: io::Result<hlc::Timestamp<hlc::WallT>> =
+        hlc::Timestamp::read_bytes(&mut &data[0..HLC_SIZE]);
+    match timestamp {
+        Ok(timestamp) => {
+            if timestamp > hlc::Clock::wall().now() {
+                report.problems.push(Problem::FutureTimestamp(timestamp.clone()));
+            }
+            report.hlc = Some(timestamp);
+        }
+        Err(err) => report.problems.push(Problem::Nvmem(err.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use tempdir::TempDir;
+    use lmdb;
+    use hlc;
+
+    use compression;
+    use encryption::{Encryption, KeyStore};
+    use storage;
+    use super::*;
+
+    fn create(path: &Path, pairs: &[(Option<&str>, &[u8], &[u8])]) {
+        let env = unsafe {
+            let mut builder = lmdb::EnvBuilder::new().expect("can't create env builder");
+            builder.set_maxdbs(storage::DEFAULT_MAXDBS).expect("can't set maxdbs");
+            builder.open(path.to_str().unwrap(), lmdb::open::NOTLS, 0o600).expect("can't open env")
+        };
+        for &(keyspace, key, value) in pairs {
+            let db = lmdb::Database::open(&env, keyspace, &lmdb::DatabaseOptions::new(lmdb::db::CREATE)).unwrap();
+            let txn = lmdb::WriteTransaction::new(&env).unwrap();
+            {
+                let mut access = txn.access();
+                access.put(&db, key, value, lmdb::put::Flags::empty()).unwrap();
+            }
+            txn.commit().unwrap();
+        }
+    }
+
+    fn rules() -> compression::Rules {
+        compression::Rules {
+            keyspaces: vec![Vec::from("events")],
+            prefixes: vec![Vec::from("b")],
+        }
+    }
+
+    #[test]
+    fn clean() {
+        let dir = TempDir::new("pumpkindb").unwrap();
+        let compressed = compression::compress(b"value");
+        create(dir.path(), &[(None, &b"a"[..], &b"1"[..]),
+                             (None, &b"b"[..], &compressed[..]),
+                             (Some("events"), &b"c"[..], &b"3"[..])]);
+        let report = check(dir.path(), None, &rules(), None);
+        assert!(report.problems.is_empty(), "{}", report);
+        assert_eq!(report.keyspaces, 2);
+        assert_eq!(report.keys, 3);
+        assert_eq!(report.compressed, 1);
+        assert!(report.hlc.is_none());
+    }
+
+    #[test]
+    fn corrupt_compressed() {
+        let dir = TempDir::new("pumpkindb").unwrap();
+        create(dir.path(), &[(Some("events"), &b"a"[..], &b"\xC0PZ\x01not zlib"[..]),
+                             (Some("other"), &b"a"[..], &b"\xC0PZ\x01not zlib"[..])]);
+        let report = check(dir.path(), None, &rules(), None);
+        assert_eq!(report.problems.len(), 1);
+        assert_matches!(report.problems[0], Problem::Decompression { .. });
+        // without compression rules, values that look compressed are just values
+        let report = check(dir.path(), None, &compression::Rules::default(), None);
+        assert!(report.problems.is_empty(), "{}", report);
+        assert_eq!(report.compressed, 0);
+    }
+
+    fn encryption(path: &Path) -> Encryption {
+        let mut encryption = Encryption::new(KeyStore::open_existing(path));
+        encryption.prefixes = vec![Vec::from("user/")];
+        encryption.delimiter = Some(b'/');
+        encryption
+    }
+
+    #[test]
+    fn encrypted() {
+        let dir = TempDir::new("pumpkindb").unwrap();
+        let keys_dir = TempDir::new("pumpkindb").unwrap();
+        let keys = encryption(keys_dir.path());
+        let first = keys.keys.encrypt(b"user/1", &compression::compress(b"value"), true).unwrap();
+        let second = keys.keys.encrypt(b"user/2", b"value", true).unwrap();
+        let mut tampered = keys.keys.encrypt(b"user/2", b"value", true).unwrap();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        keys.keys.shred(b"user/1").unwrap();
+        create(dir.path(), &[(None, &b"user/1/a"[..], &first[..]),
+                             (None, &b"user/2/b"[..], &second[..]),
+                             (None, &b"user/2/c"[..], &tampered[..]),
+                             // encrypted for another stream
+                             (None, &b"user/3/d"[..], &second[..]),
+                             // not in a stream, so not encrypted
+                             (None, &b"order/e"[..], &second[..])]);
+
+        let report = check(dir.path(), None, &rules(), Some(&keys));
+        assert_eq!(report.encrypted, 4);
+        assert_eq!(report.shredded, 1);
+        assert_eq!(report.problems.len(), 2);
+        assert_matches!(report.problems[0], Problem::Authentication { .. });
+        assert_matches!(report.problems[1], Problem::Authentication { .. });
+
+        // a key store that doesn't match the database
+        let other_dir = TempDir::new("pumpkindb").unwrap();
+        let report = check(dir.path(), None, &rules(), Some(&encryption(other_dir.path())));
+        assert_eq!(report.encrypted, 4);
+        assert_eq!(report.problems.len(), 4);
+        assert_matches!(report.problems[0], Problem::MissingKey { .. });
+
+        // a missing key store, which is not created
+        let missing = keys_dir.path().join("missing");
+        let report = check(dir.path(), None, &rules(), Some(&encryption(&missing)));
+        assert_eq!(report.problems.len(), 4);
+        assert!(!missing.exists());
+
+        // without encryption, no values are considered encrypted
+        let report = check(dir.path(), None, &rules(), None);
+        assert_eq!(report.encrypted, 0);
+        assert!(report.problems.is_empty(), "{}", report);
+    }
+
+    #[test]
+    fn future_timestamp() {
+        let dir = TempDir::new("pumpkindb").unwrap();
+        create(dir.path(), &[]);
+        let mut clock = hlc::Clock::wall();
+        clock.set_epoch(u32::max_value());
+        let mut nvmem = fs::File::create(dir.path().join(storage::NVMEM_FILE)).unwrap();
+        clock.now().write_bytes(&mut nvmem).unwrap();
+        let report = check(dir.path(), None, &rules(), None);
+        assert!(report.hlc.is_some());
+        assert_eq!(report.problems.len(), 1);
+        assert_matches!(report.problems[0], Problem::FutureTimestamp(_));
+    }
+
+    #[test]
+    fn missing() {
+        let dir = TempDir::new("pumpkindb").unwrap();
+        let report = check(&dir.path().join("missing"), None, &rules(), None);
+        assert_eq!(report.problems.len(), 1);
+        assert_matches!(report.problems[0], Problem::Storage(_));
+    }
+}
@@ -15,5 +15,9 @@ include!("crates.rs");
 pub mod script;
 pub mod messaging;
 pub mod storage;
+pub mod compression;
+pub mod encryption;
+pub mod fsck;
+pub mod dump;
 pub mod timestamp;
 pub mod nvmem;
\ No newline at end of file
@@ -11,7 +11,21 @@ pub trait Dispatcher<'a> {
     fn init(&mut self, env: &mut Env<'a>, pid: EnvId) {}
     #[allow(unused_variables)]
     fn done(&mut self, env: &mut Env<'a>, pid: EnvId) {}
+    /// Returns `true` if the environment can be moved to another scheduler,
+    /// which is only the case if the dispatcher doesn't keep any state of it
+    /// (such as open transactions). Dispatchers have to opt in, environments
+    /// are never moved by default.
+    #[allow(unused_variables)]
+    fn movable(&mut self, pid: EnvId) -> bool {
+        false
+    }
     fn handle(&mut self, env: &mut Env<'a>, instruction: &'a [u8], pid: EnvId) -> PassResult<'a>;
+    /// Resolves a word that is neither an instruction nor defined
+    /// in the environment's dictionary
+    #[allow(unused_variables)]
+    fn lookup(&mut self, env: &mut Env<'a>, instruction: &'a [u8], pid: EnvId) -> PassResult<'a> {
+        Err(Error::UnknownInstruction)
+    }
 }
 
 include!("macros.rs");
@@ -27,12 +41,21 @@ impl<'a> Dispatcher<'a> for Vec<Box<Dispatcher<'a>>> {
             disp.done(env, pid);
         }
     }
+    fn movable(&mut self, pid: EnvId) -> bool {
+        self.into_iter().all(|disp| disp.movable(pid))
+    }
     fn handle(&mut self, env: &mut Env<'a>, instruction: &'a [u8], pid: EnvId) -> PassResult<'a> {
         for mut disp in self.into_iter() {
             try_instruction!(env, disp.handle(env, instruction, pid));
         }
         Err(Error::UnknownInstruction)
     }
+    fn lookup(&mut self, env: &mut Env<'a>, instruction: &'a [u8], pid: EnvId) -> PassResult<'a> {
+        for mut disp in self.into_iter() {
+            try_instruction!(env, disp.lookup(env, instruction, pid));
+        }
+        Err(Error::UnknownInstruction)
+    }
 }
 
 macro_rules! for_each_dispatcher {
@@ -92,13 +115,18 @@ macro_rules! for_each_dispatcher {
             let ref mut $module = $dispatcher.string;
             $expr
         }
+        #[cfg(feature="mod_tuple")]
+        {
+            let ref mut $module = $dispatcher.tuple;
+            $expr
+        }
     }};
 }
 
 use super::super::nvmem::NonVolatileMemory;
 
 pub struct StandardDispatcher<'a, P: 'a, S: 'a, N: 'a, T>
-    where P : messaging::Publisher, S : messaging::Subscriber,
+    where P : messaging::Publisher + Clone, S : messaging::Subscriber,
           N : NonVolatileMemory, T : AsRef<storage::Storage<'a>> + 'a
 {
     #[cfg(feature = "mod_core")]
@@ -110,7 +138,7 @@ pub struct StandardDispatcher<'a, P: 'a, S: 'a, N: 'a, T>
     #[cfg(feature = "mod_numbers")]
     numbers: mod_numbers::Handler<'a>,
     #[cfg(feature = "mod_storage")]
-    storage: mod_storage::Handler<'a, T>,
+    storage: mod_storage::Handler<'a, T, P>,
     #[cfg(feature = "mod_hash")]
     hash: mod_hash::Handler<'a>,
     #[cfg(feature = "mod_hlc")]
@@ -122,12 +150,14 @@ pub struct StandardDispatcher<'a, P: 'a, S: 'a, N: 'a, T>
     #[cfg(feature = "mod_uuid")]
     uuid: mod_uuid::Handler<'a>,
     #[cfg(feature = "mod_string")]
-    string: mod_string::Handler<'a>
+    string: mod_string::Handler<'a>,
+    #[cfg(feature = "mod_tuple")]
+    tuple: mod_tuple::Handler<'a>
 }
 
 
 impl<'a, P: 'a, S: 'a, N: 'a, T> StandardDispatcher<'a, P, S, N, T>
-    where P : messaging::Publisher, S : messaging::Subscriber,
+    where P : messaging::Publisher + Clone, S : messaging::Subscriber,
           N : NonVolatileMemory, T : AsRef<storage::Storage<'a>> + 'a {
 
     pub fn new(db: T,
@@ -144,7 +174,7 @@ impl<'a, P: 'a, S: 'a, N: 'a, T> StandardDispatcher<'a, P, S, N, T>
                 #[cfg(feature = "mod_numbers")]
                     numbers: mod_numbers::Handler::new(),
                 #[cfg(feature = "mod_storage")]
-                    storage: mod_storage::Handler::new(db),
+                    storage: mod_storage::Handler::new(db, publisher.clone()),
                 #[cfg(feature = "mod_hash")]
                     hash: mod_hash::Handler::new(),
                 #[cfg(feature = "mod_hlc")]
@@ -157,12 +187,14 @@ impl<'a, P: 'a, S: 'a, N: 'a, T> StandardDispatcher<'a, P, S, N, T>
                     uuid: mod_uuid::Handler::new(),
                 #[cfg(feature = "mod_string")]
                     string: mod_string::Handler::new(),
+                #[cfg(feature = "mod_tuple")]
+                    tuple: mod_tuple::Handler::new(),
         }
     }
 }
 
 impl<'a, P: 'a, S: 'a, N: 'a, T> Dispatcher<'a> for StandardDispatcher<'a, P, S, N, T>
-    where P : messaging::Publisher, S : messaging::Subscriber, N : NonVolatileMemory,
+    where P : messaging::Publisher + Clone, S : messaging::Subscriber, N : NonVolatileMemory,
           T : AsRef<storage::Storage<'a>> + 'a {
     fn init(&mut self, env: &mut Env<'a>, pid: EnvId) {
         for_each_dispatcher!(disp, self, disp.init(env, pid));
@@ -170,10 +202,19 @@ impl<'a, P: 'a, S: 'a, N: 'a, T> Dispatcher<'a> for StandardDispatcher<'a, P, S,
     fn done(&mut self, env: &mut Env<'a>, pid: EnvId) {
         for_each_dispatcher!(disp, self, disp.done(env, pid));
     }
+    fn movable(&mut self, pid: EnvId) -> bool {
+        let mut movable = true;
+        for_each_dispatcher!(disp, self, movable = movable && disp.movable(pid));
+        movable
+    }
     fn handle(&mut self, env: &mut Env<'a>, instruction: &'a [u8], pid: EnvId) -> PassResult<'a> {
         for_each_dispatcher!(disp, self, try_instruction!(env, disp.handle(env, instruction, pid)));
         Err(Error::UnknownInstruction)
     }
+    fn lookup(&mut self, env: &mut Env<'a>, instruction: &'a [u8], pid: EnvId) -> PassResult<'a> {
+        for_each_dispatcher!(disp, self, try_instruction!(env, disp.lookup(env, instruction, pid)));
+        Err(Error::UnknownInstruction)
+    }
 }
 
 #[cfg(test)]
@@ -202,8 +243,7 @@ mod tests {
       pub fn handle_test(&mut self, env: &mut Env<'a>,
                           instruction: &'a [u8], _: EnvId) -> PassResult<'a> {
           instruction_is!(instruction, b"\x84TEST");
-          env.push(b"TEST");
-          Ok(())
+          env.push(b"TEST")
       }
 
   }
@@ -227,7 +267,7 @@ mod tests {
           let (sender0, _) = mpsc::channel();
           sender.schedule_env(EnvId::new(), script.clone(), callback, Box::new(sender0));
           match receiver.recv() {
-              Ok(ResponseMessage::EnvTerminated(_, stack, stack_size)) => {
+              Ok(ResponseMessage::EnvTerminated(_, stack, stack_size, _)) => {
                   // terminated without an error
                   let mut stack_ = Vec::with_capacity(stack.len());
                   for i in 0..(&stack).len() {
@@ -237,7 +277,7 @@ mod tests {
                   let val = script_env.pop().unwrap();
                   assert_eq!(val, b"TEST");
               },
-              Ok(ResponseMessage::EnvFailed(_, err, stack, stack_size)) => {
+              Ok(ResponseMessage::EnvFailed(_, err, stack, stack_size, _)) => {
                   let _ = sender.send(RequestMessage::Shutdown);
                   panic!("error: {:?}", err);
               }
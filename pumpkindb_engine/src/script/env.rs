@@ -4,11 +4,12 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use super::Error;
+use super::{Error, Waker, Priority, error_memory_exceeded};
 use super::envheap::EnvHeap;
 use super::super::messaging;
 
 use std::collections::BTreeMap;
+use std::time::Instant;
 
 /// Initial stack size
 pub const STACK_SIZE: usize = 32_768;
@@ -31,7 +32,19 @@ pub struct Env<'a> {
     // current TRY status
     pub tracking_errors: usize,
     pub aborting_try: Vec<Error>,
+    // limits (see Scheduler)
+    pub instructions: u64,
+    pub max_instructions: Option<u64>,
+    pub deadline: Option<Instant>,
+    /// Maximum number of bytes the heap and the stack can take,
+    /// unlimited if `None`
+    pub max_memory: Option<usize>,
+    pub priority: Priority,
+    /// Number of instructions evaluated with effects that can't be rolled back
+    /// (such as PUBLISH), a WRITE is only retried if there were none within it
+    pub side_effects: usize,
     published_message_callback: Option<Box<messaging::PublishedMessageCallback + Send>>,
+    waker: Option<Waker>,
 }
 
 impl<'a> ::std::fmt::Debug for Env<'a> {
@@ -75,7 +88,14 @@ impl<'a> Env<'a> {
             dictionary: dictionary,
             tracking_errors: 0,
             aborting_try: Vec::new(),
+            instructions: 0,
+            max_instructions: None,
+            deadline: None,
+            max_memory: None,
+            priority: Priority::Normal,
+            side_effects: 0,
             published_message_callback: None,
+            waker: None,
         })
     }
 
@@ -115,23 +135,45 @@ impl<'a> Env<'a> {
     }
 
     /// Pushes value on top of the stack
+    ///
+    /// Fails if the stack would have to grow beyond
+    /// the [quota](#structfield.max_memory).
     #[inline]
-    pub fn push(&mut self, data: &'a [u8]) {
+    pub fn push(&mut self, data: &'a [u8]) -> Result<(), Error> {
         // check if we are at capacity
         if self.stack_size == self.stack.len() {
+            if let Some(max) = self.max_memory {
+                if self.memory_usage() + STACK_SIZE * mem::size_of::<&[u8]>() > max {
+                    return Err(error_memory_exceeded());
+                }
+            }
             let mut vec = vec![_EMPTY; STACK_SIZE];
             self.stack.append(&mut vec);
         }
         self.stack.as_mut_slice()[self.stack_size] = data;
         self.stack_size += 1;
+        Ok(())
     }
 
     /// Allocates a slice off the Env-specific heap. Will be collected
     /// once this Env is dropped.
+    ///
+    /// Fails if the heap would have to grow beyond
+    /// the [quota](#structfield.max_memory).
     pub fn alloc(&mut self, len: usize) -> Result<&'a mut [u8], Error> {
+        if let Some(max) = self.max_memory {
+            if self.memory_usage() + self.heap.growth(len) > max {
+                return Err(error_memory_exceeded());
+            }
+        }
         Ok(unsafe { mem::transmute::<&mut [u8], &'a mut [u8]>(self.heap.alloc(len)) })
     }
 
+    /// Returns the number of bytes taken by the heap and the stack.
+    /// Neither of them ever shrinks, so this is also the peak usage.
+    pub fn memory_usage(&self) -> usize {
+        self.heap.size() + self.stack.len() * mem::size_of::<&[u8]>()
+    }
 
     #[cfg(feature = "scoped_dictionary")]
     pub fn push_dictionary(&mut self) {
@@ -157,21 +199,54 @@ impl<'a> Env<'a> {
             Some(ref cb) => Some(cb.cloned())
         }
     }
+
+    pub fn set_waker(&mut self, waker: Waker) {
+        self.waker = Some(waker);
+    }
+
+    /// Returns a waker that resumes this environment after it
+    /// has been parked (see [`Error::Park`](enum.Error.html))
+    pub fn waker(&self) -> Option<Waker> {
+        self.waker.clone()
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
-    use super::{Env, _EMPTY};
+    use super::{Env, _EMPTY, STACK_SIZE, HEAP_SIZE};
 
     #[test]
     fn env_stack_growth() {
         let mut env = Env::new().unwrap();
         let target = env.stack.len() * 100;
         for _ in 1..target {
-            env.push(_EMPTY);
+            env.push(_EMPTY).unwrap();
         }
         assert!(env.stack.len() >= target);
     }
 
+    #[test]
+    fn env_heap_quota() {
+        let mut env = Env::new().unwrap();
+        env.max_memory = Some(env.memory_usage() + HEAP_SIZE);
+        assert!(env.alloc(HEAP_SIZE).is_ok());
+        // adds a chunk
+        assert!(env.alloc(1).is_ok());
+        // would add one more
+        assert!(env.alloc(HEAP_SIZE).is_err());
+    }
+
+    #[test]
+    fn env_stack_quota() {
+        let mut env = Env::new().unwrap();
+        env.max_memory = Some(env.memory_usage());
+        for _ in 0..STACK_SIZE {
+            assert!(env.push(_EMPTY).is_ok());
+        }
+        // would grow the stack
+        assert!(env.push(_EMPTY).is_err());
+        assert_eq!(env.stack.len(), STACK_SIZE);
+    }
+
 }
@@ -21,13 +21,30 @@ use std::slice;
 /// of resizing existing ones.
 pub struct EnvHeap {
     chunks: Vec<(usize, RawVec<u8>)>,
+    size: usize,
 }
 
 impl EnvHeap {
     /// Creates new EnvHeap with a certain chunk size, which
     /// can't be changed later
     pub fn new(chunk_size: usize) -> Self {
-        EnvHeap { chunks: vec![(0, RawVec::with_capacity(chunk_size))] }
+        EnvHeap { chunks: vec![(0, RawVec::with_capacity(chunk_size))], size: chunk_size }
+    }
+
+    /// Returns the total size of all chunks
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the number of bytes the heap will grow by
+    /// if a slice of `size` is allocated
+    pub fn growth(&self, size: usize) -> usize {
+        for &(ptr, ref chunk) in self.chunks.iter() {
+            if ptr + size <= chunk.cap() {
+                return 0
+            }
+        }
+        cmp::max(self.chunks[self.chunks.len() - 1].1.cap(), size)
     }
 
     /// Allocates a new mutable slice
@@ -39,7 +56,9 @@ impl EnvHeap {
             let ptr = self.chunks[i].0;
             if ptr + size > cap {
                 if i == (nchunks - 1) {
-                    self.chunks.push((0, RawVec::with_capacity(cmp::max(cap, size))));
+                    let chunk_size = cmp::max(cap, size);
+                    self.chunks.push((0, RawVec::with_capacity(chunk_size)));
+                    self.size += chunk_size;
                     return self.alloc(size)
                 } else {
                     continue;
@@ -75,4 +94,16 @@ mod tests {
         }
         assert_eq!(50, heap.chunks.len());
     }
+
+    #[test]
+    fn growth() {
+        let mut heap = EnvHeap::new(1_000);
+        assert_eq!(heap.growth(600), 0);
+        heap.alloc(600);
+        assert_eq!(heap.growth(400), 0);
+        assert_eq!(heap.growth(500), 1_000);
+        assert_eq!(heap.growth(5_000), 5_000);
+        heap.alloc(5_000);
+        assert_eq!(heap.size(), 6_000);
+    }
 }
@@ -271,10 +271,9 @@ macro_rules! eval {
             let path = dir.path().to_str().unwrap();
             fs::create_dir_all(path).expect("can't create directory");
             let env = unsafe {
-                lmdb::EnvBuilder::new()
-                    .expect("can't create env builder")
-                    .open(path, lmdb::open::NOTLS, 0o600)
-                    .expect("can't open env")
+                let mut builder = lmdb::EnvBuilder::new().expect("can't create env builder");
+                builder.set_maxdbs(storage::DEFAULT_MAXDBS).expect("can't set maxdbs");
+                builder.open(path, lmdb::open::NOTLS, 0o600).expect("can't open env")
             };
 
             let db = Arc::new(storage::Storage::new(&env));
@@ -297,7 +296,7 @@ macro_rules! eval {
                 sender.schedule_env(EnvId::new(),
                                     script.clone(), callback, Box::new($sender));
                 match receiver.recv() {
-                   Ok(ResponseMessage::EnvTerminated(_, stack, stack_size)) => {
+                   Ok(ResponseMessage::EnvTerminated(_, stack, stack_size, _)) => {
                       sender.shutdown();
                       messaging_accessor.shutdown();
                       let $result = Ok::<(), Error>(());
@@ -308,7 +307,7 @@ macro_rules! eval {
                       let mut $env = Env::new_with_stack(stack_, stack_size).unwrap();
                       $expr;
                    }
-                   Ok(ResponseMessage::EnvFailed(_, err, stack, stack_size)) => {
+                   Ok(ResponseMessage::EnvFailed(_, err, stack, stack_size, _)) => {
                       sender.shutdown();
                       messaging_accessor.shutdown();
                       let $result = Err::<(), Error>(err);
@@ -344,6 +343,7 @@ macro_rules! bench_eval {
             let env = unsafe {
                 let mut builder = lmdb::EnvBuilder::new().expect("can't create env builder");
                 builder.set_mapsize(1024 * 1024 * 1024).expect("can't set mapsize");
+                builder.set_maxdbs(storage::DEFAULT_MAXDBS).expect("can't set maxdbs");
                 builder.open(path, lmdb::open::NOTLS, 0o600).expect("can't open env")
             };
 
@@ -380,8 +380,8 @@ macro_rules! bench_eval {
                     let _ = senders.clone().schedule_env(EnvId::new(),
                                            script.clone(), callback, Box::new(sender0));
                     match receiver.recv() {
-                       Ok(ResponseMessage::EnvTerminated(_, stack, stack_size)) => (),
-                       Ok(ResponseMessage::EnvFailed(_, err, stack, stack_size)) => {
+                       Ok(ResponseMessage::EnvTerminated(_, stack, stack_size, _)) => (),
+                       Ok(ResponseMessage::EnvFailed(_, err, stack, stack_size, _)) => {
                           senders.shutdown();
                           messaging_accessor.shutdown();
                           panic!("error: {:?}", err);
@@ -86,8 +86,12 @@ macro_rules! instruction {
 instruction!(TRY, b"\x83TRY");
 instruction!(TRY_END, b"\x80\x83TRY"); // internal instruction
 
+instruction!(LIMIT_INSTRUCTIONS, b"\x92LIMIT/INSTRUCTIONS");
+instruction!(LIMIT_TIME, b"\x8ALIMIT/TIME");
+
 
 use std::str;
+use std::time::{Duration, Instant};
 
 // To add instructions that don't belong to a core set,
 // add a module with a handler, and reference it in the Scheduler's pass
@@ -102,11 +106,13 @@ pub enum Error {
     /// An internal scheduler's error to indicate that currently
     /// executed environment should be rescheduled from the same point
     Reschedule,
+    /// An internal scheduler's error to indicate that currently
+    /// executed environment should be parked until it is woken up
+    /// with its [`Waker`](struct.Waker.html) or until the deadline
+    /// (if any), after which it will be rescheduled from the same point
+    Park(Option<Instant>),
     /// Program Error
     ProgramError(Vec<u8>),
-    /// Unable to (re)allocate the heap so the returning slice points to
-    /// unallocated memory.
-    HeapAllocFailed,
 }
 /// Parse-related error
 #[derive(Debug, PartialEq)]
@@ -145,19 +151,73 @@ use std;
 
 pub type EnvId = ProcessUniqueId;
 
+/// Priority class of a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Priority {
+    /// Requests that can wait, such as analytical reads
+    Low,
+    Normal,
+    /// Latency-critical requests, such as writes and administration
+    High,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+impl Priority {
+    /// Number of instructions an environment evaluates in a row
+    /// before the scheduler switches to the next one
+    ///
+    /// Normal priority keeps the scheduler's quantum of one instruction, which
+    /// can't get any smaller, so low priority environments are only set apart
+    /// by being run on a pool of their own.
+    pub fn slices(&self) -> usize {
+        match self {
+            &Priority::Low => 1,
+            &Priority::Normal => 1,
+            &Priority::High => 4,
+        }
+    }
+}
+
 pub trait SchedulerHandle {
+    /// Schedules an environment with the `Normal` priority
     fn schedule_env(&self, env_id: EnvId, program: Vec<u8>, response_sender: Sender<ResponseMessage>,
-                    published_message_callback: Box<messaging::PublishedMessageCallback + Send>);
+                    published_message_callback: Box<messaging::PublishedMessageCallback + Send>) {
+        self.schedule_env_with_priority(env_id, program, Priority::Normal, response_sender,
+                                        published_message_callback)
+    }
+    fn schedule_env_with_priority(&self, env_id: EnvId, program: Vec<u8>, priority: Priority,
+                                  response_sender: Sender<ResponseMessage>,
+                                  published_message_callback: Box<messaging::PublishedMessageCallback + Send>);
+    /// Stops an environment, rolling back its transactions and releasing
+    /// its cursors. The environment fails with a `Cancelled` error. Does
+    /// nothing if it has already terminated.
+    fn cancel(&self, env_id: EnvId);
     fn shutdown(&self);
+    /// Returns the number of environments the scheduler is running,
+    /// `0` if it is unknown
+    fn load(&self) -> usize {
+        0
+    }
 }
 
 pub type Sender<T> = mpsc::Sender<T>;
 pub type Receiver<T> = mpsc::Receiver<T>;
 
 impl SchedulerHandle for Sender<RequestMessage> {
-    fn schedule_env(&self, env_id: EnvId, program: Vec<u8>, response_sender: Sender<ResponseMessage>,
-                    published_message_callback: Box<messaging::PublishedMessageCallback + Send>) {
-        let _ = self.send(RequestMessage::ScheduleEnv(env_id, program, response_sender, published_message_callback));
+    fn schedule_env_with_priority(&self, env_id: EnvId, program: Vec<u8>, priority: Priority,
+                                  response_sender: Sender<ResponseMessage>,
+                                  published_message_callback: Box<messaging::PublishedMessageCallback + Send>) {
+        let _ = self.send(RequestMessage::ScheduleEnv(env_id, program, priority, response_sender,
+                                                      published_message_callback));
+    }
+
+    fn cancel(&self, env_id: EnvId) {
+        let _ = self.send(RequestMessage::Cancel(env_id));
     }
 
     fn shutdown(&self) {
@@ -167,13 +227,61 @@ impl SchedulerHandle for Sender<RequestMessage> {
 
 use rand::{thread_rng, Rng};
 
+/// Sends requests to a [Scheduler](struct.Scheduler.html) and keeps
+/// track of its load (see [`Scheduler::sender`](struct.Scheduler.html#method.sender))
+#[derive(Clone)]
+pub struct SchedulerSender {
+    sender: Sender<RequestMessage>,
+    load: Arc<AtomicUsize>,
+}
+
+impl SchedulerHandle for SchedulerSender {
+    fn schedule_env_with_priority(&self, env_id: EnvId, program: Vec<u8>, priority: Priority,
+                                  response_sender: Sender<ResponseMessage>,
+                                  published_message_callback: Box<messaging::PublishedMessageCallback + Send>) {
+        // the scheduler will correct it once it gets to the request,
+        // until then other senders can take the new env into account
+        self.load.fetch_add(1, Ordering::Relaxed);
+        self.sender.schedule_env_with_priority(env_id, program, priority, response_sender,
+                                               published_message_callback);
+    }
+
+    fn cancel(&self, env_id: EnvId) {
+        self.sender.cancel(env_id);
+    }
+
+    fn shutdown(&self) {
+        self.sender.shutdown();
+    }
+
+    fn load(&self) -> usize {
+        self.load.load(Ordering::Relaxed)
+    }
+}
+
 impl<T : SchedulerHandle> SchedulerHandle for Vec<T> {
-    fn schedule_env(&self, env_id: EnvId, program: Vec<u8>, response_sender: Sender<ResponseMessage>, published_message_callback: Box<messaging::PublishedMessageCallback + Send>) {
-        let mut rng = thread_rng();
-        let index: usize = rng.gen_range(0, self.len() - 1);
-        match self.get(index) {
-            None => panic!("no available schedulers"),
-            Some(scheduler) => scheduler.schedule_env(env_id, program, response_sender, published_message_callback)
+    /// Schedules the environment on the less loaded of
+    /// two randomly picked schedulers ("power of two choices")
+    fn schedule_env_with_priority(&self, env_id: EnvId, program: Vec<u8>, priority: Priority,
+                                  response_sender: Sender<ResponseMessage>,
+                                  published_message_callback: Box<messaging::PublishedMessageCallback + Send>) {
+        let index = match self.len() {
+            0 => panic!("no available schedulers"),
+            1 => 0,
+            len => {
+                let mut rng = thread_rng();
+                let a = rng.gen_range(0, len);
+                let b = (a + rng.gen_range(1, len)) % len;
+                if self[b].load() < self[a].load() { b } else { a }
+            }
+        };
+        self[index].schedule_env_with_priority(env_id, program, priority, response_sender, published_message_callback)
+    }
+
+    fn cancel(&self, env_id: EnvId) {
+        // only the scheduler running the environment will act on it
+        for scheduler in self {
+            scheduler.cancel(env_id);
         }
     }
 
@@ -182,14 +290,88 @@ impl<T : SchedulerHandle> SchedulerHandle for Vec<T> {
             scheduler.shutdown();
         }
     }
+
+    fn load(&self) -> usize {
+        self.iter().map(|scheduler| scheduler.load()).sum()
+    }
+}
+
+/// Schedulers split into pools by the priority of requests they run.
+/// Requests of a priority that doesn't have its own pool are run
+/// by the `Normal` pool.
+pub struct SchedulerPools<T: SchedulerHandle> {
+    normal: Vec<T>,
+    pools: HashMap<Priority, Vec<T>>,
+}
+
+impl<T: SchedulerHandle> SchedulerPools<T> {
+    /// Creates pools with all schedulers in the `Normal` pool
+    pub fn new(normal: Vec<T>) -> Self {
+        SchedulerPools { normal: normal, pools: HashMap::new() }
+    }
+
+    /// Dedicates `schedulers` to requests of the `priority`
+    pub fn insert(&mut self, priority: Priority, schedulers: Vec<T>) {
+        if priority == Priority::Normal {
+            self.normal = schedulers;
+        } else {
+            self.pools.insert(priority, schedulers);
+        }
+    }
+
+    fn pool(&self, priority: Priority) -> &Vec<T> {
+        match self.pools.get(&priority) {
+            Some(pool) if !pool.is_empty() => pool,
+            _ => &self.normal,
+        }
+    }
+}
+
+impl<T: SchedulerHandle> SchedulerHandle for SchedulerPools<T> {
+    fn schedule_env_with_priority(&self, env_id: EnvId, program: Vec<u8>, priority: Priority,
+                                  response_sender: Sender<ResponseMessage>,
+                                  published_message_callback: Box<messaging::PublishedMessageCallback + Send>) {
+        self.pool(priority).schedule_env_with_priority(env_id, program, priority, response_sender,
+                                                       published_message_callback)
+    }
+
+    fn cancel(&self, env_id: EnvId) {
+        self.normal.cancel(env_id);
+        for pool in self.pools.values() {
+            pool.cancel(env_id);
+        }
+    }
+
+    fn shutdown(&self) {
+        self.normal.shutdown();
+        for pool in self.pools.values() {
+            pool.shutdown();
+        }
+    }
+
+    fn load(&self) -> usize {
+        self.normal.load() + self.pools.values().map(|pool| pool.load()).sum::<usize>()
+    }
 }
 
 /// Communication messages used to talk with the [Scheduler](struct.Scheduler.html) thread.
 pub enum RequestMessage {
     /// Requests scheduling a new environment with a given
     /// id and a program.
-    ScheduleEnv(EnvId, Vec<u8>, Sender<ResponseMessage>,
+    ScheduleEnv(EnvId, Vec<u8>, Priority, Sender<ResponseMessage>,
                 Box<messaging::PublishedMessageCallback + Send>),
+    /// Requests resuming a parked environment
+    Wake(EnvId),
+    /// Requests stopping an environment
+    Cancel(EnvId),
+    /// Requests moving a runnable environment to the peer
+    /// with a given index (see [`Scheduler::join`](struct.Scheduler.html#method.join))
+    Steal(usize),
+    /// Notifies of a stolen environment sent to this scheduler
+    Adopt,
+    /// Notifies of a stolen environment with a given id
+    /// having been picked up by the peer that stole it
+    Adopted(EnvId),
     /// Requests Scheduler shutdown
     Shutdown,
 }
@@ -198,15 +380,30 @@ pub enum RequestMessage {
 #[derive(Debug)]
 pub enum ResponseMessage {
     /// Notifies of successful environment termination with
-    /// an id, stack and top of the stack pointer.
-    EnvTerminated(EnvId, Vec<Vec<u8>>, usize),
+    /// an id, stack, top of the stack pointer and peak memory usage
+    /// (in bytes).
+    EnvTerminated(EnvId, Vec<Vec<u8>>, usize, usize),
     /// Notifies of abnormal environment termination with
-    /// an id, error, stack and top of the stack pointer.
-    EnvFailed(EnvId, Error, Option<Vec<Vec<u8>>>, Option<usize>),
+    /// an id, error, stack, top of the stack pointer and peak memory
+    /// usage (in bytes).
+    EnvFailed(EnvId, Error, Option<Vec<Vec<u8>>>, Option<usize>, usize),
 }
 
 pub type TrySendError<T> = std::sync::mpsc::TrySendError<T>;
 
+/// Resumes a parked environment (see [`Error::Park`](enum.Error.html))
+#[derive(Clone)]
+pub struct Waker {
+    env_id: EnvId,
+    sender: Sender<RequestMessage>,
+}
+
+impl Waker {
+    pub fn wake(&self) {
+        let _ = self.sender.send(RequestMessage::Wake(self.env_id));
+    }
+}
+
 use storage;
 use timestamp;
 
@@ -232,6 +429,8 @@ pub mod mod_msg;
 pub mod mod_uuid;
 #[cfg(feature="mod_string")]
 pub mod mod_string;
+#[cfg(feature="mod_tuple")]
+pub mod mod_tuple;
 
 /// Scheduler is a PumpkinScript scheduler and interpreter. This is the
 /// most central part of this module.
@@ -247,12 +446,12 @@ pub mod mod_string;
 /// let (callback, receiver) = mpsc::channel::<ResponseMessage>();
 /// sender.schedule_env(EnvId::new(), script.clone(), callback);
 /// match receiver.recv() {
-///     Ok(ResponseMessage::EnvTerminated(_, stack, stack_size)) => {
+///     Ok(ResponseMessage::EnvTerminated(_, stack, stack_size, memory)) => {
 ///         sender.shutdown();
 ///         // success
 ///         // ...
 ///     }
-///     Ok(ResponseMessage::EnvFailed(_, err, stack, stack_size)) => {
+///     Ok(ResponseMessage::EnvFailed(_, err, stack, stack_size, memory)) => {
 ///         sender.shutdown();
 ///         // failure
 ///         // ...
@@ -263,13 +462,54 @@ pub mod mod_string;
 /// }
 /// ```
 
-use std::collections::VecDeque;
+use std::collections::{VecDeque, HashMap};
 
 use std::marker::PhantomData;
 
+/// Limits every environment is started with. Scripts can only
+/// lower the instruction and time limits (see LIMIT/INSTRUCTIONS
+/// and LIMIT/TIME).
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Limits {
+    /// Maximum number of instructions (including data pushes)
+    /// an environment can evaluate, unlimited if `None`
+    pub max_instructions: Option<u64>,
+    /// Maximum time an environment can run for,
+    /// unlimited if `None`
+    pub max_duration: Option<Duration>,
+    /// Maximum number of bytes an environment's heap and
+    /// stack can take, unlimited if `None`
+    pub max_memory: Option<usize>,
+}
+
+// Environment that is ready to run, along with its id and response channel
+type Runnable<'a> = (EnvId, Env<'a>, Sender<ResponseMessage>);
+
+// Stolen environment, along with the index of the peer it was stolen from
+type Stolen<'a> = (usize, Runnable<'a>);
+
+/// A member of a group of schedulers that steal
+/// runnable environments from each other
+#[derive(Clone)]
+pub struct Peer<'a> {
+    sender: SchedulerSender,
+    stolen: Sender<Stolen<'a>>,
+}
+
+// How often an idle scheduler will try to steal
+// from its busiest peer
+const STEAL_INTERVAL: u64 = 20;
+
 pub struct Scheduler<'a, T : Dispatcher<'a>> {
     inbox: Receiver<RequestMessage>,
+    sender: Sender<RequestMessage>,
     dispatcher: T,
+    pub limits: Limits,
+    load: Arc<AtomicUsize>,
+    stolen: Receiver<Stolen<'a>>,
+    stolen_sender: Sender<Stolen<'a>>,
+    peers: Vec<Peer<'a>>,
+    position: Option<usize>,
     phantom: PhantomData<&'a ()>,
 }
 
@@ -289,22 +529,121 @@ const ERROR_UNKNOWN_KEY: &'static [u8] = b"\x01\x07";
 const ERROR_NO_TX: &'static [u8] = b"\x01\x08";
 const ERROR_DATABASE: &'static [u8] = b"\x01\x09";
 const ERROR_NO_VALUE: &'static [u8] = b"\x01\x0A";
+const ERROR_WRITE_TIMEOUT: &'static [u8] = b"\x01\x0B";
+const ERROR_SHREDDED: &'static [u8] = b"\x01\x0C";
+const ERROR_WRONG_VERSION: &'static [u8] = b"\x01\x0D";
+const ERROR_LIMIT_EXCEEDED: &'static [u8] = b"\x01\x0E";
+const ERROR_MEMORY_EXCEEDED: &'static [u8] = b"\x01\x0F";
+const ERROR_CANCELLED: &'static [u8] = b"\x01\x10";
+const ERROR_UNKNOWN_KEYSPACE: &'static [u8] = b"\x01\x11";
 
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use pumpkinscript::{binparser};
+use num_bigint::BigUint;
+use num_traits::ToPrimitive;
+
+// Returns an error if the environment has exhausted its instruction
+// budget or has run past its deadline. This error can't be caught with TRY.
+fn limit_exceeded(env: &Env) -> Option<Error> {
+    if let Some(max) = env.max_instructions {
+        if env.instructions >= max {
+            let consumed = BigUint::from(env.instructions).to_bytes_be();
+            return Some(error_program!("Instruction limit exceeded".as_bytes(), &consumed, ERROR_LIMIT_EXCEEDED));
+        }
+    }
+    if let Some(deadline) = env.deadline {
+        if Instant::now() >= deadline {
+            let consumed = BigUint::from(env.instructions).to_bytes_be();
+            return Some(error_program!("Time limit exceeded".as_bytes(), &consumed, ERROR_LIMIT_EXCEEDED));
+        }
+    }
+    None
+}
+
+// Used by `Env` (macros are not available there)
+fn error_memory_exceeded() -> Error {
+    let vec = Vec::new();
+    error_program!("Memory quota exceeded".as_bytes(), &vec, ERROR_MEMORY_EXCEEDED)
+}
 
 impl<'a, T: Dispatcher<'a>> Scheduler<'a, T> {
     /// Creates an instance of Scheduler and a Sender
     pub fn new(dispatcher: T) -> (Self, Sender<RequestMessage>) {
         let (tx, rx) = mpsc::channel::<RequestMessage>();
+        let (stolen_tx, stolen_rx) = mpsc::channel();
         (Scheduler::<'a, T> {
             inbox: rx,
+            sender: tx.clone(),
             dispatcher: dispatcher,
+            limits: Limits::default(),
+            load: Arc::new(AtomicUsize::new(0)),
+            stolen: stolen_rx,
+            stolen_sender: stolen_tx,
+            peers: Vec::new(),
+            position: None,
             phantom: PhantomData,
         }, tx)
     }
 
+    /// Returns a sender that keeps track of this scheduler's load
+    pub fn sender(&self) -> SchedulerSender {
+        SchedulerSender {
+            sender: self.sender.clone(),
+            load: self.load.clone(),
+        }
+    }
+
+    /// Returns a handle other schedulers can use to
+    /// steal environments from this one
+    pub fn peer(&self) -> Peer<'a> {
+        Peer {
+            sender: self.sender(),
+            stolen: self.stolen_sender.clone(),
+        }
+    }
+
+    /// Makes this scheduler a member of a group of `peers` (which should
+    /// include this scheduler, too). Once idle, it will periodically try
+    /// to steal a runnable environment from the busiest peer.
+    ///
+    /// Only environments that don't hold any state in the dispatcher
+    /// (see [`Dispatcher::movable`](trait.Dispatcher.html#method.movable))
+    /// can be stolen.
+    pub fn join(&mut self, peers: Vec<Peer<'a>>) {
+        self.position = peers.iter().position(|peer| Arc::ptr_eq(&peer.sender.load, &self.load));
+        self.peers = peers;
+    }
+
+    // Asks the busiest peer for a runnable environment
+    fn steal(&self) {
+        let position = match self.position {
+            Some(position) => position,
+            None => return,
+        };
+        let busiest = self.peers.iter().enumerate()
+            .filter(|&(i, _)| i != position)
+            .max_by_key(|&(_, peer)| peer.sender.load());
+        if let Some((_, peer)) = busiest {
+            if peer.sender.load() > 1 {
+                let _ = peer.sender.sender.send(RequestMessage::Steal(position));
+            }
+        }
+    }
+
+    // Picks up environments stolen from peers and lets
+    // the peers know they no longer have to keep track of them
+    fn adopt(&self, envs: &mut VecDeque<Runnable<'a>>) {
+        while let Ok((victim, (pid, mut env, chan))) = self.stolen.try_recv() {
+            env.set_waker(Waker { env_id: pid, sender: self.sender.clone() });
+            envs.push_back((pid, env, chan));
+            if let Some(peer) = self.peers.get(victim) {
+                let _ = peer.sender.sender.send(RequestMessage::Adopted(pid));
+            }
+        }
+    }
+
     /// Scheduler. It is supposed to be running in a separate thread
     ///
     /// The scheduler handles all incoming  messages. Once at least one
@@ -314,35 +653,84 @@ impl<'a, T: Dispatcher<'a>> Scheduler<'a, T> {
     ///
     /// Once an environment execution has been terminated, a message will be sent,
     /// depending on the result (`EnvTerminated` or `EnvFailed`)
+    ///
+    /// Environments that can't proceed until some event occurs (for example,
+    /// a write lock becoming available) get parked and are only rescheduled
+    /// when woken up (`Wake`) or when their deadline passes.
+    ///
+    /// Environments that exceed their [limits](struct.Limits.html) are
+    /// terminated with an error.
+    ///
+    /// If the scheduler has [joined](#method.join) a group, it will steal
+    /// environments from its peers whenever it has nothing to run.
     pub fn run(&mut self) {
-        let mut envs: VecDeque<(EnvId, Env<'a>, Sender<ResponseMessage>)> = VecDeque::new();
+        let mut envs: VecDeque<Runnable<'a>> = VecDeque::new();
+        let mut parked: HashMap<EnvId, (Env<'a>, Sender<ResponseMessage>, Option<Instant>)> = HashMap::new();
+        // environments stolen by peers (indexed by their ids) that they haven't picked
+        // up yet, cancellations of such environments are forwarded to the thieves
+        let mut handed_over: HashMap<EnvId, usize> = HashMap::new();
+        let mut next_steal = Instant::now();
+        // instructions the environment in front has evaluated in a row
+        let mut slices = 0;
 
         loop {
+            self.adopt(&mut envs);
             match envs.pop_front() {
                 Some((pid, mut env, chan)) => {
-                    let program = env.program[env.program.len() - 1];
-                    match self.pass(&mut env, pid.clone()) {
+                    slices += 1;
+                    let depth = env.program.len();
+                    let program = env.program[depth - 1];
+                    let result = match limit_exceeded(&env) {
+                        Some(err) => Err(err),
+                        None => self.pass(&mut env, pid.clone()),
+                    };
+                    match result {
                         Err(Error::Reschedule) => {
+                            env.program.truncate(depth - 1);
                             env.program.push(program);
+                            slices = 0;
                             envs.push_back((pid, env, chan));
                         }
+                        Err(Error::Park(deadline)) => {
+                            env.program.truncate(depth - 1);
+                            env.program.push(program);
+                            // wake up in time to fail once the time limit is exceeded
+                            let deadline = match (deadline, env.deadline) {
+                                (Some(deadline), Some(limit)) => Some(::std::cmp::min(deadline, limit)),
+                                (deadline, limit) => deadline.or(limit),
+                            };
+                            slices = 0;
+                            parked.insert(pid, (env, chan, deadline));
+                        }
                         Err(err) => {
+                            slices = 0;
+                            env.instructions += 1;
                             self.dispatcher.done(&mut env, pid);
                             let stack_size = env.stack_size;
+                            let memory = env.memory_usage();
                             let _ = chan.send(ResponseMessage::EnvFailed(pid,
                                                                          err,
                                                                          Some(env.stack_copy()),
-                                                                         Some(stack_size)));
+                                                                         Some(stack_size),
+                                                                         memory));
                         }
                         Ok(()) => {
+                            env.instructions += 1;
                             if env.program.is_empty() ||
                                 (env.program.len() == 1 && env.program[0].len() == 0) {
+                                slices = 0;
                                 self.dispatcher.done(&mut env, pid);
                                 let stack_size = env.stack_size;
+                                let memory = env.memory_usage();
                                 let _ = chan.send(ResponseMessage::EnvTerminated(pid,
                                                                                  env.stack_copy(),
-                                                                                 stack_size));
+                                                                                 stack_size,
+                                                                                 memory));
+                            } else if slices < env.priority.slices() {
+                                // higher priority environments get more slices per round
+                                envs.push_front((pid, env, chan));
                             } else {
+                                slices = 0;
                                 envs.push_back((pid, env, chan));
                             }
                         }
@@ -350,8 +738,38 @@ impl<'a, T: Dispatcher<'a>> Scheduler<'a, T> {
                 }
                 None => (),
             }
+            if !parked.is_empty() {
+                let now = Instant::now();
+                let expired: Vec<EnvId> = parked.iter()
+                    .filter(|&(_, &(_, _, deadline))| deadline.map_or(false, |deadline| deadline <= now))
+                    .map(|(pid, _)| pid.clone()).collect();
+                for pid in expired {
+                    let (env, chan, _) = parked.remove(&pid).unwrap();
+                    envs.push_back((pid, env, chan));
+                }
+            }
+            self.load.store(envs.len() + parked.len(), Ordering::Relaxed);
             let message = if envs.len() == 0 {
-                self.inbox.recv()
+                let mut deadline = parked.values().filter_map(|&(_, _, deadline)| deadline).min();
+                if !self.peers.is_empty() {
+                    let now = Instant::now();
+                    if next_steal <= now {
+                        self.steal();
+                        next_steal = now + Duration::from_millis(STEAL_INTERVAL);
+                    }
+                    deadline = Some(deadline.map_or(next_steal, |deadline| ::std::cmp::min(deadline, next_steal)));
+                }
+                match deadline {
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        let timeout = if deadline > now { deadline - now } else { Duration::from_millis(0) };
+                        match self.inbox.recv_timeout(timeout) {
+                            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                            msg => msg.map_err(|_| mpsc::RecvError {}),
+                        }
+                    },
+                    None => self.inbox.recv(),
+                }
             } else {
                 let msg = self.inbox.try_recv();
                 if let Err(mpsc::TryRecvError::Empty) = msg {
@@ -362,10 +780,73 @@ impl<'a, T: Dispatcher<'a>> Scheduler<'a, T> {
             match message {
                 Err(err) => panic!("error receiving: {:?}", err),
                 Ok(RequestMessage::Shutdown) => break,
-                Ok(RequestMessage::ScheduleEnv(pid, program, chan, cb)) => {
+                Ok(RequestMessage::Wake(pid)) => {
+                    if let Some((env, chan, _)) = parked.remove(&pid) {
+                        envs.push_back((pid, env, chan));
+                    }
+                }
+                Ok(RequestMessage::Steal(thief)) => {
+                    // keep at least one environment for itself
+                    if envs.len() > 1 {
+                        let dispatcher = &mut self.dispatcher;
+                        let position = envs.iter().rposition(|&(pid, _, _)| dispatcher.movable(pid));
+                        if let (Some(position), Some(victim), Some(peer)) = (position, self.position, self.peers.get(thief)) {
+                            slices = 0;
+                            let runnable = envs.remove(position).unwrap();
+                            let pid = runnable.0;
+                            match peer.stolen.send((victim, runnable)) {
+                                Ok(()) => {
+                                    handed_over.insert(pid, thief);
+                                    peer.sender.load.fetch_add(1, Ordering::Relaxed);
+                                    let _ = peer.sender.sender.send(RequestMessage::Adopt);
+                                }
+                                Err(mpsc::SendError((_, runnable))) => envs.push_back(runnable),
+                            }
+                        }
+                    }
+                }
+                // stolen environments are picked up at the beginning of the loop
+                Ok(RequestMessage::Adopt) => (),
+                Ok(RequestMessage::Adopted(pid)) => {
+                    handed_over.remove(&pid);
+                }
+                Ok(RequestMessage::Cancel(pid)) => {
+                    slices = 0;
+                    // the environment might have been stolen already, but not picked up yet
+                    self.adopt(&mut envs);
+                    // or stolen from this scheduler (the thief might have
+                    // received the cancellation before the environment)
+                    if let Some(thief) = handed_over.remove(&pid) {
+                        if let Some(peer) = self.peers.get(thief) {
+                            let _ = peer.sender.sender.send(RequestMessage::Cancel(pid));
+                        }
+                    }
+                    let cancelled = match envs.iter().position(|&(ref id, _, _)| *id == pid) {
+                        Some(index) => envs.remove(index).map(|(_, env, chan)| (env, chan)),
+                        None => parked.remove(&pid).map(|(env, chan, _)| (env, chan)),
+                    };
+                    if let Some((mut env, chan)) = cancelled {
+                        self.dispatcher.done(&mut env, pid);
+                        let vec = Vec::new();
+                        let err = error_program!("Cancelled".as_bytes(), &vec, ERROR_CANCELLED);
+                        let stack_size = env.stack_size;
+                        let memory = env.memory_usage();
+                        let _ = chan.send(ResponseMessage::EnvFailed(pid,
+                                                                     err,
+                                                                     Some(env.stack_copy()),
+                                                                     Some(stack_size),
+                                                                     memory));
+                    }
+                }
+                Ok(RequestMessage::ScheduleEnv(pid, program, priority, chan, cb)) => {
                     match Env::new() {
                         Ok(mut env) => {
                             env.set_published_message_callback(cb);
+                            env.set_waker(Waker { env_id: pid, sender: self.sender.clone() });
+                            env.max_instructions = self.limits.max_instructions;
+                            env.deadline = self.limits.max_duration.map(|duration| Instant::now() + duration);
+                            env.max_memory = self.limits.max_memory;
+                            env.priority = priority;
                             match env.alloc(program.len()) {
                                 Ok(slice) => {
                                     slice.copy_from_slice(program.as_slice());
@@ -375,12 +856,12 @@ impl<'a, T: Dispatcher<'a>> Scheduler<'a, T> {
                                 }
                                 Err(err) => {
                                     let _ =
-                                        chan.send(ResponseMessage::EnvFailed(pid, err, None, None));
+                                        chan.send(ResponseMessage::EnvFailed(pid, err, None, None, env.memory_usage()));
                                 }
                             }
                         }
                         Err(err) => {
-                            let _ = chan.send(ResponseMessage::EnvFailed(pid, err, None, None));
+                            let _ = chan.send(ResponseMessage::EnvFailed(pid, err, None, None, 0));
                         }
                     }
                 }
@@ -399,7 +880,9 @@ impl<'a, T: Dispatcher<'a>> Scheduler<'a, T> {
         }
         if let pumpkinscript::ParseResult::Done(rest, data) = binparser::data(program) {
             if env.aborting_try.is_empty() {
-                env.push(&data[offset_by_size(data.len())..]);
+                if let Err(err) = env.push(&data[offset_by_size(data.len())..]) {
+                    return handle_error!(env, err);
+                }
             }
             if rest.len() > 0 {
                 env.program.push(rest);
@@ -410,7 +893,10 @@ impl<'a, T: Dispatcher<'a>> Scheduler<'a, T> {
             if rest.len() > 0 {
                 env.program.push(rest);
             }
-            if instruction != TRY_END && !env.aborting_try.is_empty() {
+            // While aborting, only internal instructions are evaluated
+            // so that transactions and scopes opened within TRY are
+            // properly ended
+            if instruction[0] != 0x80 && !env.aborting_try.is_empty() {
                 return Ok(());
             }
 
@@ -429,7 +915,7 @@ impl<'a, T: Dispatcher<'a>> Scheduler<'a, T> {
     fn handle_dictionary(&mut self,
                          env: &mut Env<'a>,
                          instruction: &'a [u8],
-                         _: EnvId)
+                         pid: EnvId)
                          -> PassResult<'a> {
         if env.dictionary.contains_key(instruction) {
             {
@@ -438,7 +924,8 @@ impl<'a, T: Dispatcher<'a>> Scheduler<'a, T> {
             }
             Ok(())
         } else {
-            Err(Error::UnknownInstruction)
+            // stored procedures are consulted after the environment's own words
+            self.dispatcher.lookup(env, instruction, pid)
         }
     }
 
@@ -447,7 +934,7 @@ impl<'a, T: Dispatcher<'a>> Scheduler<'a, T> {
     fn handle_dictionary(&mut self,
                          env: &mut Env<'a>,
                          instruction: &'a [u8],
-                         _: EnvId)
+                         pid: EnvId)
                          -> PassResult<'a> {
         let mut found = false;
 
@@ -463,7 +950,8 @@ impl<'a, T: Dispatcher<'a>> Scheduler<'a, T> {
         if found {
             Ok(())
         } else {
-            Err(Error::UnknownInstruction)
+            // stored procedures are consulted after the environment's own scopes
+            self.dispatcher.lookup(env, instruction, pid)
         }
     }
 
@@ -481,29 +969,62 @@ impl<'a, T: Dispatcher<'a>> Scheduler<'a, T> {
     fn handle_try_end(&mut self,
                       env: &mut Env<'a>,
                       instruction: &'a [u8],
-                      pid: EnvId)
+                      _: EnvId)
                       -> PassResult<'a> {
         instruction_is!(instruction, TRY_END);
         env.tracking_errors -= 1;
         if env.aborting_try.is_empty() {
-            env.push(_EMPTY);
-            Ok(())
+            env.push(_EMPTY)
         } else if let Some(Error::ProgramError(err)) = env.aborting_try.pop() {
-            self.dispatcher.done(env, pid);
             let slice = alloc_and_write!(err.as_slice(), env);
-            env.push(slice);
-            Ok(())
+            env.push(slice)
         } else {
-            env.push(_EMPTY);
-            Ok(())
+            env.push(_EMPTY)
         }
     }
+
+    #[inline]
+    fn handle_limit_instructions(&mut self,
+                                 env: &mut Env<'a>,
+                                 instruction: &'a [u8],
+                                 _: EnvId)
+                                 -> PassResult<'a> {
+        instruction_is!(instruction, LIMIT_INSTRUCTIONS);
+        let v = stack_pop!(env);
+        let n = match BigUint::from_bytes_be(v).to_u64() {
+            Some(n) => n,
+            None => return Err(error_invalid_value!(v)),
+        };
+        // LIMIT/INSTRUCTIONS itself is not counted yet
+        let max = env.instructions.saturating_add(1).saturating_add(n);
+        env.max_instructions = Some(env.max_instructions.map_or(max, |current| ::std::cmp::min(current, max)));
+        Ok(())
+    }
+
+    #[inline]
+    fn handle_limit_time(&mut self,
+                         env: &mut Env<'a>,
+                         instruction: &'a [u8],
+                         _: EnvId)
+                         -> PassResult<'a> {
+        instruction_is!(instruction, LIMIT_TIME);
+        let v = stack_pop!(env);
+        let millis = match BigUint::from_bytes_be(v).to_u64() {
+            Some(millis) => millis,
+            None => return Err(error_invalid_value!(v)),
+        };
+        let deadline = Instant::now() + Duration::from_millis(millis);
+        env.deadline = Some(env.deadline.map_or(deadline, |current| ::std::cmp::min(current, deadline)));
+        Ok(())
+    }
 }
 
 impl<'a, T: Dispatcher<'a>> Dispatcher<'a> for Scheduler<'a, T> {
     fn handle(&mut self, env: &mut Env<'a>, instruction: &'a [u8], pid: EnvId) -> PassResult<'a> {
         try_instruction!(env, self.handle_try(env, instruction, pid));
         try_instruction!(env, self.handle_try_end(env, instruction, pid));
+        try_instruction!(env, self.handle_limit_instructions(env, instruction, pid));
+        try_instruction!(env, self.handle_limit_time(env, instruction, pid));
 
         try_instruction!(env, self.dispatcher.handle(env, instruction, pid));
 
@@ -522,6 +1043,7 @@ mod tests {
     use messaging;
     use nvmem::{MmapedFile, MmapedRegion, NonVolatileMemory};
     use script::{Env, Scheduler, Error, RequestMessage, ResponseMessage, EnvId, dispatcher};
+    use super::ERROR_LIMIT_EXCEEDED;
     use std::sync::mpsc;
     use std::sync::Arc;
     use timestamp;
@@ -608,6 +1130,69 @@ mod tests {
 
     }
 
+    #[test]
+    fn limit_instructions() {
+        eval!("10 LIMIT/INSTRUCTIONS [1] DOWHILE", env, result, {
+            assert!(matches!(result, Err(Error::ProgramError(ref err)) if err.ends_with(ERROR_LIMIT_EXCEEDED)));
+        });
+
+        // limits can only be lowered
+        eval!("[10 LIMIT/INSTRUCTIONS] TRY DROP 1000 LIMIT/INSTRUCTIONS [1] DOWHILE", env, result, {
+            assert!(matches!(result, Err(Error::ProgramError(ref err)) if err.ends_with(ERROR_LIMIT_EXCEEDED)));
+        });
+
+        eval!("100 LIMIT/INSTRUCTIONS 1 2", env, result, {
+            assert!(!result.is_err());
+        });
+    }
+
+    #[test]
+    fn least_loaded_scheduler() {
+        use super::{SchedulerSender, SchedulerHandle};
+        use std::sync::atomic::AtomicUsize;
+        let (busy, busy_inbox) = mpsc::channel();
+        let (idle, idle_inbox) = mpsc::channel();
+        let senders = vec![SchedulerSender { sender: busy, load: Arc::new(AtomicUsize::new(10)) },
+                           SchedulerSender { sender: idle, load: Arc::new(AtomicUsize::new(0)) }];
+        let (callback, _) = mpsc::channel::<ResponseMessage>();
+        let (msg_sender, _) = mpsc::channel::<(Vec<u8>, Vec<u8>)>();
+        senders.schedule_env(EnvId::new(), vec![], callback, Box::new(msg_sender));
+        assert!(busy_inbox.try_recv().is_err());
+        assert!(idle_inbox.try_recv().is_ok());
+        assert_eq!(senders[1].load(), 1);
+
+        // a single scheduler
+        let senders = vec![senders[0].clone()];
+        let (callback, _) = mpsc::channel::<ResponseMessage>();
+        let (msg_sender, _) = mpsc::channel::<(Vec<u8>, Vec<u8>)>();
+        senders.schedule_env(EnvId::new(), vec![], callback, Box::new(msg_sender));
+        assert!(busy_inbox.try_recv().is_ok());
+    }
+
+    #[test]
+    fn scheduler_pools() {
+        use super::{SchedulerPools, SchedulerHandle, Priority};
+        let (normal, normal_inbox) = mpsc::channel();
+        let (high, high_inbox) = mpsc::channel();
+        let mut pools = SchedulerPools::new(vec![normal]);
+        pools.insert(Priority::High, vec![high]);
+        for &priority in [Priority::High, Priority::Low].iter() {
+            let (callback, _) = mpsc::channel::<ResponseMessage>();
+            let (msg_sender, _) = mpsc::channel::<(Vec<u8>, Vec<u8>)>();
+            pools.schedule_env_with_priority(EnvId::new(), vec![], priority, callback, Box::new(msg_sender));
+        }
+        assert!(matches!(high_inbox.try_recv(), Ok(RequestMessage::ScheduleEnv(_, _, Priority::High, _, _))));
+        // there's no pool for low priority requests
+        assert!(matches!(normal_inbox.try_recv(), Ok(RequestMessage::ScheduleEnv(_, _, Priority::Low, _, _))));
+    }
+
+    #[test]
+    fn limit_time() {
+        eval!("10 LIMIT/TIME [1] DOWHILE", env, result, {
+            assert!(matches!(result, Err(Error::ProgramError(ref err)) if err.ends_with(ERROR_LIMIT_EXCEEDED)));
+        });
+    }
+
     use test::Bencher;
 
     #[bench]
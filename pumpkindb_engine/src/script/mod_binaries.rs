@@ -28,6 +28,9 @@ pub struct Handler<'a> {
 builtins!("mod_binaries.builtins");
 
 impl<'a> Dispatcher<'a> for Handler<'a> {
+    fn movable(&mut self, _: EnvId) -> bool {
+        true
+    }
     fn handle(&mut self, env: &mut Env<'a>, instruction: &'a [u8], pid: EnvId) -> PassResult<'a> {
         try_instruction!(env, self.handle_builtins(env, instruction, pid));
         try_instruction!(env, self.handle_ltp(env, instruction, pid));
@@ -59,9 +62,9 @@ impl<'a> Handler<'a> {
         let b = stack_pop!(env);
 
         if a == b {
-            env.push(STACK_TRUE);
+            try!(env.push(STACK_TRUE));
         } else {
-            env.push(STACK_FALSE);
+            try!(env.push(STACK_FALSE));
         }
 
         Ok(())
@@ -74,9 +77,9 @@ impl<'a> Handler<'a> {
         let b = stack_pop!(env);
 
         if b < a {
-            env.push(STACK_TRUE);
+            try!(env.push(STACK_TRUE));
         } else {
-            env.push(STACK_FALSE);
+            try!(env.push(STACK_FALSE));
         }
 
         Ok(())
@@ -89,9 +92,9 @@ impl<'a> Handler<'a> {
         let b = stack_pop!(env);
 
         if b > a {
-            env.push(STACK_TRUE);
+            try!(env.push(STACK_TRUE));
         } else {
-            env.push(STACK_FALSE);
+            try!(env.push(STACK_FALSE));
         }
 
         Ok(())
@@ -112,7 +115,7 @@ impl<'a> Handler<'a> {
         slice[0..b.len()].copy_from_slice(b);
         slice[b.len()..b.len() + a.len()].copy_from_slice(a);
 
-        env.push(slice);
+        try!(env.push(slice));
 
         Ok(())
     }
@@ -144,7 +147,7 @@ impl<'a> Handler<'a> {
             return Err(error_invalid_value!(end));
         }
 
-        env.push(&slice[start_int..end_int]);
+        try!(env.push(&slice[start_int..end_int]));
 
         Ok(())
     }
@@ -177,7 +180,7 @@ impl<'a> Handler<'a> {
         }
         slice[size_int - value.len()..].copy_from_slice(value);
 
-        env.push(slice);
+        try!(env.push(slice));
 
         Ok(())
     }
@@ -196,7 +199,7 @@ impl<'a> Handler<'a> {
 
         let slice = alloc_and_write!(len_bytes.as_slice(), env);
 
-        env.push(slice);
+        try!(env.push(slice));
 
         Ok(())
     }
@@ -44,6 +44,9 @@ pub struct Handler<'a> {
 }
 
 impl<'a> Dispatcher<'a> for Handler<'a> {
+    fn movable(&mut self, _: EnvId) -> bool {
+        true
+    }
     fn handle(&mut self, env: &mut Env<'a>, instruction: &'a [u8], pid: EnvId) -> PassResult<'a> {
         try_instruction!(env, self.handle_builtins(env, instruction, pid));
         try_instruction!(env, self.handle_dowhile(env, instruction, pid));
@@ -78,9 +81,9 @@ impl<'a> Handler<'a> {
         let a = stack_pop!(env);
 
         if a == STACK_TRUE {
-            env.push(STACK_FALSE);
+            try!(env.push(STACK_FALSE));
         } else if a == STACK_FALSE {
-            env.push(STACK_TRUE);
+            try!(env.push(STACK_TRUE));
         } else {
             return Err(error_invalid_value!(a));
         }
@@ -102,9 +105,9 @@ impl<'a> Handler<'a> {
         }
 
         if a == STACK_TRUE && b == STACK_TRUE {
-            env.push(STACK_TRUE);
+            try!(env.push(STACK_TRUE));
         } else if a == STACK_FALSE || b == STACK_FALSE {
-            env.push(STACK_FALSE);
+            try!(env.push(STACK_FALSE));
         }
 
         Ok(())
@@ -124,9 +127,9 @@ impl<'a> Handler<'a> {
         }
 
         if a == STACK_TRUE || b == STACK_TRUE {
-            env.push(STACK_TRUE);
+            try!(env.push(STACK_TRUE));
         } else {
-            env.push(STACK_FALSE);
+            try!(env.push(STACK_FALSE));
         }
 
         Ok(())
@@ -216,9 +219,9 @@ impl<'a> Handler<'a> {
         instruction_is!(instruction, EVAL_VALIDP);
         let a = stack_pop!(env);
         if parse_bin(a).is_ok() {
-            env.push(STACK_TRUE);
+            try!(env.push(STACK_TRUE));
         } else {
-            env.push(STACK_FALSE);
+            try!(env.push(STACK_FALSE));
         }
         Ok(())
     }
@@ -336,12 +339,12 @@ impl<'a> Handler<'a> {
         #[cfg(feature = "scoped_dictionary")]
         {
             if name == "scoped_dictionary".as_bytes() {
-                env.push(STACK_TRUE);
+                try!(env.push(STACK_TRUE));
                 return Ok(());
             }
         }
 
-        env.push(STACK_FALSE);
+        try!(env.push(STACK_FALSE));
 
         Ok(())
     }
@@ -46,13 +46,16 @@ macro_rules! hash_instruction {
         hasher.input(a);
         let mut slice = alloc_slice!($size, env);
         hasher.result(&mut slice);
-        env.push(slice);
+        try!(env.push(slice));
         Ok(())
     }
     };
 }
 
 impl<'a> Dispatcher<'a> for Handler<'a> {
+    fn movable(&mut self, _: EnvId) -> bool {
+        true
+    }
     fn handle(&mut self, env: &mut Env<'a>, instruction: &'a [u8], pid: EnvId) -> PassResult<'a> {
         try_instruction!(env, self.handle_hash_sha1(env, instruction, pid));
         try_instruction!(env, self.handle_hash_sha224(env, instruction, pid));
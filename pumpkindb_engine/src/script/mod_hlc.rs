@@ -25,12 +25,18 @@ use byteorder::{BigEndian, WriteBytesExt};
 use std::sync::Arc;
 use super::super::nvmem::NonVolatileMemory;
 
+/// Size of an encoded HLC timestamp
+pub const HLC_SIZE: usize = 16;
+
 pub struct Handler<'a, N> where N : NonVolatileMemory {
     phantom: PhantomData<&'a ()>,
     timestamp: Arc<timestamp::Timestamp<N>>,
 }
 
 impl<'a, N> Dispatcher<'a> for Handler<'a, N> where N : NonVolatileMemory {
+    fn movable(&mut self, _: EnvId) -> bool {
+        true
+    }
     fn handle(&mut self, env: &mut Env<'a>, instruction: &'a [u8], pid: EnvId) -> PassResult<'a> {
         try_instruction!(env, self.handle_hlc(env, instruction, pid));
         try_instruction!(env, self.handle_hlc_lc(env, instruction, pid));
@@ -53,9 +59,9 @@ impl<'a, N> Handler<'a, N> where N : NonVolatileMemory {
     pub fn handle_hlc(&self, env: &mut Env<'a>, instruction: &'a [u8], _: EnvId) -> PassResult<'a> {
         instruction_is!(instruction, HLC);
         let now = self.timestamp.hlc();
-        let slice = alloc_slice!(16, env);
+        let slice = alloc_slice!(HLC_SIZE, env);
         let _ = now.write_bytes(&mut slice[0..]).unwrap();
-        env.push(slice);
+        try!(env.push(slice));
         Ok(())
     }
 
@@ -84,9 +90,9 @@ impl<'a, N> Handler<'a, N> where N : NonVolatileMemory {
         let mut t1 = t1_.unwrap();
         t1.count += 1;
 
-        let slice = alloc_slice!(16, env);
+        let slice = alloc_slice!(HLC_SIZE, env);
         let _ = t1.write_bytes(&mut slice[0..]).unwrap();
-        env.push(slice);
+        try!(env.push(slice));
 
         Ok(())
     }
@@ -117,7 +123,7 @@ impl<'a, N> Handler<'a, N> where N : NonVolatileMemory {
         let slice = alloc_slice!(4, env);
         let _ = (&mut slice[0..]).write_u32::<BigEndian>(t1.count);
 
-        env.push(slice);
+        try!(env.push(slice));
 
         Ok(())
     }
@@ -135,10 +141,10 @@ impl<'a, N> Handler<'a, N> where N : NonVolatileMemory {
                     return Err(error_invalid_value!(observed_bytes));
                 }
 
-                let slice = alloc_slice!(16, env);
+                let slice = alloc_slice!(HLC_SIZE, env);
                 let _ = self.timestamp.hlc().write_bytes(&mut slice[0..]).unwrap();
 
-                env.push(slice);
+                try!(env.push(slice));
 
                 Ok(())
             } else {
@@ -40,8 +40,8 @@ macro_rules! json_is_a {
         let a = stack_pop!($env);
 
         match json::from_slice::<json::Value>(a) {
-            Ok(json::Value::$t) => $env.push(STACK_TRUE),
-            _ => $env.push(STACK_FALSE),
+            Ok(json::Value::$t) => try!($env.push(STACK_TRUE)),
+            _ => try!($env.push(STACK_FALSE)),
         }
 
         Ok(())
@@ -51,8 +51,8 @@ macro_rules! json_is_a {
         let a = stack_pop!($env);
 
         match json::from_slice::<json::Value>(a) {
-            Ok(json::Value::$t(_)) => $env.push(STACK_TRUE),
-            _ => $env.push(STACK_FALSE),
+            Ok(json::Value::$t(_)) => try!($env.push(STACK_TRUE)),
+            _ => try!($env.push(STACK_FALSE)),
         }
 
         Ok(())
@@ -62,6 +62,9 @@ macro_rules! json_is_a {
 builtins!("mod_json.builtins");
 
 impl<'a> Dispatcher<'a> for Handler<'a> {
+    fn movable(&mut self, _: EnvId) -> bool {
+        true
+    }
     fn handle(&mut self, env: &mut Env<'a>, instruction: &'a [u8], pid: EnvId) -> PassResult<'a> {
         try_instruction!(env, self.handle_builtins(env, instruction, pid));
         try_instruction!(env, self.handle_jsonq(env, instruction, pid));
@@ -97,8 +100,8 @@ impl<'a> Handler<'a> {
         let a = stack_pop!(env);
 
         match json::from_slice::<json::Value>(a) {
-            Ok(_) => env.push(STACK_TRUE),
-            Err(_) => env.push(STACK_FALSE),
+            Ok(_) => try!(env.push(STACK_TRUE)),
+            Err(_) => try!(env.push(STACK_FALSE)),
         }
 
         Ok(())
@@ -181,7 +184,7 @@ impl<'a> Handler<'a> {
                     Some(val) => {
                         let s = val.to_string();
                         let val = alloc_and_write!(s.as_bytes(), env);
-                        env.push(val);
+                        try!(env.push(val));
                     }
                     None => return Err(error_invalid_value!(field)),
                 }
@@ -212,9 +215,9 @@ impl<'a> Handler<'a> {
         match json::from_slice::<json::Value>(a) {
             Ok(json::Value::Object(map)) => {
                 if map.contains_key(&key) {
-                    env.push(STACK_TRUE);
+                    try!(env.push(STACK_TRUE));
                 } else {
-                    env.push(STACK_FALSE);
+                    try!(env.push(STACK_FALSE));
                 }
             }
             Ok(_) => return Err(error_invalid_value!(a)),
@@ -251,7 +254,7 @@ impl<'a> Handler<'a> {
                 map.insert(key, value);
                 let s = json::Value::Object(map).to_string();
                 let val = alloc_and_write!(s.as_bytes(), env);
-                env.push(val);
+                try!(env.push(val));
             }
             Ok(_) => return Err(error_invalid_value!(a)),
             Err(_) => return Err(error_invalid_value!(a)),
@@ -273,7 +276,7 @@ impl<'a> Handler<'a> {
         match json::from_slice::<json::Value>(a) {
             Ok(json::Value::String(val)) => {
                 let val = alloc_and_write!(val.as_bytes(), env);
-                env.push(val);
+                try!(env.push(val));
             }
             Ok(_) => return Err(error_invalid_value!(a)),
             Err(_) => return Err(error_invalid_value!(a)),
@@ -299,7 +302,7 @@ impl<'a> Handler<'a> {
 
         let str = json::Value::String(s).to_string();
         let val = alloc_and_write!(str.as_bytes(), env);
-        env.push(val);
+        try!(env.push(val));
 
         Ok(())
     }
@@ -8,6 +8,7 @@ use super::{Env, EnvId, Dispatcher, PassResult, Error, ERROR_EMPTY_STACK, offset
 use super::super::messaging;
 
 use std::marker::PhantomData;
+use std::collections::HashMap;
 
 instruction!(PUBLISH, (a, b => ), b"\x87PUBLISH");
 instruction!(SUBSCRIBE, (a => ), b"\x89SUBSCRIBE");
@@ -16,10 +17,21 @@ instruction!(UNSUBSCRIBE, (a => ), b"\x8bUNSUBSCRIBE");
 pub struct Handler<'a, P: messaging::Publisher, S: messaging::Subscriber> {
     publisher: P,
     subscriber: S,
+    // Subscriptions made by running environments (that haven't
+    // been cancelled by them), such environments are not movable
+    subscriptions: HashMap<EnvId, Vec<Vec<u8>>>,
     phantom: PhantomData<&'a ()>,
 }
 
 impl<'a, P: messaging::Publisher, S: messaging::Subscriber> Dispatcher<'a> for Handler<'a, P, S> {
+    fn done(&mut self, _: &mut Env<'a>, pid: EnvId) {
+        self.subscriptions.remove(&pid);
+    }
+
+    fn movable(&mut self, pid: EnvId) -> bool {
+        !self.subscriptions.contains_key(&pid)
+    }
+
     fn handle(&mut self, env: &mut Env<'a>, instruction: &'a [u8], pid: EnvId) -> PassResult<'a> {
         try_instruction!(env, self.handle_publish(env, instruction, pid));
         try_instruction!(env, self.handle_subscribe(env, instruction, pid));
@@ -33,6 +45,7 @@ impl<'a, P: messaging::Publisher, S: messaging::Subscriber> Handler<'a, P, S> {
         Handler {
             publisher: publisher,
             subscriber: subscriber,
+            subscriptions: HashMap::new(),
             phantom: PhantomData,
         }
     }
@@ -48,6 +61,7 @@ impl<'a, P: messaging::Publisher, S: messaging::Subscriber> Handler<'a, P, S> {
         let data = stack_pop!(env);
 
         self.publisher.publish(topic, data);
+        env.side_effects += 1;
 
         Ok(())
     }
@@ -56,7 +70,7 @@ impl<'a, P: messaging::Publisher, S: messaging::Subscriber> Handler<'a, P, S> {
     fn handle_subscribe(&mut self,
                       env: &mut Env<'a>,
                       instruction: &'a [u8],
-                      _: EnvId)
+                      pid: EnvId)
                       -> PassResult<'a> {
         instruction_is!(instruction, SUBSCRIBE);
 
@@ -66,8 +80,10 @@ impl<'a, P: messaging::Publisher, S: messaging::Subscriber> Handler<'a, P, S> {
             None => (),
             Some(cb) => {
                 let ident = self.subscriber.subscribe(topic, cb);
+                env.side_effects += 1;
+                self.subscriptions.entry(pid).or_insert_with(Vec::new).push(ident.clone());
                 let slice = alloc_and_write!(&ident, env);
-                env.push(slice);
+                try!(env.push(slice));
             }
         }
 
@@ -78,13 +94,24 @@ impl<'a, P: messaging::Publisher, S: messaging::Subscriber> Handler<'a, P, S> {
     fn handle_unsubscribe(&mut self,
                         env: &mut Env<'a>,
                         instruction: &'a [u8],
-                        _: EnvId)
+                        pid: EnvId)
                         -> PassResult<'a> {
         instruction_is!(instruction, UNSUBSCRIBE);
 
         let identifier = stack_pop!(env);
 
         self.subscriber.unsubscribe(identifier);
+        env.side_effects += 1;
+        let empty = match self.subscriptions.get_mut(&pid) {
+            Some(subscriptions) => {
+                subscriptions.retain(|ident| ident.as_slice() != identifier);
+                subscriptions.is_empty()
+            },
+            None => false,
+        };
+        if empty {
+            self.subscriptions.remove(&pid);
+        }
 
         Ok(())
     }
@@ -97,9 +124,12 @@ mod tests {
 
     use pumpkinscript::parse;
     use messaging;
-    use script::{Env, Scheduler, Error, RequestMessage, ResponseMessage, EnvId, dispatcher};
+    use script::{Env, Scheduler, Error, RequestMessage, ResponseMessage, EnvId, dispatcher,
+                 Dispatcher, PassResult, SchedulerHandle};
+    use super::Handler;
     use std::sync::mpsc;
-    use std::sync::Arc;
+    use std::sync::{Arc, Mutex};
+    use std::collections::HashSet;
     use std::fs;
     use tempdir::TempDir;
     use lmdb;
@@ -151,5 +181,79 @@ mod tests {
 
     }
 
+    // Records which scheduler evaluates instructions of which environment
+    struct Recording<'a> {
+        scheduler: usize,
+        msg: Handler<'a, messaging::SimpleAccessor, messaging::SimpleAccessor>,
+        evaluated: Arc<Mutex<HashSet<(EnvId, usize)>>>,
+    }
+
+    impl<'a> Dispatcher<'a> for Recording<'a> {
+        fn done(&mut self, env: &mut Env<'a>, pid: EnvId) {
+            self.msg.done(env, pid)
+        }
+
+        fn movable(&mut self, pid: EnvId) -> bool {
+            self.msg.movable(pid)
+        }
+
+        fn handle(&mut self, env: &mut Env<'a>, instruction: &'a [u8], pid: EnvId) -> PassResult<'a> {
+            self.evaluated.lock().unwrap().insert((pid, self.scheduler));
+            self.msg.handle(env, instruction, pid)
+        }
+    }
+
+    #[test]
+    fn steal_subscribed() {
+        let busy = "\"Hello\" \"Topic1\" PUBLISH ".repeat(20_000);
+        let subscribed = parse(&format!("\"Topic\" SUBSCRIBE {}", busy)).unwrap();
+        let unsubscribed = parse(&busy).unwrap();
+        let ids = [EnvId::new(), EnvId::new(), EnvId::new()];
+        let evaluated = Arc::new(Mutex::new(HashSet::new()));
+
+        let mut simple = messaging::Simple::new();
+        let accessor = simple.accessor();
+        crossbeam::scope(|scope| {
+            let publisher_thread = scope.spawn(move || simple.run());
+            let mut schedulers: Vec<_> = (0..2).map(|i| Scheduler::new(Recording {
+                scheduler: i,
+                msg: Handler::new(accessor.clone(), accessor.clone()),
+                evaluated: evaluated.clone(),
+            }).0).collect();
+            let peers: Vec<_> = schedulers.iter().map(|scheduler| scheduler.peer()).collect();
+            let senders: Vec<_> = schedulers.iter().map(|scheduler| scheduler.sender()).collect();
+            for scheduler in schedulers.iter_mut() {
+                scheduler.join(peers.clone());
+            }
+            let handles: Vec<_> = schedulers.into_iter()
+                .map(|mut scheduler| scope.spawn(move || scheduler.run())).collect();
+
+            // the first scheduler is the busiest one, the second one will steal from it
+            let (callback, receiver) = mpsc::channel::<ResponseMessage>();
+            let (sender0, _receiver0) = mpsc::channel();
+            senders[0].schedule_env(ids[0], subscribed.clone(), callback.clone(), Box::new(sender0.clone()));
+            senders[0].schedule_env(ids[1], subscribed.clone(), callback.clone(), Box::new(sender0.clone()));
+            senders[0].schedule_env(ids[2], unsubscribed.clone(), callback.clone(), Box::new(sender0.clone()));
+            for _ in 0..ids.len() {
+                match receiver.recv() {
+                    Ok(ResponseMessage::EnvTerminated(_, _, _, _)) => (),
+                    Ok(ResponseMessage::EnvFailed(_, err, _, _, _)) => panic!("error: {:?}", err),
+                    Err(err) => panic!("recv error: {:?}", err),
+                }
+            }
+            senders.shutdown();
+            accessor.shutdown();
+            for handle in handles {
+                let _ = handle.join();
+            }
+            let _ = publisher_thread.join();
+        });
+
+        let evaluated = evaluated.lock().unwrap();
+        // environments with subscriptions stay where they are
+        assert!(!evaluated.contains(&(ids[0], 1)));
+        assert!(!evaluated.contains(&(ids[1], 1)));
+        assert!(evaluated.contains(&(ids[2], 1)));
+    }
 
 }
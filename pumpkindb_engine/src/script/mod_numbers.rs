@@ -86,9 +86,9 @@ macro_rules! uint_comparison {
         let b_: BigUint = b.unpack().ok_or(error_invalid_value!(b))?;
 
         if a_.$cmp(&b_) {
-            $env.push(STACK_TRUE);
+            try!($env.push(STACK_TRUE));
         } else {
-            $env.push(STACK_FALSE);
+            try!($env.push(STACK_FALSE));
         }
         Ok(())
     }};
@@ -104,9 +104,9 @@ macro_rules! int_comparison {
         let b_: BigInt = b.unpack().ok_or(error_invalid_value!(b))?;
 
         if a_.$cmp(&b_) {
-            $env.push(STACK_TRUE);
+            try!($env.push(STACK_TRUE));
         } else {
-            $env.push(STACK_FALSE);
+            try!($env.push(STACK_FALSE));
         }
         Ok(())
     }};
@@ -140,7 +140,7 @@ macro_rules! no_endianness_sized_uint_op {
         }
 
         let slice = alloc_and_write!(c_bytes.as_slice(), $env);
-        $env.push(slice);
+        try!($env.push(slice));
         Ok(())
     }};
 }
@@ -180,7 +180,7 @@ macro_rules! no_endianness_sized_int_op {
         c_bytes[0] ^= 1u8 << 7;
 
         let slice = alloc_and_write!(c_bytes.as_slice(), $env);
-        $env.push(slice);
+        try!($env.push(slice));
         Ok(())
     }};
 }
@@ -212,7 +212,7 @@ macro_rules! sized_uint_op {
         }
 
         let slice = alloc_and_write!(c_bytes.as_slice(), $env);
-        $env.push(slice);
+        try!($env.push(slice));
         Ok(())
     }};
 }
@@ -252,7 +252,7 @@ macro_rules! sized_int_op {
         c_bytes[0] ^= 1u8 << 7;
 
         let slice = alloc_and_write!(c_bytes.as_slice(), $env);
-        $env.push(slice);
+        try!($env.push(slice));
         Ok(())
     }};
 }
@@ -272,6 +272,9 @@ pub struct Handler<'a> {
 }
 
 impl<'a> Dispatcher<'a> for Handler<'a> {
+    fn movable(&mut self, _: EnvId) -> bool {
+        true
+    }
     fn handle(&mut self, env: &mut Env<'a>, instruction: &'a [u8], pid: EnvId) -> PassResult<'a> {
         try_instruction!(env, self.handle_uint_add(env, instruction, pid));
         try_instruction!(env, self.handle_uint_sub(env, instruction, pid));
@@ -335,7 +338,7 @@ impl<'a> Handler<'a> {
         let c_int = a_int.add(b_int);
 
         let slice = alloc_and_write!(c_int.pack().as_slice(), env);
-        env.push(slice);
+        try!(env.push(slice));
         Ok(())
     }
 
@@ -354,7 +357,7 @@ impl<'a> Handler<'a> {
         let c_int = a_int.add(b_int);
 
         let slice = alloc_and_write!(c_int.pack().as_slice(), env);
-        env.push(slice);
+        try!(env.push(slice));
         Ok(())
     }
 
@@ -373,7 +376,7 @@ impl<'a> Handler<'a> {
         let c_int = a_int.sub(b_int);
 
         let slice = alloc_and_write!(c_int.pack().as_slice(), env);
-        env.push(slice);
+        try!(env.push(slice));
         Ok(())
     }
 
@@ -388,7 +391,7 @@ impl<'a> Handler<'a> {
 
         let a_uint = a_int.to_biguint().ok_or(error_invalid_value!(a))?;
         let slice = alloc_and_write!(a_uint.pack().as_slice(), env);
-        env.push(slice);
+        try!(env.push(slice));
         Ok(())
     }
 
@@ -406,7 +409,7 @@ impl<'a> Handler<'a> {
         bytes.extend_from_slice(&a_bytes);
         let slice = alloc_and_write!(bytes.as_slice(), env);
 
-        env.push(slice);
+        try!(env.push(slice));
         Ok(())
     }
 
@@ -431,7 +434,7 @@ impl<'a> Handler<'a> {
 
         let c_bytes = c_uint.to_bytes_be();
         let slice = alloc_and_write!(c_bytes.as_slice(), env);
-        env.push(slice);
+        try!(env.push(slice));
         Ok(())
     }
 
@@ -664,7 +667,7 @@ impl<'a> Handler<'a> {
 
         let bytes = (a + b).pack();
         let slice = alloc_and_write!(bytes.as_slice(), env);
-        env.push(slice);
+        try!(env.push(slice));
         
         Ok(())                  
     }
@@ -684,7 +687,7 @@ impl<'a> Handler<'a> {
         
         let bytes = (b - a).pack();
         let slice = alloc_and_write!(bytes.as_slice(), env);
-        env.push(slice);
+        try!(env.push(slice));
 
         Ok(())
     }
@@ -704,7 +707,7 @@ impl<'a> Handler<'a> {
         
         let bytes = (a + b).pack();
         let slice = alloc_and_write!(bytes.as_slice(), env);
-        env.push(slice);
+        try!(env.push(slice));
         
         Ok(())                  
     }
@@ -724,7 +727,7 @@ impl<'a> Handler<'a> {
         
         let bytes = (b - a).pack();
         let slice = alloc_and_write!(bytes.as_slice(), env);
-        env.push(slice);
+        try!(env.push(slice));
 
         Ok(())
     }
@@ -742,7 +745,7 @@ impl<'a> Handler<'a> {
 
         let s = format!("{}", a);
         let val = alloc_and_write!(s.as_bytes(), env);
-        env.push(val);
+        try!(env.push(val));
 
         Ok(())
     }
@@ -760,7 +763,7 @@ impl<'a> Handler<'a> {
 
         let s = format!("{}", a);
         let val = alloc_and_write!(s.as_bytes(), env);
-        env.push(val);
+        try!(env.push(val));
 
         Ok(())
     }
@@ -788,7 +791,7 @@ impl<'a> Handler<'a> {
         };
         
         let val = alloc_and_write!(s.as_bytes(), env);
-        env.push(val);
+        try!(env.push(val));
 
 
         Ok(())
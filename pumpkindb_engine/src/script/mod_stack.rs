@@ -32,6 +32,9 @@ pub struct Handler<'a> {
 builtins!("mod_stack.builtins");
 
 impl<'a> Dispatcher<'a> for Handler<'a> {
+    fn movable(&mut self, _: EnvId) -> bool {
+        true
+    }
     fn handle(&mut self, env: &mut Env<'a>, instruction: &'a [u8], pid: EnvId) -> PassResult<'a> {
         try_instruction!(env, self.handle_builtins(env, instruction, pid));
         try_instruction!(env, self.handle_drop(env, instruction, pid));
@@ -61,8 +64,8 @@ impl<'a> Handler<'a> {
         instruction_is!(instruction, DUP);
         let v = stack_pop!(env);
 
-        env.push(v);
-        env.push(v);
+        try!(env.push(v));
+        try!(env.push(v));
         Ok(())
     }
 
@@ -76,8 +79,8 @@ impl<'a> Handler<'a> {
         let a = stack_pop!(env);
         let b = stack_pop!(env);
 
-        env.push(a);
-        env.push(b);
+        try!(env.push(a));
+        try!(env.push(b));
 
         Ok(())
     }
@@ -94,11 +97,11 @@ impl<'a> Handler<'a> {
         let c = stack_pop!(env);
         let d = stack_pop!(env);
 
-        env.push(b);
-        env.push(a);
+        try!(env.push(b));
+        try!(env.push(a));
 
-        env.push(d);
-        env.push(c);
+        try!(env.push(d));
+        try!(env.push(c));
 
         Ok(())
     }
@@ -113,9 +116,9 @@ impl<'a> Handler<'a> {
         let a = stack_pop!(env);
         let b = stack_pop!(env);
 
-        env.push(b);
-        env.push(a);
-        env.push(b);
+        try!(env.push(b));
+        try!(env.push(a));
+        try!(env.push(b));
 
         Ok(())
     }
@@ -132,12 +135,12 @@ impl<'a> Handler<'a> {
         let b = stack_pop!(env);
         let a = stack_pop!(env);
 
-        env.push(a);
-        env.push(b);
-        env.push(c);
-        env.push(d);
-        env.push(a);
-        env.push(b);
+        try!(env.push(a));
+        try!(env.push(b));
+        try!(env.push(c));
+        try!(env.push(d));
+        try!(env.push(a));
+        try!(env.push(b));
 
         Ok(())
     }
@@ -149,9 +152,9 @@ impl<'a> Handler<'a> {
         let b = stack_pop!(env);
         let c = stack_pop!(env);
 
-        env.push(b);
-        env.push(a);
-        env.push(c);
+        try!(env.push(b));
+        try!(env.push(a));
+        try!(env.push(c));
 
         Ok(())
     }
@@ -170,12 +173,12 @@ impl<'a> Handler<'a> {
         let b = stack_pop!(env);
         let a = stack_pop!(env);
 
-        env.push(c);
-        env.push(d);
-        env.push(e);
-        env.push(f);
-        env.push(a);
-        env.push(b);
+        try!(env.push(c));
+        try!(env.push(d));
+        try!(env.push(e));
+        try!(env.push(f));
+        try!(env.push(a));
+        try!(env.push(b));
 
         Ok(())
     }
@@ -201,7 +204,7 @@ impl<'a> Handler<'a> {
         instruction_is!(instruction, DEPTH);
         let bytes = BigUint::from(env.stack_size).to_bytes_be();
         let slice = alloc_and_write!(bytes.as_slice(), env);
-        env.push(slice);
+        try!(env.push(slice));
         Ok(())
     }
 
@@ -237,7 +240,7 @@ impl<'a> Handler<'a> {
             slice[offset..offset + item.len()].copy_from_slice(item);
             offset += item.len();
         }
-        env.push(slice);
+        try!(env.push(slice));
         Ok(())
     }
 
@@ -252,7 +255,7 @@ impl<'a> Handler<'a> {
         while current.len() > 0 {
             match binparser::data(current) {
                 pumpkinscript::ParseResult::Done(rest, val) => {
-                    env.push(&val[offset_by_size(val.len())..]);
+                    try!(env.push(&val[offset_by_size(val.len())..]));
                     current = rest
                 }
                 _ => return Err(error_invalid_value!(current)),
@@ -13,17 +13,33 @@
 use lmdb;
 use lmdb::traits::{LmdbResultExt, AsLmdbBytes, FromLmdbBytes};
 use storage;
+use messaging;
+use compression;
+use encryption;
+use std::cmp;
 use std::mem;
+use std::str;
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+use std::path::{Component, Path};
 use std::error::Error as StdError;
-use std::collections::HashMap;
-use super::{Env, EnvId, Dispatcher, PassResult, Error, STACK_TRUE, STACK_FALSE, offset_by_size,
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use super::{Env, EnvId, Waker, Dispatcher, PassResult, Error, STACK_TRUE, STACK_FALSE, offset_by_size,
             ERROR_EMPTY_STACK, ERROR_INVALID_VALUE, ERROR_DUPLICATE_KEY, ERROR_NO_TX,
-            ERROR_UNKNOWN_KEY, ERROR_DATABASE, ERROR_NO_VALUE};
+            ERROR_UNKNOWN_KEY, ERROR_DATABASE, ERROR_NO_VALUE, ERROR_WRITE_TIMEOUT,
+            ERROR_SHREDDED, ERROR_WRONG_VERSION, ERROR_UNKNOWN_KEYSPACE};
 use snowflake::ProcessUniqueId;
 use std::collections::BTreeMap;
-use storage::WriteTransactionContainer;
+use storage::{WriteTransactionContainer, ReadTransactionContainer};
 use num_bigint::BigUint;
-use num_traits::FromPrimitive;
+use num_traits::{FromPrimitive, ToPrimitive};
+use serde_json as json;
+use byteorder::{BigEndian, ByteOrder};
+use pumpkinscript::{self, binparser, Encodable};
+use pumpkinscript::tuple::{self, Element};
+use super::mod_hlc::HLC_SIZE;
 
 pub type CursorId = ProcessUniqueId;
 
@@ -46,16 +62,45 @@ instruction!(CURSOR_SEEK, b"\x8BCURSOR/SEEK");
 instruction!(CURSOR_POSITIONEDQ, b"\x92CURSOR/POSITIONED?");
 instruction!(CURSOR_KEY, b"\x8ACURSOR/KEY");
 instruction!(CURSOR_VAL, b"\x8ACURSOR/VAL");
+instruction!(CURSOR_RANGE, b"\x8CCURSOR/RANGE");
+instruction!(CURSOR_TOKEN, b"\x8CCURSOR/TOKEN");
+instruction!(CURSOR_RESUME, b"\x8DCURSOR/RESUME");
+instruction!(CURSOR_TOKEN_CHANGEDQ, b"\x95CURSOR/TOKEN-CHANGED?");
 
 instruction!(COMMIT, b"\x86COMMIT");
 
-instruction!(MAXKEYSIZE, b"\x92$SYSTEM/MAXKEYSIZE");
+instruction!(COMPRESS, b"\x88COMPRESS");
+instruction!(DECOMPRESS, b"\x8ADECOMPRESS");
 
-#[derive(PartialEq, Debug)]
-enum TxType {
-    Read,
-    Write,
-}
+instruction!(ENCRYPT, b"\x87ENCRYPT");
+instruction!(DECRYPT, b"\x87DECRYPT");
+instruction!(SHRED, b"\x85SHRED");
+
+instruction!(KEYSPACE, b"\x88KEYSPACE");
+instruction!(KEYSPACE_END, b"\x80\x88KEYSPACE"); // internal instruction
+
+instruction!(ASOF, b"\x84ASOF");
+instruction!(ASOF_END, b"\x80\x84ASOF"); // internal instruction
+instruction!(CURSOR_SEEKLAST, b"\x8FCURSOR/SEEKLAST"); // builtin, native within ASOF
+
+instruction!(INDEX_DEFINE, b"\x8CINDEX/DEFINE");
+instruction!(INDEX_DROP, b"\x8AINDEX/DROP");
+instruction!(INDEX_LIST, b"\x8AINDEX/LIST");
+instruction!(INDEX_BACKFILL, b"\x8EINDEX/BACKFILL");
+instruction!(INDEX_CURSOR, b"\x8CINDEX/CURSOR");
+instruction!(INDEX_BEGIN, b"\x80\x8BINDEX/BEGIN"); // internal instruction
+instruction!(INDEX_END, b"\x80\x89INDEX/END"); // internal instruction
+
+instruction!(STREAM_APPEND, b"\x8DSTREAM/APPEND");
+instruction!(STREAM_VERSION, b"\x8ESTREAM/VERSION");
+instruction!(STREAM_READ, b"\x8BSTREAM/READ");
+
+instruction!(MAXKEYSIZE, b"\x92$SYSTEM/MAXKEYSIZE");
+instruction!(BACKUP, b"\x8E$SYSTEM/BACKUP");
+instruction!(STATS, b"\x8D$SYSTEM/STATS");
+instruction!(DBSTATS, b"\x8F$SYSTEM/DBSTATS");
+instruction!(SYSTEM_DEFINE, b"\x8E$SYSTEM/DEFINE");
+instruction!(SYSTEM_UNDEFINE, b"\x90$SYSTEM/UNDEFINE");
 
 enum Accessor<'a> {
     Const(lmdb::ConstAccessor<'a>),
@@ -78,8 +123,11 @@ impl<'a> Accessor<'a> {
 
 #[derive(Debug)]
 enum Txn<'a> {
-    Read(lmdb::ReadTransaction<'a>),
+    Read(ReadTransactionContainer<'a>),
     Write(WriteTransactionContainer<'a>),
+    /// Write transaction that has been committed but whose
+    /// WRITE hasn't ended yet
+    Committed,
 }
 
 impl<'a> Txn<'a> {
@@ -87,97 +135,857 @@ impl<'a> Txn<'a> {
         match self {
             &Txn::Read(ref txn) => Accessor::Const(txn.access()),
             &Txn::Write(ref txn) => Accessor::Write(txn.access()),
+            &Txn::Committed => panic!("transaction has been committed"),
         }
     }
     fn cursor(&self, db: &'a lmdb::Database) -> Result<lmdb::Cursor, lmdb::Error> {
         match self {
             &Txn::Read(ref txn) => txn.cursor(db),
             &Txn::Write(ref txn) => txn.cursor(db),
+            &Txn::Committed => panic!("transaction has been committed"),
         }
     }
-    fn tx_type(&self) -> TxType {
+    fn stat(&self, db: &lmdb::Database) -> Result<lmdb::Stat, lmdb::Error> {
         match self {
-            &Txn::Read(_) => TxType::Read,
-            &Txn::Write(_) => TxType::Write,
+            &Txn::Read(ref txn) => txn.db_stat(db),
+            &Txn::Write(ref txn) => txn.db_stat(db),
+            &Txn::Committed => panic!("transaction has been committed"),
+        }
+    }
+    fn id(&self) -> usize {
+        match self {
+            &Txn::Read(ref txn) => txn.id(),
+            &Txn::Write(ref txn) => txn.id(),
+            &Txn::Committed => panic!("transaction has been committed"),
+        }
+    }
+}
+
+/// Encodes statistics as a JSON object
+fn stats_json(stats: &[(&str, u64)]) -> String {
+    let mut map = json::Map::new();
+    for &(name, value) in stats {
+        map.insert(String::from(name), json::Value::from(value));
+    }
+    json::Value::Object(map).to_string()
+}
+
+// CURSOR/RANGE flags
+const RANGE_EXCLUDE_START: u8 = 0x01;
+const RANGE_INCLUDE_END: u8 = 0x02;
+const RANGE_REVERSE: u8 = 0x04;
+const RANGE_END_PREFIX: u8 = 0x08;
+
+// Version of CURSOR/TOKEN's format
+const TOKEN_VERSION: &'static [u8] = b"\x02";
+
+/// Positions the cursor at the first key equal or greater than `key`
+/// (or at the first key if there's no `key`)
+fn seek_ge<'c>(cursor: &mut lmdb::Cursor, access: &'c lmdb::ConstAccessor, key: Option<&[u8]>)
+               -> Option<&'c [u8]> {
+    match key {
+        Some(key) => cursor.seek_range_k::<[u8], [u8]>(access, key),
+        None => cursor.first::<[u8], [u8]>(access),
+    }.ok().map(|(key, _)| key)
+}
+
+/// Positions the cursor at the last key equal or less than `key`
+/// (or at the last key if there's no `key`)
+fn seek_le<'c>(cursor: &mut lmdb::Cursor, access: &'c lmdb::ConstAccessor, key: Option<&[u8]>)
+               -> Option<&'c [u8]> {
+    match key {
+        Some(key) => match cursor.seek_range_k::<[u8], [u8]>(access, key) {
+            Ok((found, _)) if found == key => Some(found),
+            Ok(_) => step(cursor, access, true),
+            Err(_) => cursor.last::<[u8], [u8]>(access).ok().map(|(key, _)| key),
+        },
+        None => cursor.last::<[u8], [u8]>(access).ok().map(|(key, _)| key),
+    }
+}
+
+fn step<'c>(cursor: &mut lmdb::Cursor, access: &'c lmdb::ConstAccessor, backwards: bool)
+            -> Option<&'c [u8]> {
+    match backwards {
+        true => cursor.prev::<[u8], [u8]>(access),
+        false => cursor.next::<[u8], [u8]>(access),
+    }.ok().map(|(key, _)| key)
+}
+
+/// Positions the cursor at the latest version of `prefix` (a key consisting of `prefix`
+/// followed by an HLC timestamp) that is not newer than `hlc`
+fn seek_as_of<'c>(cursor: &mut lmdb::Cursor, access: &'c lmdb::ConstAccessor, prefix: &[u8], hlc: &[u8])
+                  -> Option<&'c [u8]> {
+    let mut key = Vec::from(prefix);
+    key.extend_from_slice(hlc);
+    let mut found = seek_le(cursor, access, Some(&key[..]));
+    while let Some(candidate) = found {
+        if !candidate.starts_with(prefix) {
+            return None;
+        }
+        // longer keys sharing the prefix are not its versions
+        if candidate.len() == prefix.len() + HLC_SIZE {
+            return Some(candidate);
+        }
+        found = step(cursor, access, true);
+    }
+    None
+}
+
+/// Returns the smallest key that is greater than any key starting
+/// with `prefix`, `None` if there's no such key
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut key = Vec::from(prefix);
+    while let Some(byte) = key.pop() {
+        if byte < 0xff {
+            key.push(byte + 1);
+            return Some(key);
+        }
+    }
+    None
+}
+
+/// Bounds of a range cursor (see CURSOR/RANGE)
+///
+/// Cursor operations on a range cursor move it in the direction of the range
+/// and never position it outside of the range.
+struct Range {
+    start: Option<Vec<u8>>,
+    start_inclusive: bool,
+    end: Option<Vec<u8>>,
+    end_inclusive: bool,
+    reverse: bool,
+    limit: Option<u64>,
+    // number of keys visited since the cursor was positioned
+    // with CURSOR/FIRST or CURSOR/SEEK
+    count: u64,
+    positioned: bool,
+}
+
+impl Range {
+    /// Range of all keys, used for plain cursors
+    fn unbounded() -> Range {
+        Range {
+            start: None,
+            start_inclusive: true,
+            end: None,
+            end_inclusive: false,
+            reverse: false,
+            limit: None,
+            count: 0,
+            positioned: false,
+        }
+    }
+
+    /// Encodes a continuation token of the cursor over `keyspace` positioned at `key`
+    /// within a transaction with the ID of `txn_id`
+    ///
+    /// The token is a sequence of binaries: format version, flags (as in CURSOR/RANGE),
+    /// keyspace name, transaction ID, key, start, end and limit.
+    fn token(&self, keyspace: &[u8], txn_id: u64, key: &[u8]) -> Vec<u8> {
+        let mut flags = 0;
+        if !self.start_inclusive {
+            flags |= RANGE_EXCLUDE_START;
+        }
+        if self.end_inclusive {
+            flags |= RANGE_INCLUDE_END;
+        }
+        if self.reverse {
+            flags |= RANGE_REVERSE;
+        }
+        let mut txn = vec![0; 8];
+        BigEndian::write_u64(&mut txn, txn_id);
+        let mut limit = vec![0; 8];
+        BigEndian::write_u64(&mut limit, self.limit.unwrap_or(0));
+        (Vec::from(TOKEN_VERSION), vec![flags], Vec::from(keyspace), txn, Vec::from(key),
+         self.start.clone().unwrap_or_default(), self.end.clone().unwrap_or_default(), limit).encode()
+    }
+
+    /// Decodes a continuation token into the range, keyspace name, transaction ID
+    /// and the key the cursor was positioned at
+    fn from_token(token: &[u8]) -> Option<(Range, &[u8], u64, &[u8])> {
+        let mut fields = Vec::new();
+        let mut input = token;
+        while input.len() > 0 {
+            match binparser::data(input) {
+                pumpkinscript::ParseResult::Done(rest, data) => {
+                    fields.push(&data[offset_by_size(data.len())..]);
+                    input = rest;
+                },
+                _ => return None,
+            }
+        }
+        if fields.len() != 8 || fields[0] != TOKEN_VERSION || fields[1].len() != 1 ||
+           fields[3].len() != 8 || fields[7].len() != 8 {
+            return None;
+        }
+        let flags = fields[1][0];
+        let limit = BigEndian::read_u64(fields[7]);
+        let range = Range {
+            start: if fields[5].len() == 0 { None } else { Some(Vec::from(fields[5])) },
+            start_inclusive: flags & RANGE_EXCLUDE_START == 0,
+            end: if fields[6].len() == 0 { None } else { Some(Vec::from(fields[6])) },
+            end_inclusive: flags & RANGE_INCLUDE_END != 0,
+            reverse: flags & RANGE_REVERSE != 0,
+            limit: if limit == 0 { None } else { Some(limit) },
+            count: 0,
+            positioned: false,
+        };
+        Some((range, fields[2], BigEndian::read_u64(fields[3]), fields[4]))
+    }
+
+    fn after_start(&self, key: &[u8]) -> bool {
+        match self.start {
+            Some(ref start) => key > start.as_slice() || (self.start_inclusive && key == start.as_slice()),
+            None => true,
+        }
+    }
+
+    fn before_end(&self, key: &[u8]) -> bool {
+        match self.end {
+            Some(ref end) => key < end.as_slice() || (self.end_inclusive && key == end.as_slice()),
+            None => true,
+        }
+    }
+
+    fn position(&mut self, key: Option<&[u8]>) -> bool {
+        let positioned = key.map_or(false, |key| self.after_start(key) && self.before_end(key));
+        self.positioned = positioned;
+        positioned
+    }
+
+    fn seek_start<'c>(&self, cursor: &mut lmdb::Cursor, access: &'c lmdb::ConstAccessor) -> Option<&'c [u8]> {
+        match seek_ge(cursor, access, self.start.as_ref().map(|start| start.as_slice())) {
+            Some(key) if !self.after_start(key) => step(cursor, access, false),
+            key => key,
+        }
+    }
+
+    fn seek_end<'c>(&self, cursor: &mut lmdb::Cursor, access: &'c lmdb::ConstAccessor) -> Option<&'c [u8]> {
+        match seek_le(cursor, access, self.end.as_ref().map(|end| end.as_slice())) {
+            Some(key) if !self.before_end(key) => step(cursor, access, true),
+            key => key,
+        }
+    }
+
+    fn first(&mut self, cursor: &mut lmdb::Cursor, access: &lmdb::ConstAccessor) -> bool {
+        self.count = 1;
+        let key = match self.reverse {
+            true => self.seek_end(cursor, access),
+            false => self.seek_start(cursor, access),
+        };
+        self.position(key)
+    }
+
+    fn last(&mut self, cursor: &mut lmdb::Cursor, access: &lmdb::ConstAccessor) -> bool {
+        self.count = 1;
+        let key = match self.reverse {
+            true => self.seek_start(cursor, access),
+            false => self.seek_end(cursor, access),
+        };
+        self.position(key)
+    }
+
+    fn next(&mut self, cursor: &mut lmdb::Cursor, access: &lmdb::ConstAccessor) -> bool {
+        if !self.positioned || self.limit.map_or(false, |limit| self.count >= limit) {
+            self.positioned = false;
+            return false;
+        }
+        self.count += 1;
+        let key = step(cursor, access, self.reverse);
+        self.position(key)
+    }
+
+    fn prev(&mut self, cursor: &mut lmdb::Cursor, access: &lmdb::ConstAccessor) -> bool {
+        if !self.positioned {
+            return false;
+        }
+        self.count = self.count.saturating_sub(1);
+        let key = step(cursor, access, !self.reverse);
+        self.position(key)
+    }
+
+    /// Positions the cursor at the first key of the range that follows `key`
+    fn resume(&mut self, cursor: &mut lmdb::Cursor, access: &lmdb::ConstAccessor, key: &[u8]) -> bool {
+        self.count = 1;
+        let found = match self.reverse {
+            true => match seek_le(cursor, access, Some(key)) {
+                Some(found) if found == key => step(cursor, access, true),
+                found => found,
+            },
+            false => match seek_ge(cursor, access, Some(key)) {
+                Some(found) if found == key => step(cursor, access, false),
+                found => found,
+            },
+        };
+        self.position(found)
+    }
+
+    fn seek_as_of(&mut self, cursor: &mut lmdb::Cursor, access: &lmdb::ConstAccessor, prefix: &[u8], hlc: &[u8])
+                  -> bool {
+        self.count = 1;
+        let found = seek_as_of(cursor, access, prefix, hlc);
+        self.position(found)
+    }
+
+    fn seek_range_k(&mut self, cursor: &mut lmdb::Cursor, access: &lmdb::ConstAccessor, key: &[u8]) -> bool {
+        self.count = 1;
+        let found = match self.reverse {
+            true => match seek_le(cursor, access, Some(key)) {
+                // seeking past the range positions the cursor at its beginning
+                Some(found) if !self.before_end(found) => self.seek_end(cursor, access),
+                found => found,
+            },
+            false => match seek_ge(cursor, access, Some(key)) {
+                Some(found) if !self.after_start(found) => self.seek_start(cursor, access),
+                found => found,
+            },
+        };
+        self.position(found)
+    }
+}
+
+// Kinds of keys in the indexes keyspace
+const INDEX_DEFINITION: u8 = 0x00;
+const INDEX_ENTRY: u8 = 0x01;
+
+/// Key of a definition of the keyspace's index
+fn index_definition_key(keyspace: &[u8], name: &[u8]) -> Vec<u8> {
+    tuple::pack(&[Element::U8(INDEX_DEFINITION), Element::from(keyspace), Element::from(name)])
+}
+
+/// Range of keys of definitions of the keyspace's indexes
+fn index_definitions_range(keyspace: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    tuple::prefix_range(&[Element::U8(INDEX_DEFINITION), Element::from(keyspace)])
+}
+
+/// Key of an index entry that maps `index_key` to the primary `key`
+fn index_entry_key(keyspace: &[u8], name: &[u8], index_key: &[u8], key: &[u8]) -> Vec<u8> {
+    tuple::pack(&[Element::U8(INDEX_ENTRY), Element::from(keyspace), Element::from(name),
+                  Element::from(index_key), Element::from(key)])
+}
+
+/// Range of keys of the index's entries (only of the ones for `index_key`,
+/// if it is given)
+fn index_entries_range(keyspace: &[u8], name: &[u8], index_key: Option<&[u8]>) -> (Vec<u8>, Vec<u8>) {
+    let mut prefix = vec![Element::U8(INDEX_ENTRY), Element::from(keyspace), Element::from(name)];
+    if let Some(index_key) = index_key {
+        prefix.push(Element::from(index_key));
+    }
+    tuple::prefix_range(&prefix)
+}
+
+/// Decodes an index definition (key prefix and closure)
+fn decode_index_definition(value: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let mut fields = Vec::new();
+    let mut input = value;
+    while input.len() > 0 {
+        match binparser::data(input) {
+            pumpkinscript::ParseResult::Done(rest, data) => {
+                fields.push(Vec::from(&data[offset_by_size(data.len())..]));
+                input = rest;
+            },
+            _ => return None,
+        }
+    }
+    if fields.len() != 2 {
+        return None;
+    }
+    let closure = fields.pop().unwrap();
+    let prefix = fields.pop().unwrap();
+    Some((prefix, closure))
+}
+
+/// Appends code that indexes the `key`/`value` pair to `program`
+///
+/// The closure is run with the key and the value on the stack and whatever
+/// it leaves on top of them is stored as index keys (see INDEX/DEFINE).
+fn index_program(program: &mut Vec<u8>, keyspace: &[u8], name: &[u8], closure: &[u8],
+                 key: &[u8], value: &[u8]) {
+    program.append(&mut key.encode());
+    program.append(&mut value.encode());
+    program.extend_from_slice(INDEX_BEGIN);
+    program.extend_from_slice(closure);
+    program.append(&mut keyspace.encode());
+    program.append(&mut name.encode());
+    program.append(&mut key.encode());
+    program.extend_from_slice(INDEX_END);
+}
+
+/// Key of the stream's event with the given version
+fn stream_event_key(stream: &[u8], version: u64) -> Vec<u8> {
+    tuple::pack(&[Element::from(stream), Element::U64(version)])
+}
+
+/// Returns the version of the last event of the stream (`0` if there are none)
+fn stream_version(cursor: &mut lmdb::Cursor, access: &lmdb::ConstAccessor, stream: &[u8]) -> u64 {
+    let first = stream_event_key(stream, 0);
+    match seek_le(cursor, access, Some(&stream_event_key(stream, u64::max_value())[..])) {
+        Some(key) if key >= &first[..] => match tuple::unpack(key).as_ref().map(|v| v.as_slice()) {
+            Some(&[_, Element::U64(version)]) => version,
+            _ => 0,
+        },
+        _ => 0,
+    }
+}
+
+/// Returns the latest definition of the stored procedure (`None` if it
+/// has never been defined or has been undefined since)
+fn latest_procedure(cursor: &mut lmdb::Cursor, access: &lmdb::ConstAccessor, name: &[u8])
+                    -> Result<Option<Vec<u8>>, lmdb::Error> {
+    let version = stream_version(cursor, access, name);
+    if version == 0 {
+        return Ok(None);
+    }
+    // the cursor is positioned at the latest version,
+    // an empty definition marks an undefined procedure
+    let (_, definition) = try!(cursor.get_current::<[u8], [u8]>(access));
+    Ok(if definition.len() == 0 { None } else { Some(Vec::from(definition)) })
+}
+
+/// Collects key/value pairs starting from `start` (or from the first key, if it's empty)
+/// up until `end` (exclusive)
+fn scan(cursor: &mut lmdb::Cursor, access: &lmdb::ConstAccessor, start: &[u8], end: Option<&[u8]>)
+        -> Result<Vec<(Vec<u8>, Vec<u8>)>, lmdb::Error> {
+    let mut pairs = Vec::new();
+    let mut item = if start.len() == 0 {
+        cursor.first::<[u8], [u8]>(access)
+    } else {
+        cursor.seek_range_k::<[u8], [u8]>(access, start)
+    };
+    loop {
+        match item {
+            Ok((key, value)) => {
+                if end.map_or(false, |end| key >= end) {
+                    break;
+                }
+                pairs.push((Vec::from(key), Vec::from(value)));
+            },
+            Err(lmdb::Error::Code(code)) if code == lmdb::error::NOTFOUND => break,
+            Err(err) => return Err(err),
         }
+        item = cursor.next::<[u8], [u8]>(access);
     }
+    Ok(pairs)
+}
+
+/// Changes (keyspace name, key and value) made within a write transaction,
+/// recorded for change data capture
+type Changes = Vec<(Vec<u8>, Vec<u8>, Vec<u8>)>;
+
+/// Publishes changes of a committed write transaction to the change data capture topic
+///
+/// The message is a sequence of binaries in PumpkinScript's data encoding (so that
+/// it can be `UNWRAP`ped): the ID of the transaction (8 bytes, big-endian) followed by
+/// the keyspace name (empty for the default keyspace), key and value of every change,
+/// in the order they were made.
+fn publish_changes<P: messaging::Publisher>(publisher: &P, topic: &[u8], txn_id: usize, changes: &Changes) {
+    if changes.len() == 0 {
+        return;
+    }
+    let mut id = vec![0; 8];
+    BigEndian::write_u64(&mut id, txn_id as u64);
+    let mut message = id.encode();
+    for &(ref keyspace, ref key, ref value) in changes {
+        message.append(&mut keyspace.encode());
+        message.append(&mut key.encode());
+        message.append(&mut value.encode());
+    }
+    publisher.publish(topic, &message);
+}
+
+/// How long a write group lets new members in if there's
+/// no maximum write wait configured (in milliseconds)
+const DEFAULT_GROUP_WINDOW: u64 = 100;
+
+/// Write transaction shared by multiple top-level WRITEs (group commit),
+/// each of them running in a nested transaction of its own, one at a time
+struct WriteGroup<'a> {
+    txn: WriteTransactionContainer<'a>,
+    // time after which no more WRITEs join the group, so that its
+    // members don't wait for the commit longer than a WRITE waits for the lock
+    deadline: Instant,
+    // number of WRITEs that have joined the group
+    size: usize,
+    // environment writing within the group and the depth of its transaction
+    active: Option<(EnvId, usize)>,
+    // environment that has been woken up to write within the group next
+    next: Option<EnvId>,
+    // environments waiting to write within the group
+    waiting: VecDeque<(EnvId, Waker)>,
+    // environments that committed within the group, waiting for it to be committed
+    committed: Vec<(EnvId, Waker)>,
+    // changes committed within the group
+    changes: Changes,
+    // streams recorded within the group
+    new_streams: Vec<Vec<u8>>,
 }
 
-pub struct Handler<'a, T : AsRef<storage::Storage<'a>> + 'a> {
+/// Copy of the database made by `$SYSTEM/BACKUP` in a thread of its own
+struct Backup {
+    thread: thread::JoinHandle<()>,
+    result: mpsc::Receiver<Result<(), String>>,
+}
+
+/// State of an environment at the beginning of its top-level WRITE,
+/// used to retry the WRITE after the map has been grown
+struct WriteSnapshot<'a> {
+    code: &'a [u8],
+    stack: Vec<&'a [u8]>,
+    program: Vec<&'a [u8]>,
+    #[cfg(feature = "scoped_dictionary")]
+    dictionary: Vec<BTreeMap<&'a [u8], &'a [u8]>>,
+    #[cfg(not(feature = "scoped_dictionary"))]
+    dictionary: BTreeMap<&'a [u8], &'a [u8]>,
+    tracking_errors: usize,
+    side_effects: usize,
+    keyspaces: usize,
+    as_of: usize,
+}
+
+pub struct Handler<'a, T : AsRef<storage::Storage<'a>> + 'a, P: messaging::Publisher> {
     db: T,
+    publisher: P,
     txns: HashMap<EnvId, Vec<Txn<'a>>>,
-    cursors: BTreeMap<(EnvId, Vec<u8>), (TxType, lmdb::Cursor<'a, 'a>)>,
+    // Keyspaces along with their names
+    keyspaces: HashMap<EnvId, Vec<(&'a [u8], Arc<lmdb::Database<'a>>)>>,
+    // HLC timestamps of ASOF scopes
+    as_of: HashMap<EnvId, Vec<&'a [u8]>>,
+    // Cursors are indexed with the depth of their transaction
+    // in the environment's transaction stack
+    cursors: BTreeMap<(EnvId, Vec<u8>), (usize, lmdb::Cursor<'a, 'a>)>,
+    // Bounds of range cursors
+    ranges: HashMap<(EnvId, Vec<u8>), Range>,
+    // Names of keyspaces cursors were created in
+    cursor_keyspaces: HashMap<(EnvId, Vec<u8>), &'a [u8]>,
+    // Environments waiting for the write lock, along with the time
+    // they started waiting
+    write_waits: HashMap<EnvId, Instant>,
+    group: Option<WriteGroup<'a>>,
+    // Results of group commits for environments that committed
+    // within the group
+    group_commits: HashMap<EnvId, Result<(), String>>,
+    // Top-level WRITEs that can be retried if the map gets full
+    write_snapshots: HashMap<EnvId, WriteSnapshot<'a>>,
+    // Changes made within write transactions, indexed with the depth of
+    // the transaction (recorded only if change data capture is enabled)
+    changes: HashMap<(EnvId, usize), Changes>,
+    // Streams recorded within write transactions (indexed the same way as
+    // changes), their keys are pending until the transactions are committed
+    new_streams: HashMap<(EnvId, usize), Vec<Vec<u8>>>,
+    // Stack sizes at the beginning of index closures (see INDEX_BEGIN)
+    index_depths: HashMap<EnvId, Vec<usize>>,
+    // Backups in progress
+    backups: HashMap<EnvId, Backup>,
+    // Backups of environments that have been terminated before they were done
+    abandoned_backups: Vec<thread::JoinHandle<()>>,
     maxkeysize: Vec<u8>,
 }
 
-macro_rules! read_or_write_transaction {
+macro_rules! current_transaction {
     ($me: expr, $env_id: expr) => {
-        match $me.txns.get(&$env_id)
-            .and_then(|v| Some(&v[v.len() - 1])) {
-            None => return Err(error_no_transaction!()),
-            Some(txn) => txn
-        }
+        $me.txns.get(&$env_id)
+            .and_then(|v| v.last())
+            .and_then(|txn| match txn {
+                &Txn::Committed => None,
+                txn => Some(txn),
+            })
     };
 }
 
-macro_rules! tx_type {
-    ($me: expr, $env_id: expr) => {{
-        let txn_type = $me.txns.get(&$env_id)
-            .and_then(|v| Some(&v[v.len() - 1]))
-            .and_then(|txn| Some(txn.tx_type()));
-        if txn_type.is_none() {
-            return Err(error_no_transaction!())
+macro_rules! cursor_transaction {
+    ($me: expr, $env_id: expr, $cursor: expr) => {{
+        let depth = match $me.cursors.get(&($env_id, Vec::from($cursor))) {
+            Some(&(depth, _)) => depth,
+            None => return Err(error_invalid_value!($cursor))
+        };
+        let txns = $me.txns.get(&$env_id).unwrap();
+        // A write transaction can't be used while there are
+        // nested write transactions in progress
+        match txns[depth] {
+            Txn::Write(_) if txns[depth + 1..].iter().any(|txn| match txn {
+                &Txn::Write(_) => true,
+                _ => false,
+            }) => return Err(error_invalid_value!($cursor)),
+            ref txn => (depth, txn),
         }
-        txn_type.unwrap()
     }};
 }
 
+macro_rules! database {
+    ($me: expr, $env_id: expr) => {
+        match $me.keyspaces.get(&$env_id).and_then(|v| v.last()) {
+            Some(&(_, ref db)) => &**db,
+            None => &*$me.db.as_ref().db,
+        }
+    };
+}
+
+macro_rules! error_unknown_keyspace {
+    ($name: expr) => {{
+        error_program!(
+            "Unknown keyspace".as_bytes(),
+            $name,
+            ERROR_UNKNOWN_KEYSPACE
+        )
+    }}
+}
+
+macro_rules! error_write_timeout {
+    () => {{
+        let vec = Vec::new();
+        error_program!(
+            "Write timeout".as_bytes(),
+            &vec,
+            ERROR_WRITE_TIMEOUT
+        )
+    }}
+}
+
+macro_rules! error_in_transaction {
+    () => {{
+        let vec = Vec::new();
+        error_program!(
+            "Can't be used within a transaction".as_bytes(),
+            &vec,
+            ERROR_INVALID_VALUE
+        )
+    }}
+}
+
+macro_rules! error_shredded {
+    ($stream: expr) => {{
+        error_program!(
+            "Stream has been shredded".as_bytes(),
+            $stream,
+            ERROR_SHREDDED
+        )
+    }}
+}
+
+macro_rules! error_wrong_version {
+    ($version: expr) => {{
+        error_program!(
+            "Wrong expected version".as_bytes(),
+            $version,
+            ERROR_WRONG_VERSION
+        )
+    }}
+}
+
+macro_rules! error_no_backup_dir {
+    () => {{
+        let vec = Vec::new();
+        error_program!(
+            "Backups are not configured".as_bytes(),
+            &vec,
+            ERROR_DATABASE
+        )
+    }}
+}
+
+macro_rules! error_define_not_allowed {
+    () => {{
+        let vec = Vec::new();
+        error_program!(
+            "Defining procedures is not allowed".as_bytes(),
+            &vec,
+            ERROR_DATABASE
+        )
+    }}
+}
+
+macro_rules! error_no_key_store {
+    () => {{
+        let vec = Vec::new();
+        error_program!(
+            "Encryption is not configured".as_bytes(),
+            &vec,
+            ERROR_DATABASE
+        )
+    }}
+}
+
+macro_rules! error_encryption {
+    ($err: expr, $stream: expr) => {{
+        match $err {
+            encryption::Error::Shredded(stream) => error_shredded!(&stream),
+            encryption::Error::InvalidStream => error_invalid_value!($stream),
+            err => error_database!(err),
+        }
+    }}
+}
+
+/// Encrypts `value` for `stream` within a write transaction, returns the encrypted
+/// value and whether the stream has been recorded by it
+///
+/// A stream is recorded in the database the first time it is written to, and
+/// its key is only created if the stream hasn't been recorded, so that a key lost
+/// from the key store is never replaced with a new one. The key stays pending
+/// until the transaction is committed (see `settle_keys`).
+fn encrypt_value(storage: &storage::Storage, access: &mut lmdb::WriteAccessor,
+                 stream: &[u8], value: &[u8]) -> Result<(Vec<u8>, bool), Error> {
+    let encryption = match storage.encryption {
+        Some(ref encryption) => encryption,
+        None => return Err(error_no_key_store!()),
+    };
+    let streams = match storage.opened_keyspace(storage::STREAMS_KEYSPACE) {
+        Some(streams) => streams,
+        None => return Err(error_no_key_store!()),
+    };
+    let recorded = match access.get::<[u8], [u8]>(&streams, stream).to_opt() {
+        Ok(recorded) => recorded.is_some(),
+        Err(err) => return Err(error_database!(err)),
+    };
+    if !recorded {
+        if let Err(err) = access.put(&streams, stream, &b""[..], lmdb::put::NOOVERWRITE) {
+            return Err(error_database!(err));
+        }
+    }
+    match encryption.keys.encrypt(stream, value, !recorded) {
+        Ok(encrypted) => Ok((encrypted, !recorded)),
+        Err(err) => Err(error_encryption!(err, stream)),
+    }
+}
+
+/// Commits pending keys of streams recorded within a write transaction once it
+/// has been committed, or discards them if it hasn't
+///
+/// A key that can't be committed stays pending, which is
+/// still where the key store reads it from.
+fn settle_keys(storage: &storage::Storage, streams: &[Vec<u8>], committed: bool) {
+    if let Some(ref encryption) = storage.encryption {
+        for stream in streams {
+            let _ = if committed {
+                encryption.keys.commit(stream)
+            } else {
+                encryption.keys.discard(stream)
+            };
+        }
+    }
+}
+
+/// Why a stored value could not be decoded
+enum DecodeError {
+    Encryption(encryption::Error),
+    /// Decompressed value would exceed the limit
+    TooLarge,
+}
+
+macro_rules! error_decode {
+    ($err: expr, $value: expr) => {{
+        match $err {
+            DecodeError::Encryption(err) => error_encryption!(err, $value),
+            DecodeError::TooLarge => super::error_memory_exceeded(),
+        }
+    }}
+}
+
+/// Largest value `env` can decompress without exceeding its memory quota
+fn decode_limit(env: &Env) -> usize {
+    match env.max_memory {
+        Some(max) => cmp::min(compression::MAX_SIZE, max.saturating_sub(env.memory_usage())),
+        None => compression::MAX_SIZE,
+    }
+}
+
+/// Returns the value of the key in the keyspace as it was before it got compressed
+/// and/or encrypted when it was written, or `None` if it was written as is
+///
+/// ASSOC applies at most one layer of each, so no more than that
+/// is unwrapped here, and a decompressed value may not exceed `limit` bytes.
+/// Only values of keys compression rules apply to are decompressed, values
+/// compressed with COMPRESS elsewhere are returned as they are.
+fn decode_value(storage: &storage::Storage, keyspace: &[u8], key: &[u8], value: &[u8], limit: usize)
+                -> Result<Option<Vec<u8>>, DecodeError> {
+    let mut decoded: Option<Vec<u8>> = None;
+    let mut decompressed = !storage.compression.applies(keyspace, key);
+    let mut decrypted = false;
+    loop {
+        let next = {
+            let current = decoded.as_ref().map_or(value, |decoded| decoded.as_slice());
+            let decompression = if decompressed { None } else { compression::decompress(current, limit) };
+            match decompression {
+                Some(Ok(value)) => {
+                    decompressed = true;
+                    Some(value)
+                },
+                Some(Err(compression::TooLarge)) => return Err(DecodeError::TooLarge),
+                None => match storage.encryption {
+                    Some(ref encryption) if !decrypted => match encryption.decrypt(key, current) {
+                        Some(Ok(value)) => {
+                            decrypted = true;
+                            Some(value)
+                        },
+                        Some(Err(err)) => return Err(DecodeError::Encryption(err)),
+                        None => None,
+                    },
+                    _ => None,
+                },
+            }
+        };
+        match next {
+            Some(_) => decoded = next,
+            None => return Ok(decoded),
+        }
+    }
+}
+
 macro_rules! cursor_op {
     ($me: expr, $env: expr, $env_id: expr, $op: ident, ($($arg: expr),*)) => {{
-        let txn = read_or_write_transaction!($me, &$env_id);
+        if current_transaction!($me, $env_id).is_none() {
+            return Err(error_no_transaction!())
+        }
         let c = stack_pop!($env);
 
+        let (depth, txn) = cursor_transaction!($me, $env_id, c);
         let tuple = ($env_id, Vec::from(c));
-        let mut cursor = match $me.cursors.remove(&tuple) {
-            Some((_, cursor)) => cursor,
-            None => return Err(error_invalid_value!(c))
-        };
-        let result = match txn.access() {
-            Accessor::Const(acc) => cursor.$op::<[u8], [u8]>(&acc, $($arg)*).is_ok(),
-            Accessor::Write(acc) => cursor.$op::<[u8], [u8]>(&acc, $($arg)*).is_ok()
+        let mut cursor = $me.cursors.remove(&tuple).unwrap().1;
+        let result = match $me.ranges.get_mut(&tuple) {
+            Some(range) => match txn.access() {
+                Accessor::Const(acc) => range.$op(&mut cursor, &acc, $($arg)*),
+                Accessor::Write(acc) => range.$op(&mut cursor, &acc, $($arg)*)
+            },
+            None => match txn.access() {
+                Accessor::Const(acc) => cursor.$op::<[u8], [u8]>(&acc, $($arg)*).is_ok(),
+                Accessor::Write(acc) => cursor.$op::<[u8], [u8]>(&acc, $($arg)*).is_ok()
+            }
         };
-        $me.cursors.insert(tuple, (tx_type!($me, &$env_id), cursor));
+        $me.cursors.insert(tuple, (depth, cursor));
         if result {
-          $env.push(STACK_TRUE);
+          try!($env.push(STACK_TRUE));
         } else {
-          $env.push(STACK_FALSE);
+          try!($env.push(STACK_FALSE));
         }
     }};
 }
 
 macro_rules! cursor_map_op {
     ($me: expr, $env: expr, $env_id: expr, $op: ident, ($($arg: expr),*), $map: expr, $orelse: expr) => {{
-        let txn = read_or_write_transaction!($me, &$env_id);
+        if current_transaction!($me, $env_id).is_none() {
+            return Err(error_no_transaction!())
+        }
         let c = stack_pop!($env);
 
+        let (depth, txn) = cursor_transaction!($me, $env_id, c);
         let tuple = ($env_id, Vec::from(c));
-        let mut cursor = match $me.cursors.remove(&tuple) {
-            Some((_, cursor)) => cursor,
-            None => return Err(error_invalid_value!(c))
-        };
+        let mut cursor = $me.cursors.remove(&tuple).unwrap().1;
+        // range cursors are not positioned once they move out of the range
+        let positioned = $me.ranges.get(&tuple).map_or(true, |range| range.positioned);
         let result = match txn.access() {
+            _ if !positioned => Err(lmdb::Error::Code(lmdb::error::NOTFOUND)).map_err($orelse),
             Accessor::Const(acc) => cursor.$op::<[u8], [u8]>(&acc, $($arg)*).map_err($orelse).and_then($map),
             Accessor::Write(acc) => cursor.$op::<[u8], [u8]>(&acc, $($arg)*).map_err($orelse).and_then($map)
         };
-        $me.cursors.insert(tuple, (tx_type!($me, &$env_id), cursor));
+        $me.cursors.insert(tuple, (depth, cursor));
         result
     }};
 }
 
 builtins!("mod_storage.builtins");
 
-impl<'a, T : AsRef<storage::Storage<'a>> + 'a> Dispatcher<'a> for Handler<'a, T> {
+impl<'a, T : AsRef<storage::Storage<'a>> + 'a, P: messaging::Publisher> Dispatcher<'a> for Handler<'a, T, P> {
     fn done(&mut self, _: &mut Env, pid: EnvId) {
+        self.drop_cursors(pid, 0);
         self.txns.get_mut(&pid)
             .and_then(|vec| {
                 while vec.len() > 0 {
@@ -186,12 +994,58 @@ impl<'a, T : AsRef<storage::Storage<'a>> + 'a> Dispatcher<'a> for Handler<'a, T>
                 }
                 Some(())
             });
+        self.keyspaces.remove(&pid);
+        self.as_of.remove(&pid);
+        self.cancel_write_wait(pid);
+        self.group_commits.remove(&pid);
+        self.write_snapshots.remove(&pid);
+        self.changes.retain(|&(id, _), _| id != pid);
+        let new_streams: Vec<_> = self.new_streams.keys().filter(|&&(id, _)| id == pid).cloned().collect();
+        for key in new_streams {
+            settle_keys(self.db.as_ref(), &self.new_streams.remove(&key).unwrap(), false);
+        }
+        self.index_depths.remove(&pid);
+        if let Some(backup) = self.backups.remove(&pid) {
+            self.abandoned_backups.push(backup.thread);
+        }
+        let advance = match self.group {
+            Some(ref mut group) => {
+                group.committed.retain(|&(id, _)| id != pid);
+                if group.active.map_or(false, |(id, _)| id == pid) || group.next == Some(pid) {
+                    group.active = None;
+                    group.next = None;
+                    true
+                } else {
+                    false
+                }
+            },
+            None => false,
+        };
+        if advance {
+            self.advance_group();
+        }
+    }
+
+    fn movable(&mut self, pid: EnvId) -> bool {
+        // transactions and cursors are bound to this scheduler's thread
+        self.txns.get(&pid).map_or(true, |txns| txns.is_empty()) &&
+            self.keyspaces.get(&pid).map_or(true, |keyspaces| keyspaces.is_empty()) &&
+            self.as_of.get(&pid).map_or(true, |as_of| as_of.is_empty()) &&
+            !self.write_waits.contains_key(&pid) &&
+            !self.group_commits.contains_key(&pid) &&
+            !self.write_snapshots.contains_key(&pid) &&
+            !self.index_depths.contains_key(&pid) &&
+            // the backup's thread wakes the environment up on this scheduler
+            !self.backups.contains_key(&pid)
     }
 
     fn handle(&mut self, env: &mut Env<'a>, instruction: &'a [u8], pid: EnvId) -> PassResult<'a> {
+        try_instruction!(env, self.handle_cursor_seeklast(env, instruction, pid));
         try_instruction!(env, self.handle_builtins(env, instruction, pid));
         try_instruction!(env, self.handle_write(env, instruction, pid));
         try_instruction!(env, self.handle_read(env, instruction, pid));
+        try_instruction!(env, self.handle_keyspace(env, instruction, pid));
+        try_instruction!(env, self.handle_as_of(env, instruction, pid));
         try_instruction!(env, self.handle_assoc(env, instruction, pid));
         try_instruction!(env, self.handle_assocq(env, instruction, pid));
         try_instruction!(env, self.handle_retr(env, instruction, pid));
@@ -205,72 +1059,536 @@ impl<'a, T : AsRef<storage::Storage<'a>> + 'a> Dispatcher<'a> for Handler<'a, T>
         try_instruction!(env, self.handle_cursor_positionedq(env, instruction, pid));
         try_instruction!(env, self.handle_cursor_key(env, instruction, pid));
         try_instruction!(env, self.handle_cursor_val(env, instruction, pid));
+        try_instruction!(env, self.handle_cursor_range(env, instruction, pid));
+        try_instruction!(env, self.handle_cursor_token(env, instruction, pid));
+        try_instruction!(env, self.handle_cursor_resume(env, instruction, pid));
+        try_instruction!(env, self.handle_cursor_token_changedq(env, instruction, pid));
+        try_instruction!(env, self.handle_compress(env, instruction, pid));
+        try_instruction!(env, self.handle_decompress(env, instruction, pid));
+        try_instruction!(env, self.handle_encrypt(env, instruction, pid));
+        try_instruction!(env, self.handle_decrypt(env, instruction, pid));
+        try_instruction!(env, self.handle_shred(env, instruction, pid));
+        try_instruction!(env, self.handle_index_define(env, instruction, pid));
+        try_instruction!(env, self.handle_index_drop(env, instruction, pid));
+        try_instruction!(env, self.handle_index_list(env, instruction, pid));
+        try_instruction!(env, self.handle_index_backfill(env, instruction, pid));
+        try_instruction!(env, self.handle_index_cursor(env, instruction, pid));
+        try_instruction!(env, self.handle_index_begin(env, instruction, pid));
+        try_instruction!(env, self.handle_index_end(env, instruction, pid));
+        try_instruction!(env, self.handle_stream_append(env, instruction, pid));
+        try_instruction!(env, self.handle_stream_version(env, instruction, pid));
+        try_instruction!(env, self.handle_stream_read(env, instruction, pid));
         try_instruction!(env, self.handle_maxkeysize(env, instruction, pid));
+        try_instruction!(env, self.handle_backup(env, instruction, pid));
+        try_instruction!(env, self.handle_stats(env, instruction, pid));
+        try_instruction!(env, self.handle_dbstats(env, instruction, pid));
+        try_instruction!(env, self.handle_system_define(env, instruction, pid));
+        try_instruction!(env, self.handle_system_undefine(env, instruction, pid));
         Err(Error::UnknownInstruction)
     }
+
+    fn lookup(&mut self, env: &mut Env<'a>, instruction: &'a [u8], pid: EnvId) -> PassResult<'a> {
+        self.handle_procedure(env, instruction, pid)
+    }
+}
+
+impl<'a, T : AsRef<storage::Storage<'a>> + 'a, P: messaging::Publisher> Drop for Handler<'a, T, P> {
+    fn drop(&mut self) {
+        // backups' threads borrow the environment
+        for (_, backup) in self.backups.drain() {
+            let _ = backup.thread.join();
+        }
+        for thread in self.abandoned_backups.drain(..) {
+            let _ = thread.join();
+        }
+    }
 }
 
-impl<'a, T : AsRef<storage::Storage<'a>> + 'a> Handler<'a, T> {
-    pub fn new(db: T) -> Self {
+impl<'a, T : AsRef<storage::Storage<'a>> + 'a, P: messaging::Publisher> Handler<'a, T, P> {
+    pub fn new(db: T, publisher: P) -> Self {
         let maxkeysize = BigUint::from_u32(db.as_ref().env.maxkeysize()).unwrap().to_bytes_be();
         Handler {
             db: db,
+            publisher: publisher,
             txns: HashMap::new(),
+            keyspaces: HashMap::new(),
+            as_of: HashMap::new(),
             cursors: BTreeMap::new(),
+            ranges: HashMap::new(),
+            cursor_keyspaces: HashMap::new(),
+            write_waits: HashMap::new(),
+            group: None,
+            group_commits: HashMap::new(),
+            write_snapshots: HashMap::new(),
+            changes: HashMap::new(),
+            new_streams: HashMap::new(),
+            index_depths: HashMap::new(),
+            backups: HashMap::new(),
+            abandoned_backups: Vec::new(),
             maxkeysize: maxkeysize
         }
     }
 
     handle_builtins!();
 
-    #[inline]
-    pub fn handle_write(&mut self,
-                        env: &mut Env<'a>,
-                        instruction: &'a [u8],
-                        pid: EnvId)
-                        -> PassResult<'a> {
-        match instruction {
-            WRITE => {
-                let v = stack_pop!(env);
-                if self.txns.get(&pid).is_some() && self.txns.get(&pid).unwrap().len() > 0 {
-                    return Err(error_program!(
-                               "Nested WRITEs are not currently allowed".as_bytes(),
-                               "".as_bytes(),
-                               ERROR_DATABASE));
-                }
-                match self.db.as_ref().write() {
-                    None => Err(Error::Reschedule),
-                    Some(result) =>
-                        match result {
-                            Err(e) => Err(error_database!(e)),
-                            Ok(txn) => {
-                                if !self.txns.contains_key(&pid) {
-                                    self.txns.insert(pid, Vec::new());
-                                }
-                                let _ = self.txns.get_mut(&pid).unwrap().push(Txn::Write(txn));
-                                env.program.push(WRITE_END);
-                                env.program.push(v);
-                                Ok(())
-                            }
-                        }
-                }
+    /// Ends the innermost transaction of the environment, along with
+    /// its cursors
+    fn end_transaction(&mut self, pid: EnvId) {
+        let depth = self.txns.get(&pid).map_or(0, |vec| vec.len());
+        if depth > 0 {
+            self.drop_cursors(pid, depth - 1);
+            // changes of transactions ended without a commit are discarded
+            self.changes.remove(&(pid, depth - 1));
+            if let Some(new_streams) = self.new_streams.remove(&(pid, depth - 1)) {
+                settle_keys(self.db.as_ref(), &new_streams, false);
             }
-            WRITE_END => {
-                match self.txns.get_mut(&pid).unwrap().pop() {
-                    Some(_) => {
-                        self.cursors = mem::replace(&mut self.cursors,
-                                                    BTreeMap::new()).into_iter()
-                            .filter(|t| ((*t).1).0 != TxType::Read).collect();
-                    },
-                    _ => {}
-                };
-                Ok(())
+            let txn = self.txns.get_mut(&pid).unwrap().pop();
+            drop(txn)
+        }
+    }
+
+    /// Starts a nested write transaction within the closest write
+    /// transaction of the environment, if there's any
+    fn nested_write(&mut self, pid: EnvId) -> Option<Result<WriteTransactionContainer<'a>, lmdb::Error>> {
+        match self.txns.get_mut(&pid)
+            .and_then(|vec| vec.iter_mut().rev().filter_map(|txn| match txn {
+                &mut Txn::Write(ref mut txn) => Some(txn),
+                _ => None,
+            }).next()) {
+            Some(parent) => Some(unsafe { parent.nested() }),
+            None => None,
+        }
+    }
+
+    /// Starts a top-level write transaction. If the write lock is taken,
+    /// the environment will wait in the queue and get parked until it's its turn.
+    fn write(&mut self, waker: Option<Waker>, pid: EnvId)
+             -> Result<Result<WriteTransactionContainer<'a>, lmdb::Error>, Error> {
+        let queued = waker.is_some();
+        match waker.map_or_else(|| self.db.as_ref().write(), |waker| {
+            self.db.as_ref().write_or_enqueue(pid, Box::new(move || waker.wake()))
+        }) {
+            Some(result) => {
+                self.write_waits.remove(&pid);
+                Ok(result)
+            },
+            None if !queued => Err(Error::Reschedule),
+            None => Err(self.write_wait(pid)),
+        }
+    }
+
+    /// Starts a nested write transaction within the group's write transaction,
+    /// starting a new group if necessary. If some other environment is
+    /// writing within the group, the environment will get parked until it's its turn.
+    fn group_write(&mut self, waker: Option<Waker>, pid: EnvId)
+                   -> Result<Result<WriteTransactionContainer<'a>, lmdb::Error>, Error> {
+        let waker = match waker {
+            Some(waker) => waker,
+            None => return self.write(None, pid),
+        };
+        if self.group.is_none() {
+            let txn = match try!(self.write(Some(waker.clone()), pid)) {
+                Ok(txn) => txn,
+                Err(err) => return Ok(Err(err)),
+            };
+            self.group = Some(WriteGroup {
+                txn: txn,
+                deadline: Instant::now() + self.group_window(),
+                size: 0,
+                active: None,
+                next: None,
+                waiting: VecDeque::new(),
+                committed: Vec::new(),
+                changes: Vec::new(),
+                new_streams: Vec::new(),
+            });
+        }
+        let depth = self.txns.get(&pid).map_or(0, |vec| vec.len());
+        let max = self.db.as_ref().group_commit.unwrap_or(1);
+        let joined = {
+            let group = self.group.as_mut().unwrap();
+            if group.active.is_none() && group.size < max && Instant::now() < group.deadline &&
+               group.next.map_or(true, |next| next == pid) {
+                group.active = Some((pid, depth));
+                group.next = None;
+                group.size += 1;
+                Some(unsafe { group.txn.nested() })
+            } else {
+                if !group.waiting.iter().any(|&(id, _)| id == pid) {
+                    group.waiting.push_back((pid, waker));
+                }
+                None
             }
-            _ => Err(Error::UnknownInstruction),
+        };
+        match joined {
+            Some(result) => {
+                self.write_waits.remove(&pid);
+                Ok(result)
+            },
+            None => Err(self.write_wait(pid)),
         }
     }
 
-    #[inline]
+    /// Time a write group lets new members in
+    fn group_window(&self) -> Duration {
+        self.db.as_ref().max_write_wait.unwrap_or(Duration::from_millis(DEFAULT_GROUP_WINDOW))
+    }
+
+    /// Returns an error that parks an environment that committed within the group
+    /// until the group is committed, checking back once the group's window is over
+    /// (and periodically after that)
+    fn group_commit_wait(&self) -> Error {
+        let now = Instant::now();
+        match self.group {
+            Some(ref group) if group.deadline > now => Error::Park(Some(group.deadline)),
+            _ => Error::Park(Some(now + self.group_window())),
+        }
+    }
+
+    /// Returns an error that parks the environment waiting for a write
+    /// transaction, or a timeout error if it has been waiting for too long
+    fn write_wait(&mut self, pid: EnvId) -> Error {
+        let started = *self.write_waits.entry(pid).or_insert_with(Instant::now);
+        match self.db.as_ref().max_write_wait {
+            Some(max) if started.elapsed() >= max => {
+                self.cancel_write_wait(pid);
+                error_write_timeout!()
+            },
+            max => Error::Park(max.map(|max| started + max)),
+        }
+    }
+
+    fn cancel_write_wait(&mut self, pid: EnvId) {
+        if self.write_waits.remove(&pid).is_some() {
+            self.db.as_ref().cancel_write(pid);
+            if let Some(ref mut group) = self.group {
+                group.waiting.retain(|&(id, _)| id != pid);
+            }
+        }
+    }
+
+    /// Lets the next waiting environment write within the group, or commits
+    /// the group if there are none or the group is full or its window is over
+    fn advance_group(&mut self) {
+        let max = self.db.as_ref().group_commit.unwrap_or(1);
+        let next = match self.group {
+            Some(ref mut group) if group.size < max && Instant::now() < group.deadline =>
+                group.waiting.pop_front(),
+            _ => None,
+        };
+        match next {
+            Some((pid, waker)) => {
+                self.group.as_mut().unwrap().next = Some(pid);
+                waker.wake();
+            },
+            None => self.commit_group(),
+        }
+    }
+
+    fn commit_group(&mut self) {
+        if let Some(group) = self.group.take() {
+            let WriteGroup { txn, committed, waiting, changes, new_streams, .. } = group;
+            let id = txn.id();
+            let result = {
+                let publisher = &self.publisher;
+                let storage = self.db.as_ref();
+                txn.commit_then(|| {
+                    settle_keys(storage, &new_streams, true);
+                    if let Some(ref topic) = storage.cdc_topic {
+                        publish_changes(publisher, topic, id, &changes)
+                    }
+                })
+            }.map_err(|err| String::from(err.description()));
+            if result.is_err() {
+                settle_keys(self.db.as_ref(), &new_streams, false);
+            }
+            for (pid, waker) in committed {
+                self.group_commits.insert(pid, result.clone());
+                waker.wake();
+            }
+            // the rest will start a new group
+            for (_, waker) in waiting {
+                waker.wake();
+            }
+        }
+    }
+
+    fn group_commit_result(&mut self, pid: EnvId) -> Option<PassResult<'a>> {
+        self.group_commits.remove(&pid).map(|result| result.map_err(|reason| {
+            let vec = Vec::new();
+            error_program!(reason.as_bytes(), &vec, ERROR_DATABASE)
+        }))
+    }
+
+    /// Handles a failed write. If the map is full and the environment's top-level
+    /// WRITE can be retried, it will be, once the map has been grown.
+    fn write_failed(&mut self, env: &mut Env<'a>, pid: EnvId, err: lmdb::Error) -> PassResult<'a> {
+        let map_full = match err {
+            lmdb::Error::Code(code) => code == lmdb::error::MAP_FULL,
+            _ => false,
+        };
+        if map_full && self.retry_write(env, pid) {
+            Ok(())
+        } else {
+            Err(error_database!(err))
+        }
+    }
+
+    /// Ends environment's transactions and rolls it back to the beginning
+    /// of its top-level WRITE, requesting the map to be grown. Returns `false`
+    /// if the WRITE can't be retried.
+    ///
+    /// Side effects that are not part of the transaction (such as PUBLISH or
+    /// $SYSTEM/BACKUP) can't be undone, so WRITEs that had any are not retried.
+    fn retry_write(&mut self, env: &mut Env<'a>, pid: EnvId) -> bool {
+        let retriable = self.write_snapshots.get(&pid)
+            .map_or(false, |snapshot| snapshot.side_effects == env.side_effects);
+        if !retriable || !self.db.as_ref().request_map_growth() {
+            return false;
+        }
+        let snapshot = self.write_snapshots.remove(&pid).unwrap();
+        let member = self.group.as_ref().map_or(false, |group| group.active == Some((pid, 0)));
+        while self.txns.get(&pid).map_or(false, |vec| vec.len() > 0) {
+            self.end_transaction(pid);
+        }
+        // the map can only be grown once the group's transaction is closed,
+        // so the group is committed with the WRITEs that already committed within it
+        // instead of letting the next one in (which would wait for the growth forever)
+        if member {
+            self.group.as_mut().unwrap().active = None;
+            self.commit_group();
+        }
+        if let Some(vec) = self.keyspaces.get_mut(&pid) {
+            vec.truncate(snapshot.keyspaces);
+        }
+        if let Some(vec) = self.as_of.get_mut(&pid) {
+            vec.truncate(snapshot.as_of);
+        }
+        self.index_depths.remove(&pid);
+        while env.pop().is_some() {}
+        for item in snapshot.stack {
+            try!(env.push(item));
+        }
+        try!(env.push(snapshot.code));
+        env.program = snapshot.program;
+        env.program.push(WRITE);
+        env.dictionary = snapshot.dictionary;
+        env.tracking_errors = snapshot.tracking_errors;
+        true
+    }
+
+    /// Drops environment's cursors of transactions at `depth` and deeper
+    fn drop_cursors(&mut self, pid: EnvId, depth: usize) {
+        self.cursors = mem::replace(&mut self.cursors, BTreeMap::new()).into_iter()
+            .filter(|&(ref key, ref value)| key.0 != pid || value.0 < depth).collect();
+        let cursors = &self.cursors;
+        self.ranges.retain(|key, _| cursors.contains_key(key));
+        self.cursor_keyspaces.retain(|key, _| cursors.contains_key(key));
+    }
+
+    /// Returns the name of the current keyspace (empty for the default keyspace)
+    fn keyspace_name(&self, pid: EnvId) -> &'a [u8] {
+        self.keyspaces.get(&pid).and_then(|v| v.last()).map_or(&b""[..], |&(name, _)| name)
+    }
+
+    /// Returns definitions (name, key prefix and closure) of the current keyspace's
+    /// indexes, as seen by the current transaction
+    fn index_definitions(&self, pid: EnvId) -> Result<Vec<(Vec<u8>, Vec<u8>, Vec<u8>)>, lmdb::Error> {
+        let indexes = match self.db.as_ref().opened_keyspace(storage::INDEXES_KEYSPACE) {
+            Some(indexes) => indexes,
+            None => return Ok(Vec::new()),
+        };
+        let txn = match current_transaction!(self, pid) {
+            Some(txn) => txn,
+            None => return Ok(Vec::new()),
+        };
+        let (start, end) = index_definitions_range(self.keyspace_name(pid));
+        let pairs = {
+            let mut cursor = try!(txn.cursor(&indexes));
+            match txn.access() {
+                Accessor::Const(acc) => scan(&mut cursor, &acc, &start, Some(&end[..])),
+                Accessor::Write(acc) => scan(&mut cursor, &acc, &start, Some(&end[..])),
+            }
+        };
+        Ok(try!(pairs).into_iter().filter_map(|(key, value)| {
+            let name = match tuple::unpack(&key).and_then(|mut elements| elements.pop()) {
+                Some(Element::Binary(name)) => name,
+                _ => return None,
+            };
+            decode_index_definition(&value).map(|(prefix, closure)| (name, prefix, closure))
+        }).collect())
+    }
+
+    /// Returns the latest definitions of stored procedures, reading them (and
+    /// caching them for all schedulers) if they haven't been read since they last
+    /// changed. Returns `None` if a read transaction can't be started at the moment.
+    fn procedures(&self) -> Option<Result<Arc<HashMap<Vec<u8>, Vec<u8>>>, lmdb::Error>> {
+        let storage = self.db.as_ref();
+        let generation = match storage.procedures.get() {
+            Ok(definitions) => return Some(Ok(definitions)),
+            Err(generation) => generation,
+        };
+        let mut definitions = HashMap::new();
+        // the keyspace is only created once the first procedure is defined
+        if let Some(procedures) = storage.opened_keyspace(storage::PROCEDURES_KEYSPACE) {
+            let txn = match storage.read() {
+                Some(Ok(txn)) => txn,
+                Some(Err(err)) => return Some(Err(err)),
+                None => return None,
+            };
+            let pairs = {
+                let mut cursor = match txn.cursor(&*procedures) {
+                    Ok(cursor) => cursor,
+                    Err(err) => return Some(Err(err)),
+                };
+                match scan(&mut cursor, &txn.access(), b"", None) {
+                    Ok(pairs) => pairs,
+                    Err(err) => return Some(Err(err)),
+                }
+            };
+            // versions of a procedure are sorted, the latest one comes last
+            for (key, definition) in pairs {
+                if let Some(&[Element::Binary(ref name), _]) = tuple::unpack(&key).as_ref().map(|v| v.as_slice()) {
+                    definitions.insert(name.clone(), definition);
+                }
+            }
+            // an empty definition marks an undefined procedure
+            definitions.retain(|_, definition| definition.len() > 0);
+        }
+        let definitions = Arc::new(definitions);
+        storage.procedures.cache(generation, definitions.clone());
+        Some(Ok(definitions))
+    }
+
+    /// Runs `f` within a write transaction of its own against the given reserved
+    /// keyspace (creating the keyspace if necessary) and commits it. Returns `None`
+    /// if the write lock is taken or the map is waiting to be grown.
+    fn reserved_write<F, R>(&self, keyspace: &str, f: F) -> Option<Result<R, lmdb::Error>>
+        where F: FnOnce(&WriteTransactionContainer<'a>, &lmdb::Database<'a>) -> Result<R, lmdb::Error> {
+        let db = self.db.as_ref();
+        if db.map_growth_pending() {
+            return None;
+        }
+        let reserved = match db.keyspace(keyspace, true) {
+            Some(Ok(reserved)) => reserved,
+            Some(Err(err)) => return Some(Err(err)),
+            None => return None,
+        };
+        let txn = match db.write() {
+            Some(Ok(txn)) => txn,
+            Some(Err(err)) => return Some(Err(err)),
+            None => return None,
+        };
+        let result = f(&txn, &*reserved);
+        Some(result.and_then(|result| txn.commit().map(|_| result)))
+    }
+
+    #[inline]
+    pub fn handle_write(&mut self,
+                        env: &mut Env<'a>,
+                        instruction: &'a [u8],
+                        pid: EnvId)
+                        -> PassResult<'a> {
+        match instruction {
+            WRITE => {
+                let v = stack_pop!(env);
+                let top_level = self.txns.get(&pid).map_or(true, |vec| vec.len() == 0);
+                // no new transactions are started while the map is waiting to be grown
+                if top_level && self.db.as_ref().map_growth_pending() {
+                    try!(env.push(v));
+                    return Err(Error::Reschedule);
+                }
+                let result = match self.nested_write(pid) {
+                    Some(result) => Ok(result),
+                    None if self.db.as_ref().group_commit.is_some() => self.group_write(env.waker(), pid),
+                    None => self.write(env.waker(), pid),
+                };
+                match result {
+                    Ok(Ok(txn)) => {
+                        if top_level && self.db.as_ref().map_size_step.is_some() {
+                            let keyspaces = self.keyspaces.get(&pid).map_or(0, |vec| vec.len());
+                            let as_of = self.as_of.get(&pid).map_or(0, |vec| vec.len());
+                            self.write_snapshots.insert(pid, WriteSnapshot {
+                                code: v,
+                                stack: env.stack().to_vec(),
+                                program: env.program.clone(),
+                                dictionary: env.dictionary.clone(),
+                                tracking_errors: env.tracking_errors,
+                                side_effects: env.side_effects,
+                                keyspaces: keyspaces,
+                                as_of: as_of,
+                            });
+                        }
+                        if !self.txns.contains_key(&pid) {
+                            self.txns.insert(pid, Vec::new());
+                        }
+                        self.txns.get_mut(&pid).unwrap().push(Txn::Write(txn));
+                        env.program.push(WRITE_END);
+                        env.program.push(v);
+                        Ok(())
+                    },
+                    Ok(Err(e)) => Err(error_database!(e)),
+                    Err(err @ Error::Reschedule) | Err(err @ Error::Park(_)) => {
+                        try!(env.push(v));
+                        Err(err)
+                    },
+                    Err(err) => Err(err),
+                }
+            }
+            WRITE_END => {
+                if let Some(result) = self.group_commit_result(pid) {
+                    return result;
+                }
+                let depth = self.txns.get(&pid).map_or(0, |vec| vec.len());
+                if depth <= 1 {
+                    self.write_snapshots.remove(&pid);
+                }
+                let (member, committed) = match self.group {
+                    Some(ref group) => (depth > 0 && group.active == Some((pid, depth - 1)),
+                                        group.committed.iter().any(|&(id, _)| id == pid)),
+                    None => (false, false),
+                };
+                if committed {
+                    // still waiting for the group to be committed. If nobody is writing
+                    // within the group once its window is over (the next member got
+                    // cancelled before it joined, for example), it's committed right away
+                    let expired = self.group.as_ref()
+                        .map_or(false, |group| group.active.is_none() && Instant::now() >= group.deadline);
+                    if expired {
+                        self.commit_group();
+                        return self.group_commit_result(pid).unwrap_or_else(|| Err(self.group_commit_wait()));
+                    }
+                    return Err(self.group_commit_wait());
+                }
+                if !member {
+                    self.end_transaction(pid);
+                    return Ok(());
+                }
+                let committed = match self.txns.get(&pid).and_then(|vec| vec.last()) {
+                    Some(&Txn::Committed) => true,
+                    _ => false,
+                };
+                self.end_transaction(pid);
+                {
+                    let group = self.group.as_mut().unwrap();
+                    group.active = None;
+                    if committed {
+                        group.committed.push((pid, env.waker().unwrap()));
+                    }
+                }
+                self.advance_group();
+                if committed {
+                    // the WRITE is only done once the group is committed
+                    self.group_commit_result(pid).unwrap_or_else(|| Err(self.group_commit_wait()))
+                } else {
+                    Ok(())
+                }
+            }
+            _ => Err(Error::UnknownInstruction),
+        }
+    }
+
+    #[inline]
     pub fn handle_read(&mut self,
                        env: &mut Env<'a>,
                        instruction: &'a [u8],
@@ -279,8 +1597,16 @@ impl<'a, T : AsRef<storage::Storage<'a>> + 'a> Handler<'a, T> {
         match instruction {
             READ => {
                 let v = stack_pop!(env);
+                let top_level = self.txns.get(&pid).map_or(true, |vec| vec.len() == 0);
+                if top_level && self.db.as_ref().map_growth_pending() {
+                    try!(env.push(v));
+                    return Err(Error::Reschedule);
+                }
                 match self.db.as_ref().read() {
-                    None => Err(Error::Reschedule),
+                    None => {
+                        try!(env.push(v));
+                        Err(Error::Reschedule)
+                    },
                     Some(result) =>
                         match result {
                             Err(e) => Err(error_database!(e)),
@@ -297,69 +1623,255 @@ impl<'a, T : AsRef<storage::Storage<'a>> + 'a> Handler<'a, T> {
                 }
             }
             READ_END => {
-                match self.txns.get_mut(&pid).unwrap().pop() {
-                    Some(_) => {
-                        self.cursors = mem::replace(&mut self.cursors,
-                                                    BTreeMap::new()).into_iter()
-                            .filter(|t| ((*t).1).0 != TxType::Read).collect();
-                    },
-                    _ => {}
+                self.end_transaction(pid);
+                Ok(())
+            }
+            _ => Err(Error::UnknownInstruction),
+        }
+    }
+
+    #[inline]
+    pub fn handle_keyspace(&mut self,
+                           env: &mut Env<'a>,
+                           instruction: &'a [u8],
+                           pid: EnvId)
+                           -> PassResult<'a> {
+        match instruction {
+            KEYSPACE => {
+                let name = stack_pop!(env);
+                let v = stack_pop!(env);
+                let name_str = match str::from_utf8(name) {
+                    Ok(name_str) if name_str.len() > 0 => name_str,
+                    _ => return Err(error_invalid_value!(name)),
                 };
+                // Keyspaces can only be created outside of transactions
+                let create = self.txns.get(&pid).map_or(true, |v| v.len() == 0);
+                let pending = create && self.db.as_ref().map_growth_pending();
+                // within a write transaction, the write lock needed to open a keyspace
+                // is held by the environment itself, so only opened keyspaces are available
+                let writing = self.txns.get(&pid).map_or(false, |v| v.iter().any(|txn| match txn {
+                    &Txn::Read(_) => false,
+                    _ => true,
+                }));
+                let keyspace = if pending {
+                    None
+                } else if writing {
+                    Some(self.db.as_ref().opened_keyspace(name_str)
+                         .ok_or(lmdb::Error::Code(lmdb::error::NOTFOUND)))
+                } else {
+                    self.db.as_ref().keyspace(name_str, create)
+                };
+                match keyspace {
+                    None => {
+                        try!(env.push(v));
+                        try!(env.push(name));
+                        Err(Error::Reschedule)
+                    },
+                    Some(Err(lmdb::Error::Code(code))) if code == lmdb::error::NOTFOUND =>
+                        Err(error_unknown_keyspace!(name)),
+                    Some(Err(e)) => Err(error_database!(e)),
+                    Some(Ok(db)) => {
+                        if !self.keyspaces.contains_key(&pid) {
+                            self.keyspaces.insert(pid, Vec::new());
+                        }
+                        self.keyspaces.get_mut(&pid).unwrap().push((name, db));
+                        env.program.push(KEYSPACE_END);
+                        env.program.push(v);
+                        Ok(())
+                    }
+                }
+            }
+            KEYSPACE_END => {
+                let _ = self.keyspaces.get_mut(&pid).and_then(|vec| vec.pop());
+                Ok(())
+            }
+            _ => Err(Error::UnknownInstruction),
+        }
+    }
+
+    #[inline]
+    pub fn handle_as_of(&mut self,
+                        env: &mut Env<'a>,
+                        instruction: &'a [u8],
+                        pid: EnvId)
+                        -> PassResult<'a> {
+        match instruction {
+            ASOF => {
+                let hlc = stack_pop!(env);
+                let v = stack_pop!(env);
+                if hlc.len() != HLC_SIZE {
+                    return Err(error_invalid_value!(hlc));
+                }
+                self.as_of.entry(pid).or_insert_with(Vec::new).push(hlc);
+                env.program.push(ASOF_END);
+                env.program.push(v);
+                Ok(())
+            }
+            ASOF_END => {
+                let _ = self.as_of.get_mut(&pid).and_then(|vec| vec.pop());
                 Ok(())
             }
             _ => Err(Error::UnknownInstruction),
         }
     }
 
+    /// Returns the HLC timestamp of the innermost ASOF scope
+    fn as_of_timestamp(&self, pid: EnvId) -> Option<&'a [u8]> {
+        self.as_of.get(&pid).and_then(|v| v.last()).cloned()
+    }
+
     #[inline]
-    pub fn handle_assoc(&self,
+    pub fn handle_assoc(&mut self,
 						env: &mut Env<'a>,
 						instruction: &'a [u8],
 						pid: EnvId)
 						-> PassResult<'a> {
         instruction_is!(instruction, ASSOC);
-        match self.txns.get(&pid)
-            .and_then(|v| Some(&v[v.len() - 1]))
-            .and_then(|txn| match txn.tx_type() {
-                TxType::Write => Some(txn),
-                _ => None
-            }) {
+        let (key, value, change, new_stream, result) = match current_transaction!(self, pid) {
             Some(&Txn::Write(ref txn)) => {
                 let value = stack_pop!(env);
                 let key = stack_pop!(env);
 
+                let compressed = if self.db.as_ref().compression.applies(self.keyspace_name(pid), key) {
+                    // values that look compressed are always compressed,
+                    // so that they are read back the way they were written
+                    match compression::compress_smaller(value) {
+                        None if value.starts_with(compression::HEADER) => Some(compression::compress(value)),
+                        compressed => compressed,
+                    }
+                } else {
+                    None
+                };
+                let stored = compressed.as_ref().map_or(value, |compressed| compressed.as_slice());
+
                 let mut access = txn.access();
 
-                match access.put(&self.db.as_ref().db, key, value, lmdb::put::NOOVERWRITE) {
-                    Ok(_) => Ok(()),
-                    Err(lmdb::Error::Code(code)) if lmdb::error::KEYEXIST == code => Err(error_duplicate_key!(key)),
-                    Err(err) => Err(error_database!(err)),
+                let storage = self.db.as_ref();
+                let (encrypted, new_stream) = match storage.encryption.as_ref().and_then(|encryption| encryption.stream(key)) {
+                    Some(stream) => {
+                        let (encrypted, new) = try!(encrypt_value(storage, &mut access, stream, stored));
+                        (Some(encrypted), if new { Some(Vec::from(stream)) } else { None })
+                    },
+                    None => (None, None),
+                };
+                let stored = encrypted.as_ref().map_or(stored, |encrypted| encrypted.as_slice());
+
+                // changes are published the way they are stored,
+                // so encrypted values never leave the database in plain text
+                let change = if storage.cdc_topic.is_some() { Some(Vec::from(stored)) } else { None };
+
+                (key, value, change, new_stream,
+                 access.put(database!(self, pid), key, stored, lmdb::put::NOOVERWRITE))
+            },
+            _ => return Err(error_no_transaction!())
+        };
+        if let Some(stream) = new_stream {
+            let depth = self.txns.get(&pid).unwrap().len() - 1;
+            self.new_streams.entry((pid, depth)).or_insert_with(Vec::new).push(stream);
+        }
+        match result {
+            Ok(_) => {
+                let keyspace = self.keyspace_name(pid);
+                if let Some(change) = change {
+                    let depth = self.txns.get(&pid).unwrap().len() - 1;
+                    self.changes.entry((pid, depth)).or_insert_with(Vec::new)
+                        .push((Vec::from(keyspace), Vec::from(key), change));
+                }
+                // index entries are written by the matching index definitions'
+                // closures, run right after ASSOC within the same transaction
+                if keyspace == storage::INDEXES_KEYSPACE.as_bytes() {
+                    return Ok(());
+                }
+                let definitions = match self.index_definitions(pid) {
+                    Ok(definitions) => definitions,
+                    Err(err) => return Err(error_database!(err)),
+                };
+                let mut program = Vec::new();
+                for (name, prefix, closure) in definitions {
+                    if key.starts_with(&prefix) {
+                        index_program(&mut program, keyspace, &name, &closure, key, value);
+                    }
                 }
+                if program.len() > 0 {
+                    let slice = alloc_and_write!(program.as_slice(), env);
+                    env.program.push(slice);
+                }
+                Ok(())
             },
-            _ => Err(error_no_transaction!())
+            Err(lmdb::Error::Code(code)) if lmdb::error::KEYEXIST == code => Err(error_duplicate_key!(key)),
+            Err(err) => self.write_failed(env, pid, err),
         }
     }
 
     #[inline]
     pub fn handle_commit(&mut self,
-						 _: &Env<'a>,
+						 env: &mut Env<'a>,
 						 instruction: &'a [u8],
 						 pid: EnvId)
 						 -> PassResult<'a> {
         instruction_is!(instruction, COMMIT);
-        match self.txns.get_mut(&pid)
-            .and_then(|vec| vec.pop()) {
-            Some(Txn::Write(txn)) => {
-                match txn.commit() {
-                    Ok(_) => Ok(()),
-                    Err(reason) => Err(error_database!(reason))
+        let depth = match current_transaction!(self, pid) {
+            Some(&Txn::Write(_)) => self.txns.get(&pid).unwrap().len() - 1,
+            _ => return Err(error_no_transaction!()),
+        };
+        self.drop_cursors(pid, depth);
+        // the transaction is replaced with a placeholder so that
+        // WRITE_END wouldn't end the parent transaction
+        let txn = mem::replace(&mut self.txns.get_mut(&pid).unwrap()[depth], Txn::Committed);
+        let changes = self.changes.remove(&(pid, depth)).unwrap_or_default();
+        let new_streams = self.new_streams.remove(&(pid, depth)).unwrap_or_default();
+        // changes (and new streams) of nested transactions (including the ones of WRITEs
+        // within a group) are merged into the parent transaction and only published
+        // (or have their keys committed) once it is committed
+        let parent = self.txns.get(&pid).unwrap()[..depth].iter().rposition(|txn| match txn {
+            &Txn::Write(_) => true,
+            _ => false,
+        });
+        let member = self.group.as_ref().map_or(false, |group| group.active == Some((pid, depth)));
+        let txn = match txn {
+            Txn::Write(txn) => txn,
+            _ => unreachable!(),
+        };
+        if parent.is_some() || member {
+            match txn.commit() {
+                Ok(_) => {
+                    match parent {
+                        Some(parent) => {
+                            self.changes.entry((pid, parent)).or_insert_with(Vec::new).extend(changes);
+                            self.new_streams.entry((pid, parent)).or_insert_with(Vec::new).extend(new_streams);
+                        },
+                        None => {
+                            let group = self.group.as_mut().unwrap();
+                            group.changes.extend(changes);
+                            group.new_streams.extend(new_streams);
+                        },
+                    }
+                    Ok(())
+                },
+                Err(reason) => {
+                    settle_keys(self.db.as_ref(), &new_streams, false);
+                    self.write_failed(env, pid, reason)
                 }
-            },
-            Some(txn) => {
-                let _ = self.txns.get_mut(&pid).unwrap().push(txn);
-                Err(error_no_transaction!())
-            },
-            None => Err(error_no_transaction!())
+            }
+        } else {
+            let id = txn.id();
+            let result = {
+                let publisher = &self.publisher;
+                let storage = self.db.as_ref();
+                txn.commit_then(|| {
+                    settle_keys(storage, &new_streams, true);
+                    if let Some(ref topic) = storage.cdc_topic {
+                        publish_changes(publisher, topic, id, &changes)
+                    }
+                })
+            };
+            match result {
+                Ok(_) => Ok(()),
+                Err(reason) => {
+                    settle_keys(self.db.as_ref(), &new_streams, false);
+                    self.write_failed(env, pid, reason)
+                }
+            }
         }
     }
 
@@ -372,14 +1884,19 @@ impl<'a, T : AsRef<storage::Storage<'a>> + 'a> Handler<'a, T> {
                        -> PassResult<'a> {
         instruction_is!(instruction, RETR);
         let key = stack_pop!(env);
-        self.txns.get(&pid)
-            .and_then(|v| Some(&v[v.len() - 1]))
+        let storage = self.db.as_ref();
+        let limit = decode_limit(env);
+        current_transaction!(self, pid)
             .and_then(|txn| Some(txn.access()))
             .map_or_else(|| Err(error_no_transaction!()), |acc| {
-                match acc.get::<[u8], [u8]>(&self.db.as_ref().db, key) {
+                match acc.get::<[u8], [u8]>(database!(self, pid), key) {
                     Ok(Some(val)) => {
-                        let slice = alloc_and_write!(val, env);
-                        env.push(slice);
+                        let slice = match decode_value(storage, self.keyspace_name(pid), key, val, limit) {
+                            Ok(Some(val)) => alloc_and_write!(val.as_slice(), env),
+                            Ok(None) => alloc_and_write!(val, env),
+                            Err(err) => return Err(error_decode!(err, val)),
+                        };
+                        try!(env.push(slice));
                         Ok(())
                     },
                     Ok(None) => Err(error_unknown_key!(key)),
@@ -396,17 +1913,16 @@ impl<'a, T : AsRef<storage::Storage<'a>> + 'a> Handler<'a, T> {
                          -> PassResult<'a> {
         instruction_is!(instruction, ASSOCQ);
         let key = stack_pop!(env);
-        self.txns.get(&pid)
-            .and_then(|v| Some(&v[v.len() - 1]))
+        current_transaction!(self, pid)
             .and_then(|txn| Some(txn.access()))
             .map_or_else(|| Err(error_no_transaction!()),  |acc| {
-                match acc.get::<[u8], [u8]>(&self.db.as_ref().db, key) {
+                match acc.get::<[u8], [u8]>(database!(self, pid), key) {
                     Ok(Some(_)) => {
-                        env.push(STACK_TRUE);
+                        try!(env.push(STACK_TRUE));
                         Ok(())
                     },
                     Ok(None) => {
-                        env.push(STACK_FALSE);
+                        try!(env.push(STACK_FALSE));
                         Ok(())
                     }
                     Err(err) => Err(error_database!(err)),
@@ -424,22 +1940,39 @@ impl<'a, T : AsRef<storage::Storage<'a>> + 'a> Handler<'a, T> {
 						 instruction: &'a [u8],
 						 pid: EnvId)
 						 -> PassResult<'a> {
-        use serde_cbor;
         instruction_is!(instruction, CURSOR);
-        let db = self.db.as_ref();
-        let cursor = self.txns.get(&pid)
-            .and_then(|v| Some(&v[v.len() - 1]))
-            .map(|txn| txn.cursor(&db.db));
+        self.new_cursor(env, pid).map(|_| ())
+    }
+
+    /// Creates a cursor in the current transaction and pushes its identifier
+    /// on the stack
+    fn new_cursor(&mut self, env: &mut Env<'a>, pid: EnvId) -> Result<Vec<u8>, Error> {
+        self.new_cursor_in(env, pid, None)
+    }
+
+    /// Creates a cursor over the given keyspace (or the current one, if there's none)
+    /// in the current transaction and pushes its identifier on the stack
+    fn new_cursor_in(&mut self, env: &mut Env<'a>, pid: EnvId, keyspace: Option<(&'a [u8], &lmdb::Database<'a>)>)
+                     -> Result<Vec<u8>, Error> {
+        use serde_cbor;
+        let (name, db) = match keyspace {
+            Some(keyspace) => keyspace,
+            None => (self.keyspace_name(pid), database!(self, pid)),
+        };
+        let depth = self.txns.get(&pid).map_or(0, |vec| vec.len()).saturating_sub(1);
+        let cursor = current_transaction!(self, pid)
+            .map(|txn| txn.cursor(db));
         match cursor {
             Some(cursor) => {
                 match cursor {
                     Ok(cursor) => {
                         let id = CursorId::new();
                         let bytes = serde_cbor::to_vec(&id).unwrap();
-                        self.cursors.insert((pid.clone(), bytes.clone()), (tx_type!(self, pid), Handler::<T>::cast_away(cursor)));
+                        self.cursors.insert((pid.clone(), bytes.clone()), (depth, Handler::<T>::cast_away(cursor)));
+                        self.cursor_keyspaces.insert((pid.clone(), bytes.clone()), name);
                         let slice = alloc_and_write!(bytes.as_slice(), env);
-                        env.push(slice);
-                        Ok(())
+                        try!(env.push(slice));
+                        Ok(bytes)
                     },
                     Err(err) => Err(error_database!(err))
                 }
@@ -448,6 +1981,132 @@ impl<'a, T : AsRef<storage::Storage<'a>> + 'a> Handler<'a, T> {
         }
     }
 
+    #[inline]
+    pub fn handle_cursor_range(&mut self,
+                               env: &mut Env<'a>,
+                               instruction: &'a [u8],
+                               pid: EnvId)
+                               -> PassResult<'a> {
+        instruction_is!(instruction, CURSOR_RANGE);
+        let limit = stack_pop!(env);
+        let flags = stack_pop!(env);
+        let end = stack_pop!(env);
+        let start = stack_pop!(env);
+        let known = RANGE_EXCLUDE_START | RANGE_INCLUDE_END | RANGE_REVERSE | RANGE_END_PREFIX;
+        if flags.len() != 1 || flags[0] & !known != 0 {
+            return Err(error_invalid_value!(flags));
+        }
+        let flags = flags[0];
+        let limit = match BigUint::from_bytes_be(limit).to_u64() {
+            Some(0) => None,
+            Some(limit) => Some(limit),
+            None => return Err(error_invalid_value!(limit)),
+        };
+        let (end, end_inclusive) = if flags & RANGE_END_PREFIX != 0 {
+            (prefix_successor(end), false)
+        } else if end.len() == 0 {
+            (None, false)
+        } else {
+            (Some(Vec::from(end)), flags & RANGE_INCLUDE_END != 0)
+        };
+        let range = Range {
+            start: if start.len() == 0 { None } else { Some(Vec::from(start)) },
+            start_inclusive: flags & RANGE_EXCLUDE_START == 0,
+            end: end,
+            end_inclusive: end_inclusive,
+            reverse: flags & RANGE_REVERSE != 0,
+            limit: limit,
+            count: 0,
+            positioned: false,
+        };
+        let id = try!(self.new_cursor(env, pid));
+        self.ranges.insert((pid, id), range);
+        Ok(())
+    }
+
+    #[inline]
+    pub fn handle_cursor_token(&mut self,
+                               env: &mut Env<'a>,
+                               instruction: &'a [u8],
+                               pid: EnvId)
+                               -> PassResult<'a> {
+        instruction_is!(instruction, CURSOR_TOKEN);
+        if current_transaction!(self, pid).is_none() {
+            return Err(error_no_transaction!())
+        }
+        let c = stack_pop!(env);
+
+        let (depth, txn) = cursor_transaction!(self, pid, c);
+        let tuple = (pid, Vec::from(c));
+        let positioned = self.ranges.get(&tuple).map_or(true, |range| range.positioned);
+        let mut cursor = self.cursors.remove(&tuple).unwrap().1;
+        let key = match txn.access() {
+            Accessor::Const(acc) => cursor.get_current::<[u8], [u8]>(&acc).map(|(key, _)| Vec::from(key)),
+            Accessor::Write(acc) => cursor.get_current::<[u8], [u8]>(&acc).map(|(key, _)| Vec::from(key)),
+        };
+        self.cursors.insert(tuple.clone(), (depth, cursor));
+        let key = match key {
+            Ok(key) if positioned => key,
+            _ => return Err(error_no_value!()),
+        };
+        let keyspace = self.cursor_keyspaces.get(&tuple).map_or(&b""[..], |name| *name);
+        let token = match self.ranges.get(&tuple) {
+            Some(range) => range.token(keyspace, txn.id() as u64, &key),
+            None => Range::unbounded().token(keyspace, txn.id() as u64, &key),
+        };
+        let slice = alloc_and_write!(token.as_slice(), env);
+        try!(env.push(slice));
+        Ok(())
+    }
+
+    #[inline]
+    pub fn handle_cursor_resume(&mut self,
+                                env: &mut Env<'a>,
+                                instruction: &'a [u8],
+                                pid: EnvId)
+                                -> PassResult<'a> {
+        instruction_is!(instruction, CURSOR_RESUME);
+        let token = stack_pop!(env);
+        let (mut range, key) = match Range::from_token(token) {
+            // tokens can only be resumed in the keyspace they were created in
+            Some((range, keyspace, _, key)) if keyspace == self.keyspace_name(pid) => (range, key),
+            _ => return Err(error_invalid_value!(token)),
+        };
+        let id = try!(self.new_cursor(env, pid));
+        let tuple = (pid, id);
+        {
+            let txn = current_transaction!(self, pid).unwrap();
+            let cursor = &mut self.cursors.get_mut(&tuple).unwrap().1;
+            match txn.access() {
+                Accessor::Const(acc) => range.resume(cursor, &acc, key),
+                Accessor::Write(acc) => range.resume(cursor, &acc, key),
+            };
+        }
+        self.ranges.insert(tuple, range);
+        Ok(())
+    }
+
+    #[inline]
+    pub fn handle_cursor_token_changedq(&mut self,
+                                        env: &mut Env<'a>,
+                                        instruction: &'a [u8],
+                                        pid: EnvId)
+                                        -> PassResult<'a> {
+        instruction_is!(instruction, CURSOR_TOKEN_CHANGEDQ);
+        let token = stack_pop!(env);
+        let txn_id = match Range::from_token(token) {
+            Some((_, _, txn_id, _)) => txn_id,
+            None => return Err(error_invalid_value!(token)),
+        };
+        match current_transaction!(self, pid) {
+            Some(txn) => {
+                try!(env.push(if txn.id() as u64 == txn_id { STACK_FALSE } else { STACK_TRUE }));
+                Ok(())
+            },
+            None => Err(error_no_transaction!()),
+        }
+    }
+
     #[inline]
     pub fn handle_cursor_first(&mut self,
                                env: &mut Env<'a>,
@@ -501,7 +2160,55 @@ impl<'a, T : AsRef<storage::Storage<'a>> + 'a> Handler<'a, T> {
                               -> PassResult<'a> {
         instruction_is!(instruction, CURSOR_SEEK);
         let key = stack_pop!(env);
-        cursor_op!(self, env, pid, seek_range_k, (key));
+        match self.as_of_timestamp(pid) {
+            Some(hlc) => self.cursor_seek_as_of(env, pid, key, hlc),
+            None => {
+                cursor_op!(self, env, pid, seek_range_k, (key));
+                Ok(())
+            }
+        }
+    }
+
+    /// Handles CURSOR/SEEKLAST within ASOF (outside of it, the builtin is used)
+    #[inline]
+    pub fn handle_cursor_seeklast(&mut self,
+                                  env: &mut Env<'a>,
+                                  instruction: &'a [u8],
+                                  pid: EnvId)
+                                  -> PassResult<'a> {
+        instruction_is!(instruction, CURSOR_SEEKLAST);
+        let hlc = match self.as_of_timestamp(pid) {
+            Some(hlc) => hlc,
+            None => return Err(Error::UnknownInstruction),
+        };
+        let prefix = stack_pop!(env);
+        self.cursor_seek_as_of(env, pid, prefix, hlc)
+    }
+
+    /// Positions the cursor on top of the stack at the latest version of `prefix`
+    /// not newer than `hlc`
+    fn cursor_seek_as_of(&mut self, env: &mut Env<'a>, pid: EnvId, prefix: &[u8], hlc: &[u8])
+                         -> PassResult<'a> {
+        if current_transaction!(self, pid).is_none() {
+            return Err(error_no_transaction!())
+        }
+        let c = stack_pop!(env);
+
+        let (depth, txn) = cursor_transaction!(self, pid, c);
+        let tuple = (pid, Vec::from(c));
+        let mut cursor = self.cursors.remove(&tuple).unwrap().1;
+        let result = match self.ranges.get_mut(&tuple) {
+            Some(range) => match txn.access() {
+                Accessor::Const(acc) => range.seek_as_of(&mut cursor, &acc, prefix, hlc),
+                Accessor::Write(acc) => range.seek_as_of(&mut cursor, &acc, prefix, hlc),
+            },
+            None => match txn.access() {
+                Accessor::Const(acc) => seek_as_of(&mut cursor, &acc, prefix, hlc).is_some(),
+                Accessor::Write(acc) => seek_as_of(&mut cursor, &acc, prefix, hlc).is_some(),
+            }
+        };
+        self.cursors.insert(tuple, (depth, cursor));
+        try!(env.push(if result { STACK_TRUE } else { STACK_FALSE }));
         Ok(())
     }
 
@@ -517,7 +2224,7 @@ impl<'a, T : AsRef<storage::Storage<'a>> + 'a> Handler<'a, T> {
             Err(false) => STACK_FALSE,
             Err(true) | Ok(false) => unreachable!(),
         };
-        env.push(result);
+        try!(env.push(result));
         Ok(())
     }
 
@@ -532,7 +2239,7 @@ impl<'a, T : AsRef<storage::Storage<'a>> + 'a> Handler<'a, T> {
            |(key, _) | {
               let slice = alloc_slice!(key.len(), env);
               slice.copy_from_slice(key);
-              env.push(slice);
+              try!(env.push(slice));
               Ok(())
         }, |_| error_no_value!())
     }
@@ -544,32 +2251,754 @@ impl<'a, T : AsRef<storage::Storage<'a>> + 'a> Handler<'a, T> {
                              pid: EnvId)
                              -> PassResult<'a> {
         instruction_is!(instruction, CURSOR_VAL);
+        let storage = self.db.as_ref();
+        let limit = decode_limit(env);
+        let keyspace = env.stack_top()
+            .and_then(|c| self.cursor_keyspaces.get(&(pid, Vec::from(c))).cloned())
+            .unwrap_or(self.keyspace_name(pid));
         cursor_map_op!(self, env, pid, get_current, (),
-           |(_, val) | {
-              let slice = alloc_slice!(val.len(), env);
-              slice.copy_from_slice(val);
-              env.push(slice);
+           |(key, val) | {
+              let slice = match decode_value(storage, keyspace, key, val, limit) {
+                  Ok(Some(val)) => alloc_and_write!(val.as_slice(), env),
+                  Ok(None) => alloc_and_write!(val, env),
+                  Err(err) => return Err(error_decode!(err, val)),
+              };
+              try!(env.push(slice));
               Ok(())
         }, |_| error_no_value!())
     }
 
     #[inline]
-    pub fn handle_maxkeysize(&mut self,
+    pub fn handle_compress(&mut self,
+                           env: &mut Env<'a>,
+                           instruction: &'a [u8],
+                           _: EnvId)
+                           -> PassResult<'a> {
+        instruction_is!(instruction, COMPRESS);
+        let value = stack_pop!(env);
+        let compressed = compression::compress(value);
+        let slice = alloc_and_write!(compressed.as_slice(), env);
+        try!(env.push(slice));
+        Ok(())
+    }
+
+    #[inline]
+    pub fn handle_decompress(&mut self,
                              env: &mut Env<'a>,
                              instruction: &'a [u8],
                              _: EnvId)
                              -> PassResult<'a> {
-        instruction_is!(instruction, MAXKEYSIZE);
-        let slice = alloc_and_write!(self.maxkeysize.as_slice(), env);
-        env.push(slice);
+        instruction_is!(instruction, DECOMPRESS);
+        let value = stack_pop!(env);
+        match compression::decompress(value, decode_limit(env)) {
+            Some(Ok(decompressed)) => {
+                let slice = alloc_and_write!(decompressed.as_slice(), env);
+                try!(env.push(slice));
+                Ok(())
+            },
+            Some(Err(compression::TooLarge)) => Err(super::error_memory_exceeded()),
+            None => Err(error_invalid_value!(value)),
+        }
+    }
+
+    #[inline]
+    pub fn handle_encrypt(&mut self,
+                          env: &mut Env<'a>,
+                          instruction: &'a [u8],
+                          pid: EnvId)
+                          -> PassResult<'a> {
+        instruction_is!(instruction, ENCRYPT);
+        let stream = stack_pop!(env);
+        let value = stack_pop!(env);
+        let storage = self.db.as_ref();
+        match storage.encryption {
+            Some(ref encryption) if !encryption.is_stream(stream) => return Err(error_invalid_value!(stream)),
+            Some(_) => (),
+            None => return Err(error_no_key_store!()),
+        }
+        let (encrypted, new) = match current_transaction!(self, pid) {
+            Some(&Txn::Write(ref txn)) => try!(encrypt_value(storage, &mut txn.access(), stream, value)),
+            _ => return Err(error_no_transaction!()),
+        };
+        if new {
+            let depth = self.txns.get(&pid).unwrap().len() - 1;
+            self.new_streams.entry((pid, depth)).or_insert_with(Vec::new).push(Vec::from(stream));
+        }
+        let slice = alloc_and_write!(encrypted.as_slice(), env);
+        try!(env.push(slice));
         Ok(())
     }
-}
 
-#[cfg(test)]
-#[allow(unused_variables, unused_must_use, unused_mut, unused_imports)]
-mod tests {
-    use pumpkinscript::{parse, offset_by_size};
+    #[inline]
+    pub fn handle_decrypt(&mut self,
+                          env: &mut Env<'a>,
+                          instruction: &'a [u8],
+                          _: EnvId)
+                          -> PassResult<'a> {
+        instruction_is!(instruction, DECRYPT);
+        let value = stack_pop!(env);
+        let encryption = match self.db.as_ref().encryption {
+            Some(ref encryption) => encryption,
+            None => return Err(error_no_key_store!()),
+        };
+        let stream = match encryption::stream(value) {
+            Some(stream) if encryption.is_stream(stream) => stream,
+            _ => return Err(error_invalid_value!(value)),
+        };
+        match encryption.keys.decrypt(value) {
+            Some(Ok(decrypted)) => {
+                let slice = alloc_and_write!(decrypted.as_slice(), env);
+                try!(env.push(slice));
+                Ok(())
+            },
+            Some(Err(err)) => Err(error_encryption!(err, stream)),
+            None => Err(error_invalid_value!(value)),
+        }
+    }
+
+    #[inline]
+    pub fn handle_shred(&mut self,
+                        env: &mut Env<'a>,
+                        instruction: &'a [u8],
+                        _: EnvId)
+                        -> PassResult<'a> {
+        instruction_is!(instruction, SHRED);
+        let stream = stack_pop!(env);
+        let result = match self.db.as_ref().encryption {
+            Some(ref encryption) if !encryption.is_stream(stream) => return Err(error_invalid_value!(stream)),
+            Some(ref encryption) => encryption.keys.shred(stream),
+            None => return Err(error_no_key_store!()),
+        };
+        match result {
+            Ok(()) => Ok(()),
+            Err(err) => Err(error_encryption!(err, stream)),
+        }
+    }
+
+    #[inline]
+    pub fn handle_index_define(&mut self,
+                               env: &mut Env<'a>,
+                               instruction: &'a [u8],
+                               pid: EnvId)
+                               -> PassResult<'a> {
+        instruction_is!(instruction, INDEX_DEFINE);
+        let closure = stack_pop!(env);
+        let prefix = stack_pop!(env);
+        let name = stack_pop!(env);
+        // definitions are written in a transaction of their own
+        if self.txns.get(&pid).map_or(false, |v| v.len() > 0) {
+            return Err(error_in_transaction!());
+        }
+        let key = index_definition_key(self.keyspace_name(pid), name);
+        let definition = (Vec::from(prefix), Vec::from(closure)).encode();
+        match self.reserved_write(storage::INDEXES_KEYSPACE, |txn, indexes| {
+            txn.access().put(indexes, &key[..], &definition[..], lmdb::put::NOOVERWRITE)
+        }) {
+            None => {
+                try!(env.push(name));
+                try!(env.push(prefix));
+                try!(env.push(closure));
+                Err(Error::Reschedule)
+            },
+            Some(Ok(_)) => Ok(()),
+            Some(Err(lmdb::Error::Code(code))) if code == lmdb::error::KEYEXIST =>
+                Err(error_duplicate_key!(name)),
+            Some(Err(err)) => Err(error_database!(err)),
+        }
+    }
+
+    #[inline]
+    pub fn handle_index_drop(&mut self,
+                             env: &mut Env<'a>,
+                             instruction: &'a [u8],
+                             pid: EnvId)
+                             -> PassResult<'a> {
+        instruction_is!(instruction, INDEX_DROP);
+        let name = stack_pop!(env);
+        if self.txns.get(&pid).map_or(false, |v| v.len() > 0) {
+            return Err(error_in_transaction!());
+        }
+        let keyspace = self.keyspace_name(pid);
+        let key = index_definition_key(keyspace, name);
+        let (start, end) = index_entries_range(keyspace, name, None);
+        match self.reserved_write(storage::INDEXES_KEYSPACE, |txn, indexes| {
+            let mut access = txn.access();
+            if try!(access.get::<[u8], [u8]>(indexes, &key[..]).to_opt()).is_none() {
+                return Ok(false);
+            }
+            try!(access.del_key(indexes, &key[..]));
+            let entries = {
+                let mut cursor = try!(txn.cursor(indexes));
+                try!(scan(&mut cursor, &access, &start, Some(&end[..])))
+            };
+            for (entry, _) in entries {
+                try!(access.del_key(indexes, &entry[..]));
+            }
+            Ok(true)
+        }) {
+            None => {
+                try!(env.push(name));
+                Err(Error::Reschedule)
+            },
+            Some(Ok(true)) => Ok(()),
+            Some(Ok(false)) => Err(error_unknown_key!(name)),
+            Some(Err(err)) => Err(error_database!(err)),
+        }
+    }
+
+    #[inline]
+    pub fn handle_index_list(&mut self,
+                             env: &mut Env<'a>,
+                             instruction: &'a [u8],
+                             pid: EnvId)
+                             -> PassResult<'a> {
+        instruction_is!(instruction, INDEX_LIST);
+        if current_transaction!(self, pid).is_none() {
+            return Err(error_no_transaction!())
+        }
+        let definitions = match self.index_definitions(pid) {
+            Ok(definitions) => definitions,
+            Err(err) => return Err(error_database!(err)),
+        };
+        let mut names = Vec::new();
+        for (name, _, _) in definitions {
+            names.append(&mut name.encode());
+        }
+        let slice = alloc_and_write!(names.as_slice(), env);
+        try!(env.push(slice));
+        Ok(())
+    }
+
+    #[inline]
+    pub fn handle_index_backfill(&mut self,
+                                 env: &mut Env<'a>,
+                                 instruction: &'a [u8],
+                                 pid: EnvId)
+                                 -> PassResult<'a> {
+        instruction_is!(instruction, INDEX_BACKFILL);
+        let name = stack_pop!(env);
+        match current_transaction!(self, pid) {
+            Some(&Txn::Write(_)) => (),
+            _ => return Err(error_no_transaction!()),
+        }
+        let definition = match self.index_definitions(pid) {
+            Ok(definitions) => definitions.into_iter().find(|&(ref n, _, _)| n.as_slice() == name),
+            Err(err) => return Err(error_database!(err)),
+        };
+        let (prefix, closure) = match definition {
+            Some((_, prefix, closure)) => (prefix, closure),
+            None => return Err(error_unknown_key!(name)),
+        };
+        let pairs = {
+            let db = database!(self, pid);
+            let txn = current_transaction!(self, pid).unwrap();
+            let end = prefix_successor(&prefix);
+            match txn.cursor(db) {
+                Ok(mut cursor) => match txn.access() {
+                    Accessor::Const(acc) => scan(&mut cursor, &acc, &prefix, end.as_ref().map(|end| end.as_slice())),
+                    Accessor::Write(acc) => scan(&mut cursor, &acc, &prefix, end.as_ref().map(|end| end.as_slice())),
+                },
+                Err(err) => Err(err),
+            }
+        };
+        let pairs = match pairs {
+            Ok(pairs) => pairs,
+            Err(err) => return Err(error_database!(err)),
+        };
+        let keyspace = self.keyspace_name(pid);
+        let mut program = Vec::new();
+        for (key, value) in pairs {
+            let value = match decode_value(self.db.as_ref(), keyspace, &key, &value, decode_limit(env)) {
+                Ok(decoded) => decoded.unwrap_or(value),
+                // values of shredded streams are not indexed
+                Err(DecodeError::Encryption(encryption::Error::Shredded(_))) => continue,
+                Err(err) => return Err(error_decode!(err, value)),
+            };
+            index_program(&mut program, keyspace, name, &closure, &key, &value);
+        }
+        if program.len() > 0 {
+            let slice = alloc_and_write!(program.as_slice(), env);
+            env.program.push(slice);
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn handle_index_cursor(&mut self,
+                               env: &mut Env<'a>,
+                               instruction: &'a [u8],
+                               pid: EnvId)
+                               -> PassResult<'a> {
+        instruction_is!(instruction, INDEX_CURSOR);
+        let index_key = stack_pop!(env);
+        let name = stack_pop!(env);
+        if current_transaction!(self, pid).is_none() {
+            return Err(error_no_transaction!())
+        }
+        let defined = match self.index_definitions(pid) {
+            Ok(definitions) => definitions.iter().any(|&(ref n, _, _)| n.as_slice() == name),
+            Err(err) => return Err(error_database!(err)),
+        };
+        let indexes = match self.db.as_ref().opened_keyspace(storage::INDEXES_KEYSPACE) {
+            Some(indexes) if defined => indexes,
+            _ => return Err(error_unknown_key!(name)),
+        };
+        let (start, end) = index_entries_range(self.keyspace_name(pid), name, Some(index_key));
+        let range = Range {
+            start: Some(start),
+            start_inclusive: true,
+            end: Some(end),
+            end_inclusive: false,
+            reverse: false,
+            limit: None,
+            count: 0,
+            positioned: false,
+        };
+        let id = try!(self.new_cursor_in(env, pid, Some((storage::INDEXES_KEYSPACE.as_bytes(), &indexes))));
+        self.ranges.insert((pid, id), range);
+        Ok(())
+    }
+
+    #[inline]
+    pub fn handle_index_begin(&mut self,
+                              env: &mut Env<'a>,
+                              instruction: &'a [u8],
+                              pid: EnvId)
+                              -> PassResult<'a> {
+        instruction_is!(instruction, INDEX_BEGIN);
+        let value = stack_pop!(env);
+        let key = stack_pop!(env);
+        self.index_depths.entry(pid).or_insert_with(Vec::new).push(env.stack().len());
+        try!(env.push(key));
+        try!(env.push(value));
+        Ok(())
+    }
+
+    #[inline]
+    pub fn handle_index_end(&mut self,
+                            env: &mut Env<'a>,
+                            instruction: &'a [u8],
+                            pid: EnvId)
+                            -> PassResult<'a> {
+        instruction_is!(instruction, INDEX_END);
+        let key = stack_pop!(env);
+        let name = stack_pop!(env);
+        let keyspace = stack_pop!(env);
+        let depth = match self.index_depths.get_mut(&pid).and_then(|v| v.pop()) {
+            Some(depth) => depth,
+            None => return Err(error_invalid_value!(name)),
+        };
+        // the closure consumed more than the key and the value
+        if env.stack().len() < depth {
+            return Err(error_invalid_value!(name));
+        }
+        let mut index_keys = Vec::new();
+        while env.stack().len() > depth {
+            index_keys.push(env.pop().unwrap());
+        }
+        let indexes = match self.db.as_ref().opened_keyspace(storage::INDEXES_KEYSPACE) {
+            Some(indexes) => indexes,
+            None => return Err(error_invalid_value!(name)),
+        };
+        let result = match current_transaction!(self, pid) {
+            Some(&Txn::Write(ref txn)) => {
+                let mut access = txn.access();
+                let mut result = Ok(());
+                for index_key in index_keys.iter().rev() {
+                    let entry = index_entry_key(keyspace, name, index_key, key);
+                    result = access.put(&indexes, &entry[..], key, lmdb::put::Flags::empty());
+                    if result.is_err() {
+                        break;
+                    }
+                }
+                result
+            },
+            _ => return Err(error_no_transaction!()),
+        };
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) => self.write_failed(env, pid, err),
+        }
+    }
+
+    /// Returns the version of the stream's last event in the current keyspace,
+    /// as seen by the current transaction
+    fn current_stream_version(&self, pid: EnvId, stream: &[u8]) -> Result<u64, Error> {
+        let txn = match current_transaction!(self, pid) {
+            Some(txn) => txn,
+            None => return Err(error_no_transaction!()),
+        };
+        let mut cursor = match txn.cursor(database!(self, pid)) {
+            Ok(cursor) => cursor,
+            Err(err) => return Err(error_database!(err)),
+        };
+        Ok(match txn.access() {
+            Accessor::Const(acc) => stream_version(&mut cursor, &acc, stream),
+            Accessor::Write(acc) => stream_version(&mut cursor, &acc, stream),
+        })
+    }
+
+    #[inline]
+    pub fn handle_stream_append(&mut self,
+                                env: &mut Env<'a>,
+                                instruction: &'a [u8],
+                                pid: EnvId)
+                                -> PassResult<'a> {
+        instruction_is!(instruction, STREAM_APPEND);
+        let expected = stack_pop!(env);
+        let event = stack_pop!(env);
+        let stream = stack_pop!(env);
+        if stream.len() == 0 {
+            return Err(error_invalid_value!(stream));
+        }
+        let expected_version = match BigUint::from_bytes_be(expected).to_u64() {
+            Some(version) => version,
+            None => return Err(error_invalid_value!(expected)),
+        };
+        match current_transaction!(self, pid) {
+            Some(&Txn::Write(_)) => (),
+            _ => return Err(error_no_transaction!()),
+        }
+        let version = try!(self.current_stream_version(pid, stream));
+        if version != expected_version {
+            let version = BigUint::from_u64(version).unwrap().to_bytes_be();
+            return Err(error_wrong_version!(&version));
+        }
+        if version == u64::max_value() {
+            return Err(error_invalid_value!(stream));
+        }
+        let new_version = BigUint::from_u64(version + 1).unwrap().to_bytes_be();
+        let slice = alloc_and_write!(new_version.as_slice(), env);
+        try!(env.push(slice));
+        // the event is written with ASSOC, so that it gets compressed,
+        // encrypted and indexed just like any other value
+        let key = stream_event_key(stream, version + 1);
+        let slice = alloc_and_write!(key.as_slice(), env);
+        try!(env.push(slice));
+        try!(env.push(event));
+        env.program.push(ASSOC);
+        Ok(())
+    }
+
+    #[inline]
+    pub fn handle_stream_version(&mut self,
+                                 env: &mut Env<'a>,
+                                 instruction: &'a [u8],
+                                 pid: EnvId)
+                                 -> PassResult<'a> {
+        instruction_is!(instruction, STREAM_VERSION);
+        let stream = stack_pop!(env);
+        if stream.len() == 0 {
+            return Err(error_invalid_value!(stream));
+        }
+        let version = try!(self.current_stream_version(pid, stream));
+        let version = BigUint::from_u64(version).unwrap().to_bytes_be();
+        let slice = alloc_and_write!(version.as_slice(), env);
+        try!(env.push(slice));
+        Ok(())
+    }
+
+    #[inline]
+    pub fn handle_stream_read(&mut self,
+                              env: &mut Env<'a>,
+                              instruction: &'a [u8],
+                              pid: EnvId)
+                              -> PassResult<'a> {
+        instruction_is!(instruction, STREAM_READ);
+        let backwards = stack_pop!(env);
+        let version = stack_pop!(env);
+        let stream = stack_pop!(env);
+        if stream.len() == 0 {
+            return Err(error_invalid_value!(stream));
+        }
+        let backwards = match backwards {
+            STACK_TRUE => true,
+            STACK_FALSE => false,
+            _ => return Err(error_invalid_value!(backwards)),
+        };
+        let version = match BigUint::from_bytes_be(version).to_u64() {
+            Some(version) => version,
+            None => return Err(error_invalid_value!(version)),
+        };
+        if current_transaction!(self, pid).is_none() {
+            return Err(error_no_transaction!())
+        }
+        let (start, end) = match backwards {
+            // reading backwards from version 0 starts at the last event
+            true if version == 0 => (0, u64::max_value()),
+            true => (0, version),
+            false => (version, u64::max_value()),
+        };
+        let range = Range {
+            start: Some(stream_event_key(stream, start)),
+            start_inclusive: true,
+            end: Some(stream_event_key(stream, end)),
+            end_inclusive: true,
+            reverse: backwards,
+            limit: None,
+            count: 0,
+            positioned: false,
+        };
+        let id = try!(self.new_cursor(env, pid));
+        self.ranges.insert((pid, id), range);
+        Ok(())
+    }
+
+    #[inline]
+    pub fn handle_maxkeysize(&mut self,
+                             env: &mut Env<'a>,
+                             instruction: &'a [u8],
+                             _: EnvId)
+                             -> PassResult<'a> {
+        instruction_is!(instruction, MAXKEYSIZE);
+        let slice = alloc_and_write!(self.maxkeysize.as_slice(), env);
+        try!(env.push(slice));
+        Ok(())
+    }
+
+    #[inline]
+    pub fn handle_backup(&mut self,
+                         env: &mut Env<'a>,
+                         instruction: &'a [u8],
+                         pid: EnvId)
+                         -> PassResult<'a> {
+        instruction_is!(instruction, BACKUP);
+        // the environment is parked until the copy is done
+        if let Some(backup) = self.backups.remove(&pid) {
+            let result = match backup.result.try_recv() {
+                Ok(result) => result,
+                Err(mpsc::TryRecvError::Empty) => {
+                    self.backups.insert(pid, backup);
+                    return Err(Error::Park(None));
+                },
+                Err(mpsc::TryRecvError::Disconnected) => Err(String::from("backup failed")),
+            };
+            let _ = backup.thread.join();
+            return result.map_err(|reason| {
+                let vec = Vec::new();
+                error_program!(reason.as_bytes(), &vec, ERROR_DATABASE)
+            });
+        }
+        let compact = stack_pop!(env);
+        let path = stack_pop!(env);
+        let compact = match compact {
+            STACK_TRUE => true,
+            STACK_FALSE => false,
+            _ => return Err(error_invalid_value!(compact)),
+        };
+        // only directories within the backup directory can be written to
+        let relative = match str::from_utf8(path) {
+            Ok(path_str) if path_str.len() > 0 => Path::new(path_str),
+            _ => return Err(error_invalid_value!(path)),
+        };
+        if !relative.components().all(|component| match component {
+            Component::Normal(_) => true,
+            _ => false,
+        }) {
+            return Err(error_invalid_value!(path));
+        }
+        let target = match self.db.as_ref().backup_dir {
+            Some(ref dir) => dir.join(relative).to_string_lossy().into_owned(),
+            None => return Err(error_no_backup_dir!()),
+        };
+        env.side_effects += 1;
+        let waker = match env.waker() {
+            Some(waker) => waker,
+            None => return match storage::backup(self.db.as_ref().env, &target, compact) {
+                Ok(()) => Ok(()),
+                Err(err) => Err(error_database!(err)),
+            },
+        };
+        // Copying takes as long as reading the whole database, so it's done in a
+        // thread of its own. The handler waits for it to finish when it's dropped
+        // (see `Drop`), so the environment outlives the thread.
+        let environment: &'static lmdb::Environment = unsafe { mem::transmute(self.db.as_ref().env) };
+        let (sender, receiver) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let result = storage::backup(environment, &target, compact).map_err(|err| err.to_string());
+            let _ = sender.send(result);
+            waker.wake();
+        });
+        self.backups.insert(pid, Backup { thread: thread, result: receiver });
+        Err(Error::Park(None))
+    }
+
+    #[inline]
+    pub fn handle_stats(&mut self,
+                        env: &mut Env<'a>,
+                        instruction: &'a [u8],
+                        _: EnvId)
+                        -> PassResult<'a> {
+        instruction_is!(instruction, STATS);
+        let storage = self.db.as_ref();
+        let (info, stat) = match storage.env.info()
+            .and_then(|info| storage.env.stat().map(|stat| (info, stat))) {
+            Ok(result) => result,
+            Err(err) => return Err(error_database!(err)),
+        };
+        let stats = stats_json(&[("mapsize", info.mapsize as u64),
+                                 ("pagesize", stat.psize as u64),
+                                 ("used_pages", info.last_pgno as u64 + 1),
+                                 ("last_txnid", info.last_txnid as u64),
+                                 ("readers", info.numreaders as u64),
+                                 ("maxreaders", info.maxreaders as u64),
+                                 ("map_resizes", storage.map_resizes() as u64)]);
+        let slice = alloc_and_write!(stats.as_bytes(), env);
+        try!(env.push(slice));
+        Ok(())
+    }
+
+    #[inline]
+    pub fn handle_dbstats(&mut self,
+                          env: &mut Env<'a>,
+                          instruction: &'a [u8],
+                          pid: EnvId)
+                          -> PassResult<'a> {
+        instruction_is!(instruction, DBSTATS);
+        let result = match current_transaction!(self, pid) {
+            Some(txn) => txn.stat(database!(self, pid)),
+            None => return Err(error_no_transaction!()),
+        };
+        match result {
+            Ok(stat) => {
+                let stats = stats_json(&[("entries", stat.entries as u64),
+                                         ("depth", stat.depth as u64),
+                                         ("branch_pages", stat.branch_pages as u64),
+                                         ("leaf_pages", stat.leaf_pages as u64),
+                                         ("overflow_pages", stat.overflow_pages as u64),
+                                         ("pagesize", stat.psize as u64)]);
+                let slice = alloc_and_write!(stats.as_bytes(), env);
+                try!(env.push(slice));
+                Ok(())
+            },
+            Err(err) => Err(error_database!(err)),
+        }
+    }
+
+    #[inline]
+    pub fn handle_system_define(&mut self,
+                                env: &mut Env<'a>,
+                                instruction: &'a [u8],
+                                pid: EnvId)
+                                -> PassResult<'a> {
+        instruction_is!(instruction, SYSTEM_DEFINE);
+        if !self.db.as_ref().allow_define {
+            return Err(error_define_not_allowed!());
+        }
+        let name = stack_pop!(env);
+        let closure = stack_pop!(env);
+        match binparser::instruction(name) {
+            pumpkinscript::ParseResult::Done(_, _) => (),
+            _ => return Err(error_invalid_value!(name)),
+        }
+        // an empty definition is reserved for undefined procedures
+        if closure.len() == 0 {
+            return Err(error_invalid_value!(closure));
+        }
+        // definitions are written in a transaction of their own
+        if self.txns.get(&pid).map_or(false, |v| v.len() > 0) {
+            return Err(error_in_transaction!());
+        }
+        match self.reserved_write(storage::PROCEDURES_KEYSPACE, |txn, procedures| {
+            let mut access = txn.access();
+            let version = {
+                let mut cursor = try!(txn.cursor(procedures));
+                stream_version(&mut cursor, &access, name) + 1
+            };
+            try!(access.put(procedures, &stream_event_key(name, version)[..], closure, lmdb::put::Flags::empty()));
+            Ok(version)
+        }) {
+            None => {
+                try!(env.push(closure));
+                try!(env.push(name));
+                Err(Error::Reschedule)
+            },
+            Some(Ok(version)) => {
+                self.db.as_ref().procedures.invalidate();
+                let version = BigUint::from_u64(version).unwrap().to_bytes_be();
+                let slice = alloc_and_write!(version.as_slice(), env);
+                try!(env.push(slice));
+                Ok(())
+            },
+            Some(Err(err)) => Err(error_database!(err)),
+        }
+    }
+
+    #[inline]
+    pub fn handle_system_undefine(&mut self,
+                                  env: &mut Env<'a>,
+                                  instruction: &'a [u8],
+                                  pid: EnvId)
+                                  -> PassResult<'a> {
+        instruction_is!(instruction, SYSTEM_UNDEFINE);
+        if !self.db.as_ref().allow_define {
+            return Err(error_define_not_allowed!());
+        }
+        let name = stack_pop!(env);
+        if self.txns.get(&pid).map_or(false, |v| v.len() > 0) {
+            return Err(error_in_transaction!());
+        }
+        match self.reserved_write(storage::PROCEDURES_KEYSPACE, |txn, procedures| {
+            let mut access = txn.access();
+            let (version, defined) = {
+                let mut cursor = try!(txn.cursor(procedures));
+                let defined = try!(latest_procedure(&mut cursor, &access, name)).is_some();
+                (stream_version(&mut cursor, &access, name), defined)
+            };
+            if !defined {
+                return Ok(false);
+            }
+            // previous versions are kept, the new one is empty
+            try!(access.put(procedures, &stream_event_key(name, version + 1)[..], &b""[..],
+                            lmdb::put::Flags::empty()));
+            Ok(true)
+        }) {
+            None => {
+                try!(env.push(name));
+                Err(Error::Reschedule)
+            },
+            Some(Ok(true)) => {
+                self.db.as_ref().procedures.invalidate();
+                Ok(())
+            },
+            Some(Ok(false)) => Err(error_unknown_key!(name)),
+            Some(Err(err)) => Err(error_database!(err)),
+        }
+    }
+
+    /// Looks up a word that is not in the environment's dictionary
+    /// among the stored procedures
+    #[inline]
+    pub fn handle_procedure(&mut self,
+                            env: &mut Env<'a>,
+                            instruction: &'a [u8],
+                            _: EnvId)
+                            -> PassResult<'a> {
+        let definitions = match self.procedures() {
+            None => return Err(Error::Reschedule),
+            Some(Ok(definitions)) => definitions,
+            Some(Err(err)) => return Err(error_database!(err)),
+        };
+        let definition = match definitions.get(instruction) {
+            Some(definition) => definition,
+            None => return Err(Error::UnknownInstruction),
+        };
+        let slice = alloc_and_write!(definition.as_slice(), env);
+        // the definition is cached in the outermost dictionary, so the procedure
+        // stays the same for the rest of the environment's life
+        #[cfg(feature = "scoped_dictionary")]
+        env.dictionary[0].insert(instruction, slice);
+        #[cfg(not(feature = "scoped_dictionary"))]
+        env.dictionary.insert(instruction, slice);
+        env.program.push(slice);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(unused_variables, unused_must_use, unused_mut, unused_imports)]
+mod tests {
+    use pumpkinscript::{parse, offset_by_size};
     use messaging;
     use nvmem::{MmapedFile, MmapedRegion, NonVolatileMemory};
     use script::{Env, Scheduler, Error, RequestMessage, ResponseMessage, EnvId, dispatcher};
@@ -583,6 +3012,7 @@ mod tests {
     use crossbeam;
     use script::binparser;
     use storage;
+    use encryption;
     use timestamp;
     use rand::Rng;
 
@@ -614,6 +3044,654 @@ mod tests {
         });
     }
 
+    #[test]
+    fn nested_write() {
+        // a failure within a nested WRITE only rolls back the nested transaction
+        eval!("[\"outer\" \"1\" ASSOC [[\"inner\" \"1\" ASSOC \"inner\" \"2\" ASSOC] WRITE] TRY COMMIT] WRITE \
+               [\"outer\" ASSOC? \"inner\" ASSOC?] READ",
+              env,
+              result,
+              {
+                  assert!(!result.is_err());
+                  assert_eq!(Vec::from(env.pop().unwrap()), parsed_data!("0x00"));
+                  assert_eq!(Vec::from(env.pop().unwrap()), parsed_data!("0x01"));
+                  assert_eq!(Vec::from(env.pop().unwrap()), parsed_data!("[\"Duplicate key\" [\"inner\"] 6]"));
+                  assert_eq!(env.pop(), None);
+              });
+        // uncommitted nested transactions are rolled back when they end
+        eval!("[[\"key\" \"value\" ASSOC] WRITE \"key\" ASSOC?] WRITE", env, result, {
+            assert!(!result.is_err());
+            assert_eq!(Vec::from(env.pop().unwrap()), parsed_data!("0x00"));
+            assert_eq!(env.pop(), None);
+        });
+        // cursors of the outer transaction can't be used within the nested one
+        eval!("[\"key\" \"value\" ASSOC CURSOR [CURSOR/FIRST] WRITE] WRITE", env, result, {
+            assert!(result.is_err());
+            if let Err(Error::ProgramError(error)) = result {
+                assert!(error.ends_with(b"\x03"));
+            }
+        });
+    }
+
+    #[test]
+    fn stats() {
+        eval!("$SYSTEM/DBSTATS", env, result, {
+            assert_error!(result, "[\"No transaction\" [] 8]");
+        });
+        eval!("[[$SYSTEM/DBSTATS] READ] \"nonexistent\" KEYSPACE \"entries\" JSON/GET", env, result, {
+            assert!(!result.is_err());
+            assert_eq!(Vec::from(env.pop().unwrap()), parsed_data!("\"0\""));
+            assert_eq!(env.pop(), None);
+        });
+        eval!("[[[$SYSTEM/DBSTATS] \"nonexistent\" KEYSPACE] READ] TRY UNWRAP", env, result, {
+            assert!(!result.is_err());
+            assert_eq!(Vec::from(env.pop().unwrap()), parsed_data!("0x11"));
+            assert_eq!(Vec::from(env.pop().unwrap()), parsed_data!("[\"nonexistent\"]"));
+            assert_eq!(Vec::from(env.pop().unwrap()), parsed_data!("\"Unknown keyspace\""));
+            assert_eq!(env.pop(), None);
+        });
+    }
+
+    #[test]
+    fn cursor_range() {
+        eval!("[\"\" \"\" 0x10 0 CURSOR/RANGE] READ", env, result, {
+            assert_error!(result, "[\"Invalid value\" [0x10] 3]");
+        });
+        eval!("[\"\" \"\" 0x0001 0 CURSOR/RANGE] READ", env, result, {
+            assert_error!(result, "[\"Invalid value\" [0x0001] 3]");
+        });
+        eval!("[\"\" \"\" 0 0x010000000000000000 CURSOR/RANGE] READ", env, result, {
+            assert_error!(result, "[\"Invalid value\" [0x010000000000000000] 3]");
+        });
+        eval!("\"\" \"\" 0 0 CURSOR/RANGE", env, result, {
+            assert_error!(result, "[\"No transaction\" [] 8]");
+        });
+        // the limit counts keys within the range
+        eval!("[\"a\" 1 ASSOC \"b\" 2 ASSOC \"c\" 3 ASSOC COMMIT] WRITE \
+               [\"a\" \"\" 0x01 1 [CURSOR/VAL TRUE] CURSOR/DOWHILE-RANGE] READ",
+              env,
+              result,
+              {
+                  assert!(!result.is_err());
+                  assert_eq!(Vec::from(env.pop().unwrap()), parsed_data!("2"));
+                  assert_eq!(env.pop(), None);
+              });
+    }
+
+    #[test]
+    fn index_errors() {
+        eval!("[\"i\" \"\" [DROP DROP] INDEX/DEFINE] WRITE", env, result, {
+            assert_error!(result, "[\"Can't be used within a transaction\" [] 3]");
+        });
+        eval!("\"i\" \"\" [DROP DROP] INDEX/DEFINE \"i\" \"\" [DROP DROP] INDEX/DEFINE", env, result, {
+            assert_error!(result, "[\"Duplicate key\" [\"i\"] 6]");
+        });
+        eval!("\"i\" INDEX/DROP", env, result, {
+            assert_error!(result, "[\"Unknown key\" [\"i\"] 7]");
+        });
+        eval!("[\"i\" INDEX/BACKFILL] WRITE", env, result, {
+            assert_error!(result, "[\"Unknown key\" [\"i\"] 7]");
+        });
+        eval!("[\"i\" \"x\" INDEX/CURSOR] READ", env, result, {
+            assert_error!(result, "[\"Unknown key\" [\"i\"] 7]");
+        });
+        // indexes are defined per keyspace
+        eval!("[\"i\" \"\" [DROP DROP] INDEX/DEFINE] \"test\" KEYSPACE [\"i\" \"x\" INDEX/CURSOR] READ",
+              env,
+              result,
+              {
+                  assert_error!(result, "[\"Unknown key\" [\"i\"] 7]");
+              });
+    }
+
+    #[test]
+    fn stream_errors() {
+        eval!("[\"s\" \"e1\" 0 STREAM/APPEND DROP \"s\" \"e2\" 0 STREAM/APPEND] WRITE", env, result, {
+            assert_error!(result, "[\"Wrong expected version\" [1] 13]");
+        });
+        eval!("[\"\" \"e1\" 0 STREAM/APPEND] WRITE", env, result, {
+            assert_error!(result, "[\"Invalid value\" [] 3]");
+        });
+        eval!("[\"s\" \"e1\" 0x010000000000000000 STREAM/APPEND] WRITE", env, result, {
+            assert_error!(result, "[\"Invalid value\" [0x010000000000000000] 3]");
+        });
+        eval!("[\"s\" \"e1\" 0 STREAM/APPEND] READ", env, result, {
+            assert_error!(result, "[\"No transaction\" [] 8]");
+        });
+        eval!("[\"s\" 0 2 STREAM/READ] READ", env, result, {
+            assert_error!(result, "[\"Invalid value\" [2] 3]");
+        });
+        eval!("\"s\" STREAM/VERSION", env, result, {
+            assert_error!(result, "[\"No transaction\" [] 8]");
+        });
+        // a conflict doesn't affect events appended before it
+        eval!("[\"s\" \"e1\" 0 STREAM/APPEND DROP [\"s\" \"e2\" 0 STREAM/APPEND] TRY DROP COMMIT] WRITE \
+               [\"s\" STREAM/VERSION] READ",
+              env,
+              result,
+              {
+                  assert!(!result.is_err());
+                  assert_eq!(Vec::from(env.pop().unwrap()), parsed_data!("1"));
+                  assert_eq!(env.pop(), None);
+              });
+    }
+
+    #[test]
+    fn as_of_errors() {
+        eval!("[] \"short\" ASOF", env, result, {
+            assert_error!(result, "[\"Invalid value\" [\"short\"] 3]");
+        });
+        eval!("[CURSOR] HLC ASOF", env, result, {
+            assert_error!(result, "[\"No transaction\" [] 8]");
+        });
+        // the scope ends even if its code fails
+        eval!("[\"k\" 1 ASSOC COMMIT] WRITE [[[\"x\" RETR] READ] HLC ASOF] TRY DROP \
+               [CURSOR DUP \"k\" CURSOR/SEEK DROP CURSOR/VAL] READ",
+              env,
+              result,
+              {
+                  assert!(!result.is_err());
+                  assert_eq!(Vec::from(env.pop().unwrap()), parsed_data!("1"));
+                  assert_eq!(env.pop(), None);
+              });
+    }
+
+    #[test]
+    fn group_commit() {
+        use script::SchedulerHandle;
+        let dir = TempDir::new("pumpkindb").unwrap();
+        let path = dir.path().to_str().unwrap();
+        fs::create_dir_all(path).expect("can't create directory");
+        let env = unsafe {
+            let mut builder = lmdb::EnvBuilder::new().expect("can't create env builder");
+            builder.set_maxdbs(storage::DEFAULT_MAXDBS).expect("can't set maxdbs");
+            builder.open(path, lmdb::open::NOTLS, 0o600).expect("can't open env")
+        };
+        let mut db = storage::Storage::new(&env);
+        db.group_commit = Some(10);
+        let db = Arc::new(db);
+        crossbeam::scope(|scope| {
+            let mut nvmem = MmapedFile::new_anonymous(20).unwrap();
+            let region = nvmem.claim(20).unwrap();
+            let timestamp = Arc::new(timestamp::Timestamp::new(region));
+            let mut simple = messaging::Simple::new();
+            let messaging_accessor = simple.accessor();
+            let publisher_thread = scope.spawn(move || simple.run());
+            let (mut scheduler, sender) = Scheduler::new(
+                dispatcher::StandardDispatcher::new(db.clone(), messaging_accessor.clone(),
+                                                    messaging_accessor.clone(), timestamp.clone()));
+            let handle = scope.spawn(move || scheduler.run());
+            let (callback, receiver) = mpsc::channel::<ResponseMessage>();
+            let scripts = ["[\"a\" \"1\" ASSOC COMMIT] WRITE",
+                           "[\"b\" \"2\" ASSOC COMMIT] WRITE",
+                           "[\"c\" \"3\" ASSOC \"c\" \"3\" ASSOC COMMIT] WRITE",
+                           "[\"d\" \"4\" ASSOC] WRITE"];
+            for script in scripts.iter() {
+                let (msg_sender, _) = mpsc::channel::<(Vec<u8>, Vec<u8>)>();
+                sender.schedule_env(EnvId::new(), parse(script).unwrap(), callback.clone(), Box::new(msg_sender));
+            }
+            let mut terminated = 0;
+            let mut failed = 0;
+            for _ in 0..scripts.len() {
+                match receiver.recv().unwrap() {
+                    ResponseMessage::EnvTerminated(_, _, _, _) => terminated += 1,
+                    ResponseMessage::EnvFailed(_, _, _, _, _) => failed += 1,
+                }
+            }
+            assert_eq!(terminated, 3);
+            assert_eq!(failed, 1);
+
+            let (msg_sender, _) = mpsc::channel::<(Vec<u8>, Vec<u8>)>();
+            sender.schedule_env(EnvId::new(), parse("[\"a\" ASSOC? \"b\" ASSOC? \"c\" ASSOC? \"d\" ASSOC?] READ").unwrap(),
+                                callback.clone(), Box::new(msg_sender));
+            match receiver.recv().unwrap() {
+                ResponseMessage::EnvTerminated(_, stack, _, _) => {
+                    assert_eq!(stack, vec![vec![1], vec![1], vec![0], vec![0]]);
+                },
+                ResponseMessage::EnvFailed(_, err, _, _, _) => panic!("unexpected error: {:?}", err),
+            }
+
+            sender.shutdown();
+            messaging_accessor.shutdown();
+            let _ = handle.join();
+            let _ = publisher_thread.join();
+        });
+    }
+
+    #[test]
+    fn backup() {
+        use script::SchedulerHandle;
+        let dir = TempDir::new("pumpkindb").unwrap();
+        let path = dir.path().to_str().unwrap();
+        fs::create_dir_all(path).expect("can't create directory");
+        let env = unsafe {
+            let mut builder = lmdb::EnvBuilder::new().expect("can't create env builder");
+            builder.set_maxdbs(storage::DEFAULT_MAXDBS).expect("can't set maxdbs");
+            builder.open(path, lmdb::open::NOTLS, 0o600).expect("can't open env")
+        };
+        let backup_dir = TempDir::new("pumpkindb").unwrap();
+        let mut db = storage::Storage::new(&env);
+        db.backup_dir = Some(backup_dir.path().to_path_buf());
+        let db = Arc::new(db);
+        crossbeam::scope(|scope| {
+            let mut nvmem = MmapedFile::new_anonymous(20).unwrap();
+            let region = nvmem.claim(20).unwrap();
+            let timestamp = Arc::new(timestamp::Timestamp::new(region));
+            let mut simple = messaging::Simple::new();
+            let messaging_accessor = simple.accessor();
+            let publisher_thread = scope.spawn(move || simple.run());
+            let (mut scheduler, sender) = Scheduler::new(
+                dispatcher::StandardDispatcher::new(db.clone(), messaging_accessor.clone(),
+                                                    messaging_accessor.clone(), timestamp.clone()));
+            let handle = scope.spawn(move || scheduler.run());
+            let (callback, receiver) = mpsc::channel::<ResponseMessage>();
+            let run = |script: &str| {
+                let (msg_sender, _) = mpsc::channel::<(Vec<u8>, Vec<u8>)>();
+                sender.schedule_env(EnvId::new(), parse(script).unwrap(), callback.clone(), Box::new(msg_sender));
+                receiver.recv().unwrap()
+            };
+
+            match run("[\"key\" \"value\" ASSOC COMMIT] WRITE \"daily\" 0 $SYSTEM/BACKUP") {
+                ResponseMessage::EnvTerminated(_, _, _, _) => (),
+                ResponseMessage::EnvFailed(_, err, _, _, _) => panic!("unexpected error: {:?}", err),
+            }
+            assert!(backup_dir.path().join("daily").join("data.mdb").exists());
+            // backups are only written within the backup directory
+            for script in ["\"/tmp/daily\" 0 $SYSTEM/BACKUP", "\"../daily\" 0 $SYSTEM/BACKUP",
+                           "\"daily/../../daily\" 0 $SYSTEM/BACKUP"].iter() {
+                match run(script) {
+                    ResponseMessage::EnvFailed(_, Error::ProgramError(err), _, _, _) => assert!(err.ends_with(b"\x03")),
+                    other => panic!("unexpected result: {:?}", other),
+                }
+            }
+            // an existing backup can't be overwritten
+            match run("\"daily\" 0 $SYSTEM/BACKUP") {
+                ResponseMessage::EnvFailed(_, Error::ProgramError(err), _, _, _) => assert!(err.ends_with(b"\x09")),
+                other => panic!("unexpected result: {:?}", other),
+            }
+
+            sender.shutdown();
+            messaging_accessor.shutdown();
+            let _ = handle.join();
+            let _ = publisher_thread.join();
+        });
+    }
+
+    #[test]
+    fn cancel_write() {
+        use script::SchedulerHandle;
+        use script::ERROR_CANCELLED;
+        let dir = TempDir::new("pumpkindb").unwrap();
+        let path = dir.path().to_str().unwrap();
+        fs::create_dir_all(path).expect("can't create directory");
+        let env = unsafe {
+            let mut builder = lmdb::EnvBuilder::new().expect("can't create env builder");
+            builder.set_maxdbs(storage::DEFAULT_MAXDBS).expect("can't set maxdbs");
+            builder.open(path, lmdb::open::NOTLS, 0o600).expect("can't open env")
+        };
+        let db = Arc::new(storage::Storage::new(&env));
+        crossbeam::scope(|scope| {
+            let mut nvmem = MmapedFile::new_anonymous(20).unwrap();
+            let region = nvmem.claim(20).unwrap();
+            let timestamp = Arc::new(timestamp::Timestamp::new(region));
+            let mut simple = messaging::Simple::new();
+            let messaging_accessor = simple.accessor();
+            let publisher_thread = scope.spawn(move || simple.run());
+            let (mut scheduler, sender) = Scheduler::new(
+                dispatcher::StandardDispatcher::new(db.clone(), messaging_accessor.clone(),
+                                                    messaging_accessor.clone(), timestamp.clone()));
+            let handle = scope.spawn(move || scheduler.run());
+            let (callback, receiver) = mpsc::channel::<ResponseMessage>();
+
+            let id = EnvId::new();
+            let (msg_sender, _) = mpsc::channel::<(Vec<u8>, Vec<u8>)>();
+            sender.schedule_env(id, parse("[\"a\" \"1\" ASSOC [1] DOWHILE] WRITE").unwrap(),
+                                callback.clone(), Box::new(msg_sender));
+            sender.cancel(id);
+            match receiver.recv().unwrap() {
+                ResponseMessage::EnvTerminated(_, _, _, _) => panic!("not cancelled"),
+                ResponseMessage::EnvFailed(_, Error::ProgramError(err), _, _, _) =>
+                    assert!(err.ends_with(ERROR_CANCELLED)),
+                ResponseMessage::EnvFailed(_, err, _, _, _) => panic!("unexpected error: {:?}", err),
+            }
+
+            // the write lock is released and nothing is committed
+            let (msg_sender, _) = mpsc::channel::<(Vec<u8>, Vec<u8>)>();
+            sender.schedule_env(EnvId::new(), parse("[\"b\" \"2\" ASSOC COMMIT] WRITE [\"a\" ASSOC? \"b\" ASSOC?] READ").unwrap(),
+                                callback.clone(), Box::new(msg_sender));
+            match receiver.recv().unwrap() {
+                ResponseMessage::EnvTerminated(_, stack, _, _) => {
+                    assert_eq!(stack, vec![vec![0], vec![1]]);
+                },
+                ResponseMessage::EnvFailed(_, err, _, _, _) => panic!("unexpected error: {:?}", err),
+            }
+
+            sender.shutdown();
+            messaging_accessor.shutdown();
+            let _ = handle.join();
+            let _ = publisher_thread.join();
+        });
+    }
+
+    #[test]
+    fn keyspace_during_write() {
+        use script::SchedulerHandle;
+        let dir = TempDir::new("pumpkindb").unwrap();
+        let path = dir.path().to_str().unwrap();
+        fs::create_dir_all(path).expect("can't create directory");
+        let env = unsafe {
+            let mut builder = lmdb::EnvBuilder::new().expect("can't create env builder");
+            builder.set_maxdbs(storage::DEFAULT_MAXDBS).expect("can't set maxdbs");
+            builder.open(path, lmdb::open::NOTLS, 0o600).expect("can't open env")
+        };
+        let db = Arc::new(storage::Storage::new(&env));
+        crossbeam::scope(|scope| {
+            let mut nvmem = MmapedFile::new_anonymous(20).unwrap();
+            let region = nvmem.claim(20).unwrap();
+            let timestamp = Arc::new(timestamp::Timestamp::new(region));
+            let mut simple = messaging::Simple::new();
+            let messaging_accessor = simple.accessor();
+            let publisher_thread = scope.spawn(move || simple.run());
+            let (mut scheduler_a, sender_a) = Scheduler::new(
+                dispatcher::StandardDispatcher::new(db.clone(), messaging_accessor.clone(),
+                                                    messaging_accessor.clone(), timestamp.clone()));
+            let (mut scheduler_b, sender_b) = Scheduler::new(
+                dispatcher::StandardDispatcher::new(db.clone(), messaging_accessor.clone(),
+                                                    messaging_accessor.clone(), timestamp.clone()));
+            let handle_a = scope.spawn(move || scheduler_a.run());
+            let handle_b = scope.spawn(move || scheduler_b.run());
+            let (callback, receiver) = mpsc::channel::<ResponseMessage>();
+
+            let (msg_sender, _) = mpsc::channel::<(Vec<u8>, Vec<u8>)>();
+            sender_a.schedule_env(EnvId::new(), parse("[\"a\" \"1\" ASSOC [HLC DROP] 10000 TIMES COMMIT] WRITE").unwrap(),
+                                  callback.clone(), Box::new(msg_sender));
+            // a new keyspace is created once the running WRITE is done
+            let (msg_sender, _) = mpsc::channel::<(Vec<u8>, Vec<u8>)>();
+            sender_b.schedule_env(EnvId::new(), parse("[[\"k\" \"v\" ASSOC COMMIT] WRITE] \"fresh\" KEYSPACE").unwrap(),
+                                  callback.clone(), Box::new(msg_sender));
+            // but it can't be opened within a WRITE
+            let (msg_sender, _) = mpsc::channel::<(Vec<u8>, Vec<u8>)>();
+            sender_b.schedule_env(EnvId::new(), parse("[[[\"k\" ASSOC?] \"other\" KEYSPACE] WRITE] TRY UNWRAP").unwrap(),
+                                  callback.clone(), Box::new(msg_sender));
+            let mut stacks = Vec::new();
+            for _ in 0..3 {
+                match receiver.recv().unwrap() {
+                    ResponseMessage::EnvTerminated(_, stack, _, _) => stacks.push(stack),
+                    ResponseMessage::EnvFailed(_, err, _, _, _) => panic!("unexpected error: {:?}", err),
+                }
+            }
+            assert_eq!(stacks.iter().filter(|stack| stack.is_empty()).count(), 2);
+            assert!(stacks.iter().any(|stack| stack.last() == Some(&vec![7])));
+
+            // once opened, the keyspace is available within a WRITE
+            let (msg_sender, _) = mpsc::channel::<(Vec<u8>, Vec<u8>)>();
+            sender_a.schedule_env(EnvId::new(), parse("[[\"k\" ASSOC?] \"fresh\" KEYSPACE] WRITE").unwrap(),
+                                  callback.clone(), Box::new(msg_sender));
+            match receiver.recv().unwrap() {
+                ResponseMessage::EnvTerminated(_, stack, _, _) => assert_eq!(stack, vec![vec![1]]),
+                ResponseMessage::EnvFailed(_, err, _, _, _) => panic!("unexpected error: {:?}", err),
+            }
+
+            sender_a.shutdown();
+            sender_b.shutdown();
+            messaging_accessor.shutdown();
+            let _ = handle_a.join();
+            let _ = handle_b.join();
+            let _ = publisher_thread.join();
+        });
+    }
+
+    #[test]
+    fn map_growth() {
+        use script::SchedulerHandle;
+        let dir = TempDir::new("pumpkindb").unwrap();
+        let path = dir.path().to_str().unwrap();
+        fs::create_dir_all(path).expect("can't create directory");
+        let env = unsafe {
+            let mut builder = lmdb::EnvBuilder::new().expect("can't create env builder");
+            builder.set_mapsize(1024 * 1024).expect("can't set mapsize");
+            builder.set_maxdbs(storage::DEFAULT_MAXDBS).expect("can't set maxdbs");
+            builder.open(path, lmdb::open::NOTLS, 0o600).expect("can't open env")
+        };
+        let mut db = storage::Storage::new(&env);
+        db.map_size_step = Some(4 * 1024 * 1024);
+        let db = Arc::new(db);
+        crossbeam::scope(|scope| {
+            let mut nvmem = MmapedFile::new_anonymous(20).unwrap();
+            let region = nvmem.claim(20).unwrap();
+            let timestamp = Arc::new(timestamp::Timestamp::new(region));
+            let mut simple = messaging::Simple::new();
+            let messaging_accessor = simple.accessor();
+            let publisher_thread = scope.spawn(move || simple.run());
+            let (mut scheduler, sender) = Scheduler::new(
+                dispatcher::StandardDispatcher::new(db.clone(), messaging_accessor.clone(),
+                                                    messaging_accessor.clone(), timestamp.clone()));
+            let handle = scope.spawn(move || scheduler.run());
+            let (callback, receiver) = mpsc::channel::<ResponseMessage>();
+            // doesn't fit into the initial map
+            let script = "1 [[HLC 0x00 1024 0 PAD ASSOC] 1024 TIMES \"done\" 1 ASSOC COMMIT] WRITE \
+                          [\"done\" ASSOC?] READ";
+            let (msg_sender, _) = mpsc::channel::<(Vec<u8>, Vec<u8>)>();
+            sender.schedule_env(EnvId::new(), parse(script).unwrap(), callback.clone(), Box::new(msg_sender));
+            match receiver.recv().unwrap() {
+                ResponseMessage::EnvTerminated(_, stack, _, _) => {
+                    // the stack is restored when the WRITE is retried
+                    assert_eq!(stack, vec![vec![1], vec![1]]);
+                },
+                ResponseMessage::EnvFailed(_, err, _, _, _) => panic!("unexpected error: {:?}", err),
+            }
+            assert!(db.map_resizes() > 0);
+
+            sender.shutdown();
+            messaging_accessor.shutdown();
+            let _ = handle.join();
+            let _ = publisher_thread.join();
+        });
+    }
+
+    #[test]
+    fn group_map_growth() {
+        use script::SchedulerHandle;
+        let dir = TempDir::new("pumpkindb").unwrap();
+        let path = dir.path().to_str().unwrap();
+        fs::create_dir_all(path).expect("can't create directory");
+        let env = unsafe {
+            let mut builder = lmdb::EnvBuilder::new().expect("can't create env builder");
+            builder.set_mapsize(1024 * 1024).expect("can't set mapsize");
+            builder.set_maxdbs(storage::DEFAULT_MAXDBS).expect("can't set maxdbs");
+            builder.open(path, lmdb::open::NOTLS, 0o600).expect("can't open env")
+        };
+        let mut db = storage::Storage::new(&env);
+        db.group_commit = Some(10);
+        db.map_size_step = Some(4 * 1024 * 1024);
+        let db = Arc::new(db);
+        crossbeam::scope(|scope| {
+            let mut nvmem = MmapedFile::new_anonymous(20).unwrap();
+            let region = nvmem.claim(20).unwrap();
+            let timestamp = Arc::new(timestamp::Timestamp::new(region));
+            let mut simple = messaging::Simple::new();
+            let messaging_accessor = simple.accessor();
+            let publisher_thread = scope.spawn(move || simple.run());
+            let (mut scheduler, sender) = Scheduler::new(
+                dispatcher::StandardDispatcher::new(db.clone(), messaging_accessor.clone(),
+                                                    messaging_accessor.clone(), timestamp.clone()));
+            let handle = scope.spawn(move || scheduler.run());
+            let (callback, receiver) = mpsc::channel::<ResponseMessage>();
+            // together, the WRITEs of the group don't fit into the initial map
+            for _ in 0..4 {
+                let (msg_sender, _) = mpsc::channel::<(Vec<u8>, Vec<u8>)>();
+                sender.schedule_env(EnvId::new(), parse("[[HLC 0x00 1024 0 PAD ASSOC] 384 TIMES COMMIT] WRITE").unwrap(),
+                                    callback.clone(), Box::new(msg_sender));
+            }
+            for _ in 0..4 {
+                match receiver.recv().unwrap() {
+                    ResponseMessage::EnvTerminated(_, _, _, _) => (),
+                    ResponseMessage::EnvFailed(_, err, _, _, _) => panic!("unexpected error: {:?}", err),
+                }
+            }
+            assert!(db.map_resizes() > 0);
+
+            sender.shutdown();
+            messaging_accessor.shutdown();
+            let _ = handle.join();
+            let _ = publisher_thread.join();
+        });
+    }
+
+    #[test]
+    fn cdc() {
+        use script::SchedulerHandle;
+        use messaging::Subscriber;
+        use pumpkinscript;
+        let dir = TempDir::new("pumpkindb").unwrap();
+        let path = dir.path().to_str().unwrap();
+        fs::create_dir_all(path).expect("can't create directory");
+        let env = unsafe {
+            let mut builder = lmdb::EnvBuilder::new().expect("can't create env builder");
+            builder.set_maxdbs(storage::DEFAULT_MAXDBS).expect("can't set maxdbs");
+            builder.open(path, lmdb::open::NOTLS, 0o600).expect("can't open env")
+        };
+        let mut db = storage::Storage::new(&env);
+        db.cdc_topic = Some(Vec::from("cdc"));
+        let mut encryption = encryption::Encryption::new(encryption::KeyStore::open(dir.path().join("keys")).unwrap());
+        encryption.prefixes = vec![Vec::from("user/")];
+        db.enable_encryption(encryption).unwrap();
+        let db = Arc::new(db);
+        crossbeam::scope(|scope| {
+            let mut nvmem = MmapedFile::new_anonymous(20).unwrap();
+            let region = nvmem.claim(20).unwrap();
+            let timestamp = Arc::new(timestamp::Timestamp::new(region));
+            let mut simple = messaging::Simple::new();
+            let messaging_accessor = simple.accessor();
+            let publisher_thread = scope.spawn(move || simple.run());
+            let (cdc_sender, cdc_receiver) = mpsc::channel::<(Vec<u8>, Vec<u8>)>();
+            messaging_accessor.subscribe(b"cdc", Box::new(cdc_sender));
+            let (mut scheduler, sender) = Scheduler::new(
+                dispatcher::StandardDispatcher::new(db.clone(), messaging_accessor.clone(),
+                                                    messaging_accessor.clone(), timestamp.clone()));
+            let handle = scope.spawn(move || scheduler.run());
+            let (callback, receiver) = mpsc::channel::<ResponseMessage>();
+            let scripts = ["[\"a\" \"1\" ASSOC COMMIT] WRITE",
+                           // not committed
+                           "[\"b\" \"2\" ASSOC] WRITE",
+                           // nested transactions are published along with their parent
+                           "[[\"c\" \"3\" ASSOC COMMIT] WRITE \"d\" \"4\" ASSOC COMMIT] WRITE",
+                           // parent is not committed
+                           "[[\"e\" \"5\" ASSOC COMMIT] WRITE] WRITE",
+                           // failed
+                           "[[\"f\" \"6\" ASSOC \"a\" \"1\" ASSOC COMMIT] WRITE] TRY",
+                           "[[\"g\" \"7\" ASSOC COMMIT] WRITE] \"ks\" KEYSPACE",
+                           // encrypted values are published encrypted
+                           "[\"user/1\" \"8\" ASSOC COMMIT] WRITE",
+                           "\"end\" \"cdc\" PUBLISH"];
+            for script in scripts.iter() {
+                let (msg_sender, _) = mpsc::channel::<(Vec<u8>, Vec<u8>)>();
+                sender.schedule_env(EnvId::new(), parse(script).unwrap(), callback.clone(), Box::new(msg_sender));
+                match receiver.recv().unwrap() {
+                    ResponseMessage::EnvTerminated(_, _, _, _) => (),
+                    ResponseMessage::EnvFailed(_, err, _, _, _) => panic!("unexpected error: {:?}", err),
+                }
+            }
+
+            let mut messages = Vec::new();
+            loop {
+                let (_, message) = cdc_receiver.recv().unwrap();
+                if message == b"end" {
+                    break;
+                }
+                let mut items = Vec::new();
+                let mut input = message.as_slice();
+                while input.len() > 0 {
+                    match binparser::data(input) {
+                        pumpkinscript::ParseResult::Done(rest, data) => {
+                            items.push(Vec::from(&data[offset_by_size(data.len())..]));
+                            input = rest;
+                        },
+                        _ => panic!("invalid message {:?}", message),
+                    }
+                }
+                messages.push(items);
+            }
+            assert_eq!(messages.len(), 4);
+            // transaction IDs follow the commit order
+            assert!(messages.windows(2).all(|pair| pair[0][0] < pair[1][0]));
+            let mut changes: Vec<Vec<Vec<u8>>> = messages.into_iter()
+                .map(|items| items.into_iter().skip(1).collect()).collect();
+            let encrypted = changes.pop().unwrap();
+            assert_eq!(changes, vec![
+                vec![Vec::from(""), Vec::from("a"), Vec::from("1")],
+                vec![Vec::from(""), Vec::from("c"), Vec::from("3"),
+                     Vec::from(""), Vec::from("d"), Vec::from("4")],
+                vec![Vec::from("ks"), Vec::from("g"), Vec::from("7")],
+            ]);
+            assert_eq!(encrypted[1], Vec::from("user/1"));
+            assert_eq!(encryption::stream(&encrypted[2]), Some(&b"user/1"[..]));
+
+            sender.shutdown();
+            messaging_accessor.shutdown();
+            let _ = handle.join();
+            let _ = publisher_thread.join();
+        });
+    }
+
+    #[test]
+    fn stored_procedures() {
+        use script::SchedulerHandle;
+        let dir = TempDir::new("pumpkindb").unwrap();
+        let path = dir.path().to_str().unwrap();
+        fs::create_dir_all(path).expect("can't create directory");
+        let env = unsafe {
+            let mut builder = lmdb::EnvBuilder::new().expect("can't create env builder");
+            builder.set_maxdbs(storage::DEFAULT_MAXDBS).expect("can't set maxdbs");
+            builder.open(path, lmdb::open::NOTLS, 0o600).expect("can't open env")
+        };
+        let mut storage = storage::Storage::new(&env);
+        storage.allow_define = true;
+        let db = Arc::new(storage);
+        crossbeam::scope(|scope| {
+            let mut nvmem = MmapedFile::new_anonymous(20).unwrap();
+            let region = nvmem.claim(20).unwrap();
+            let timestamp = Arc::new(timestamp::Timestamp::new(region));
+            let mut simple = messaging::Simple::new();
+            let messaging_accessor = simple.accessor();
+            let publisher_thread = scope.spawn(move || simple.run());
+            let (mut scheduler, sender) = Scheduler::new(
+                dispatcher::StandardDispatcher::new(db.clone(), messaging_accessor.clone(),
+                                                    messaging_accessor.clone(), timestamp.clone()));
+            let handle = scope.spawn(move || scheduler.run());
+            let (callback, receiver) = mpsc::channel::<ResponseMessage>();
+            let scripts = [("[1] 'ANSWER $SYSTEM/DEFINE", vec![vec![1]]),
+                           ("ANSWER", vec![vec![1]]),
+                           // the environment's own words come first
+                           ("[3] 'ANSWER DEF ANSWER", vec![vec![3]]),
+                           ("[5] 'ANSWER $SYSTEM/DEFINE ANSWER", vec![vec![2], vec![5]]),
+                           // new environments see the latest version
+                           ("ANSWER", vec![vec![5]]),
+                           ("'ANSWER $SYSTEM/UNDEFINE [ANSWER] TRY SOME?", vec![vec![1]])];
+            for &(script, ref expected) in scripts.iter() {
+                let (msg_sender, _) = mpsc::channel::<(Vec<u8>, Vec<u8>)>();
+                sender.schedule_env(EnvId::new(), parse(script).unwrap(), callback.clone(), Box::new(msg_sender));
+                match receiver.recv().unwrap() {
+                    ResponseMessage::EnvTerminated(_, stack, _, _) => assert_eq!(&stack, expected),
+                    ResponseMessage::EnvFailed(_, err, _, _, _) => panic!("unexpected error: {:?}", err),
+                }
+            }
+
+            let (msg_sender, _) = mpsc::channel::<(Vec<u8>, Vec<u8>)>();
+            sender.schedule_env(EnvId::new(), parse("'ANSWER $SYSTEM/UNDEFINE").unwrap(),
+                                callback.clone(), Box::new(msg_sender));
+            match receiver.recv().unwrap() {
+                ResponseMessage::EnvTerminated(_, _, _, _) => panic!("undefined twice"),
+                ResponseMessage::EnvFailed(_, _, _, _, _) => (),
+            }
+
+            sender.shutdown();
+            messaging_accessor.shutdown();
+            let _ = handle.join();
+            let _ = publisher_thread.join();
+        });
+    }
+
     use test::Bencher;
 
     #[bench]
@@ -630,6 +3708,7 @@ mod tests {
         let env = unsafe {
             let mut builder = lmdb::EnvBuilder::new().expect("can't create env builder");
             builder.set_mapsize(1024 * 1024 * 1024).expect("can't set mapsize");
+            builder.set_maxdbs(storage::DEFAULT_MAXDBS).expect("can't set maxdbs");
             builder.open(path, lmdb::open::NOTLS, 0o600).expect("can't open env")
         };
         let mut nvmem = MmapedFile::new_anonymous(20).unwrap();
@@ -39,6 +39,9 @@ pub struct Handler<'a> {
 }
 
 impl<'a> Dispatcher<'a> for Handler<'a> {
+    fn movable(&mut self, _: EnvId) -> bool {
+        true
+    }
     fn handle(&mut self, env: &mut Env<'a>, instruction: &'a [u8], pid: EnvId) -> PassResult<'a> {
         try_instruction!(env, self.handle_to_uint(env, instruction, pid));
         try_instruction!(env, self.handle_to_int(env, instruction, pid));
@@ -66,7 +69,7 @@ impl<'a> Handler<'a> {
         let a: BigUint = BigUint::from_str(&s).or(Err(error_invalid_value!(a_bytes)))?;
 
         let slice = alloc_and_write!(a.pack().as_slice(), env);
-        env.push(slice);
+        try!(env.push(slice));
 
         Ok(())
     }
@@ -83,7 +86,7 @@ impl<'a> Handler<'a> {
         let a: BigInt = BigInt::from_str(&s).or(Err(error_invalid_value!(a_bytes)))?;
 
         let slice = alloc_and_write!(a.pack().as_slice(), env);
-        env.push(slice);
+        try!(env.push(slice));
 
         Ok(())
     }
@@ -111,7 +114,7 @@ impl<'a> Handler<'a> {
         };        
 
         let slice = alloc_and_write!(a.as_slice(), env);
-        env.push(slice);
+        try!(env.push(slice));
 
         Ok(())
     }
@@ -0,0 +1,136 @@
+// Copyright (c) 2017, All Contributors (see CONTRIBUTORS file)
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//!
+//! # Tuples
+//!
+//! This module handles order-preserving encoding of tuples
+//! (see `pumpkinscript::tuple`), primarily for composite keys.
+//!
+//! Tuples are represented in PumpkinScript as closures of alternating
+//! type names and values, for example `["string" "user" "u64" 1u64]`.
+//!
+
+instruction!(TUPLE_PACK, b"\x8ATUPLE/PACK");
+instruction!(TUPLE_UNPACK, b"\x8CTUPLE/UNPACK");
+instruction!(TUPLE_RANGE, b"\x8BTUPLE/RANGE");
+
+use super::{Env, EnvId, Dispatcher, PassResult, Error, ERROR_EMPTY_STACK, ERROR_INVALID_VALUE,
+            offset_by_size};
+
+use pumpkinscript::{self, binparser, Encodable};
+use pumpkinscript::tuple::{self, Element};
+use std::marker::PhantomData;
+
+/// Decodes a closure of alternating type names and values into a tuple
+fn elements(closure: &[u8]) -> Option<Vec<Element>> {
+    let mut items = Vec::new();
+    let mut input = closure;
+    while input.len() > 0 {
+        match binparser::data(input) {
+            pumpkinscript::ParseResult::Done(rest, data) => {
+                items.push(&data[offset_by_size(data.len())..]);
+                input = rest;
+            },
+            _ => return None,
+        }
+    }
+    if items.len() % 2 != 0 {
+        return None
+    }
+    let mut elements = Vec::with_capacity(items.len() / 2);
+    for pair in items.chunks(2) {
+        match Element::from_value(pair[0], pair[1]) {
+            Some(element) => elements.push(element),
+            None => return None,
+        }
+    }
+    Some(elements)
+}
+
+pub struct Handler<'a> {
+    phantom: PhantomData<&'a ()>,
+}
+
+impl<'a> Dispatcher<'a> for Handler<'a> {
+    fn movable(&mut self, _: EnvId) -> bool {
+        true
+    }
+    fn handle(&mut self, env: &mut Env<'a>, instruction: &'a [u8], pid: EnvId) -> PassResult<'a> {
+        try_instruction!(env, self.handle_tuple_pack(env, instruction, pid));
+        try_instruction!(env, self.handle_tuple_unpack(env, instruction, pid));
+        try_instruction!(env, self.handle_tuple_range(env, instruction, pid));
+        Err(Error::UnknownInstruction)
+    }
+}
+
+impl<'a> Handler<'a> {
+    pub fn new() -> Self {
+        Handler { phantom: PhantomData }
+    }
+
+    #[inline]
+    pub fn handle_tuple_pack(&mut self,
+                             env: &mut Env<'a>,
+                             instruction: &'a [u8],
+                             _: EnvId)
+                             -> PassResult<'a> {
+        instruction_is!(instruction, TUPLE_PACK);
+        let closure = stack_pop!(env);
+        match elements(closure) {
+            Some(elements) => {
+                let packed = tuple::pack(&elements);
+                let slice = alloc_and_write!(packed.as_slice(), env);
+                try!(env.push(slice));
+                Ok(())
+            },
+            None => Err(error_invalid_value!(closure)),
+        }
+    }
+
+    #[inline]
+    pub fn handle_tuple_unpack(&mut self,
+                               env: &mut Env<'a>,
+                               instruction: &'a [u8],
+                               _: EnvId)
+                               -> PassResult<'a> {
+        instruction_is!(instruction, TUPLE_UNPACK);
+        let packed = stack_pop!(env);
+        match tuple::unpack(packed) {
+            Some(elements) => {
+                let mut closure = Vec::new();
+                for element in elements {
+                    closure.append(&mut element.type_name().encode());
+                    closure.append(&mut element.value().encode());
+                }
+                let slice = alloc_and_write!(closure.as_slice(), env);
+                try!(env.push(slice));
+                Ok(())
+            },
+            None => Err(error_invalid_value!(packed)),
+        }
+    }
+
+    #[inline]
+    pub fn handle_tuple_range(&mut self,
+                              env: &mut Env<'a>,
+                              instruction: &'a [u8],
+                              _: EnvId)
+                              -> PassResult<'a> {
+        instruction_is!(instruction, TUPLE_RANGE);
+        let closure = stack_pop!(env);
+        match elements(closure) {
+            Some(elements) => {
+                let (start, end) = tuple::prefix_range(&elements);
+                let start_slice = alloc_and_write!(start.as_slice(), env);
+                try!(env.push(start_slice));
+                let end_slice = alloc_and_write!(end.as_slice(), env);
+                try!(env.push(end_slice));
+                Ok(())
+            },
+            None => Err(error_invalid_value!(closure)),
+        }
+    }
+}
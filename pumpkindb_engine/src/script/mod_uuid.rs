@@ -22,6 +22,9 @@ pub struct Handler<'a> {
 }
 
 impl<'a> Dispatcher<'a> for Handler<'a> {
+    fn movable(&mut self, _: EnvId) -> bool {
+        true
+    }
     fn handle(&mut self, env: &mut Env<'a>, instruction: &'a [u8], pid: EnvId) -> PassResult<'a> {
         try_instruction!(env, self.handle_uuid_v4(env, instruction, pid));
         try_instruction!(env, self.handle_uuid_v5(env, instruction, pid));
@@ -46,7 +49,7 @@ impl<'a> Handler<'a> {
         let uuid = Uuid::new_v4();
         let mut slice = alloc_slice!(16, env);
         slice.copy_from_slice(uuid.as_bytes());
-        env.push(slice);
+        try!(env.push(slice));
         Ok(())
     }
 
@@ -64,7 +67,7 @@ impl<'a> Handler<'a> {
                 let uuid = Uuid::new_v5(&ns_uuid, name);
                 let mut slice = alloc_slice!(16, env);
                 slice.copy_from_slice(uuid.as_bytes());
-                env.push(slice);
+                try!(env.push(slice));
                 Ok(())
             } else {
                 Err(error_invalid_value!(ns_uuid_bytes))
@@ -88,7 +91,7 @@ impl<'a> Handler<'a> {
         if let Ok(uuid) = Uuid::from_bytes(top) {
             let str = uuid.hyphenated().to_string();
             let val = alloc_and_write!(str.as_bytes(), env);
-            env.push(val);
+            try!(env.push(val));
 
             Ok(())
         } else {
@@ -109,7 +112,7 @@ impl<'a> Handler<'a> {
         if let Ok(uuid_str) = str::from_utf8(top) {
             if let Ok(uuid) = Uuid::from_str(uuid_str) {
                 let bytes = alloc_and_write!(uuid.as_bytes(), env);
-                env.push(bytes);
+                try!(env.push(bytes));
 
                 Ok(())
             } else {
@@ -15,20 +15,255 @@ use alloc::heap;
 #[cfg(not(target_os = "windows"))]
 use core::mem::size_of;
 use lmdb;
+use lmdb::traits::LmdbResultExt;
+use compression;
+use encryption;
 
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use std::io;
+use std::fmt;
+use std::path::PathBuf;
+use std::error::Error as StdError;
+use snowflake::ProcessUniqueId;
+use core::ops::Deref;
 
-pub struct WriteTransactionContainer<'a>(Option<lmdb::WriteTransaction<'a>>, Arc<AtomicBool>);
+/// Identifies a writer waiting for the write lock
+pub type WriterId = ProcessUniqueId;
+
+struct WriteLockState {
+    locked: bool,
+    // Writer the lock has been handed over to, but who hasn't
+    // claimed it yet
+    granted: Option<WriterId>,
+    waiting: VecDeque<(WriterId, Box<Fn() + Send>)>,
+}
+
+/// Write lock with a FIFO queue of writers waiting for it
+///
+/// Once released, the lock is handed over directly to the first writer
+/// in the queue (which gets notified), so that writers acquire it in
+/// the order they started waiting for it.
+pub struct WriteLock(Mutex<WriteLockState>);
+
+impl WriteLock {
+    fn new() -> Self {
+        WriteLock(Mutex::new(WriteLockState {
+            locked: false,
+            granted: None,
+            waiting: VecDeque::new(),
+        }))
+    }
+
+    /// Acquires the lock if it is not taken and nobody is waiting for it
+    fn try_acquire(&self) -> bool {
+        let mut state = self.0.lock().unwrap();
+        if state.locked || !state.waiting.is_empty() {
+            false
+        } else {
+            state.locked = true;
+            true
+        }
+    }
+
+    /// Acquires the lock or puts the writer into the queue. `wake` will be called
+    /// once the lock has been handed over to the writer, after which it
+    /// should try to acquire it again.
+    fn acquire_or_enqueue(&self, writer: WriterId, wake: Box<Fn() + Send>) -> bool {
+        let mut state = self.0.lock().unwrap();
+        if state.granted == Some(writer) {
+            state.granted = None;
+            return true;
+        }
+        if !state.locked && state.waiting.is_empty() {
+            state.locked = true;
+            return true;
+        }
+        match state.waiting.iter().position(|&(id, _)| id == writer) {
+            Some(pos) => state.waiting[pos].1 = wake,
+            None => state.waiting.push_back((writer, wake)),
+        }
+        false
+    }
+
+    /// Removes the writer from the queue. If the lock has already been
+    /// handed over to it, it will be released.
+    fn cancel(&self, writer: WriterId) {
+        let mut state = self.0.lock().unwrap();
+        state.waiting.retain(|&(id, _)| id != writer);
+        if state.granted == Some(writer) {
+            state.granted = None;
+            WriteLock::hand_over(&mut state);
+        }
+    }
+
+    fn release(&self) {
+        let mut state = self.0.lock().unwrap();
+        WriteLock::hand_over(&mut state);
+    }
+
+    fn hand_over(state: &mut WriteLockState) {
+        match state.waiting.pop_front() {
+            Some((writer, wake)) => {
+                state.granted = Some(writer);
+                wake();
+            },
+            None => state.locked = false,
+        }
+    }
+}
+
+struct TransactionsState {
+    active: usize,
+    // Map growth has been requested
+    grow: bool,
+}
+
+/// Keeps track of active top-level transactions
+///
+/// LMDB only allows the map size to be changed when there are
+/// no active transactions in the process.
+pub struct Transactions(Mutex<TransactionsState>);
+
+impl Transactions {
+    fn new() -> Self {
+        Transactions(Mutex::new(TransactionsState {
+            active: 0,
+            grow: false,
+        }))
+    }
+
+    fn begin(&self) {
+        self.0.lock().unwrap().active += 1;
+    }
+
+    fn end(&self) {
+        self.0.lock().unwrap().active -= 1;
+    }
+}
+
+/// Latest definitions of stored procedures (by name), cached so that words
+/// that are neither instructions nor defined by scripts themselves don't have
+/// to be looked up in the database
+pub struct Procedures(Mutex<ProceduresState>);
+
+struct ProceduresState {
+    // Bumped whenever the definitions change
+    generation: u64,
+    definitions: Option<Arc<HashMap<Vec<u8>, Vec<u8>>>>,
+}
+
+impl Procedures {
+    fn new() -> Self {
+        Procedures(Mutex::new(ProceduresState {
+            generation: 0,
+            definitions: None,
+        }))
+    }
+
+    /// Returns the cached definitions or, if there are none,
+    /// the generation to [cache](#method.cache) them with
+    pub fn get(&self) -> Result<Arc<HashMap<Vec<u8>, Vec<u8>>>, u64> {
+        let state = self.0.lock().unwrap();
+        match state.definitions {
+            Some(ref definitions) => Ok(definitions.clone()),
+            None => Err(state.generation),
+        }
+    }
+
+    /// Caches definitions read by a transaction started after [`get`](#method.get)
+    /// has returned `generation`, unless they have been invalidated since
+    pub fn cache(&self, generation: u64, definitions: Arc<HashMap<Vec<u8>, Vec<u8>>>) {
+        let mut state = self.0.lock().unwrap();
+        if state.generation == generation {
+            state.definitions = Some(definitions);
+        }
+    }
+
+    /// Drops the cached definitions, should be called
+    /// once a change of definitions has been committed
+    pub fn invalidate(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.generation += 1;
+        state.definitions = None;
+    }
+}
+
+/// Read transaction that is accounted for in [`Transactions`](struct.Transactions.html)
+pub struct ReadTransactionContainer<'a>(Option<lmdb::ReadTransaction<'a>>, Arc<Transactions>);
+
+impl<'a> Deref for ReadTransactionContainer<'a> {
+    type Target = lmdb::ReadTransaction<'a>;
+
+    fn deref(&self) -> &lmdb::ReadTransaction<'a> {
+        match self.0 {
+            Some(ref txn) => txn,
+            None => panic!("no transaction available")
+        }
+    }
+}
+
+impl<'a> Drop for ReadTransactionContainer<'a> {
+    fn drop(&mut self) {
+        drop(self.0.take());
+        self.1.end();
+    }
+}
+
+impl<'a> ::std::fmt::Debug for ReadTransactionContainer<'a> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Write transaction along with its write lock
+///
+/// Nested (child) transactions don't hold the lock and aren't accounted
+/// for in [`Transactions`](struct.Transactions.html), only top-level
+/// transactions are.
+pub struct WriteTransactionContainer<'a>(Option<lmdb::WriteTransaction<'a>>, Option<Arc<WriteLock>>,
+                                         Option<Arc<Transactions>>);
 
-use core::ops::Deref;
 
 impl<'a> WriteTransactionContainer<'a> {
-    pub fn commit(mut self) -> Result<(), lmdb::Error> {
+    pub fn commit(self) -> Result<(), lmdb::Error> {
+        self.commit_then(|| ())
+    }
+
+    /// Commits the transaction and, if successful, calls `f` before
+    /// the write lock is released, so that calls made by subsequent
+    /// commits happen in the commit order
+    pub fn commit_then<F: FnOnce()>(mut self, f: F) -> Result<(), lmdb::Error> {
         let commit = ::std::mem::replace(&mut self.0, None).unwrap().commit();
-        self.1.compare_and_swap(true, false, Ordering::SeqCst);
+        if commit.is_ok() {
+            f();
+        }
+        if let Some(transactions) = self.2.take() {
+            transactions.end();
+        }
+        if let Some(lock) = self.1.take() {
+            lock.release();
+        }
         commit
     }
+
+    /// Starts a nested (child) write transaction
+    ///
+    /// Committing the nested transaction merges its changes into
+    /// this transaction, dropping it discards them.
+    ///
+    /// This is unsafe because the nested transaction is not bound
+    /// to the lifetime of this one: it is up to the caller to make sure
+    /// it is committed or dropped before this transaction is used or ended.
+    pub unsafe fn nested(&mut self) -> Result<WriteTransactionContainer<'a>, lmdb::Error> {
+        match self.0 {
+            Some(ref mut txn) =>
+                txn.child_tx().map(|txn| WriteTransactionContainer(Some(::std::mem::transmute(txn)), None, None)),
+            None => panic!("no transaction available")
+        }
+    }
 }
 
 impl<'a> Deref for WriteTransactionContainer<'a> {
@@ -44,7 +279,14 @@ impl<'a> Deref for WriteTransactionContainer<'a> {
 
 impl<'a> Drop for WriteTransactionContainer<'a> {
     fn drop(&mut self) {
-        self.1.compare_and_swap(true, false, Ordering::SeqCst);
+        // make sure the transaction is aborted before the lock is released
+        drop(self.0.take());
+        if let Some(transactions) = self.2.take() {
+            transactions.end();
+        }
+        if let Some(lock) = self.1.take() {
+            lock.release();
+        }
     }
 }
 
@@ -54,10 +296,61 @@ impl<'a> ::std::fmt::Debug for WriteTransactionContainer<'a> {
     }
 }
 
+/// Name of the keyspace reserved for secondary indexes
+/// (their definitions and entries)
+pub const INDEXES_KEYSPACE: &'static str = "$INDEXES";
+
+/// Name of the keyspace reserved for the names of all other keyspaces,
+/// which are opened upfront
+pub const KEYSPACES_KEYSPACE: &'static str = "$KEYSPACES";
+
+/// Name of the default keyspace. It is a named database, like any other
+/// keyspace, since LMDB keeps records of named databases in its main one.
+pub const DEFAULT_KEYSPACE: &'static str = "$DEFAULT";
+
+/// Name of the keyspace reserved for stored procedures
+/// (versioned definitions of words available to every environment)
+pub const PROCEDURES_KEYSPACE: &'static str = "$PROCEDURES";
+
+/// Name of the keyspace reserved for the identifiers of encrypted streams,
+/// recorded as their keys are created
+pub const STREAMS_KEYSPACE: &'static str = "$STREAMS";
+
 pub struct Storage<'a> {
-    pub db: lmdb::Database<'a>,
+    /// Default keyspace
+    pub db: Arc<lmdb::Database<'a>>,
     pub env: &'a lmdb::Environment,
-    pub write: Arc<AtomicBool>,
+    pub write: Arc<WriteLock>,
+    /// Maximum time a writer should wait for the write lock,
+    /// unlimited if `None`
+    pub max_write_wait: Option<Duration>,
+    /// Maximum number of WRITEs to be committed in one write transaction
+    /// (group commit), disabled if `None`. WRITEs are only grouped within
+    /// a scheduler, since an LMDB write transaction is bound to its thread.
+    pub group_commit: Option<usize>,
+    /// Number of bytes to grow the map by when it is full,
+    /// disabled if `None`
+    pub map_size_step: Option<usize>,
+    /// Topic to publish changes of committed write transactions to
+    /// (change data capture), disabled if `None`
+    pub cdc_topic: Option<Vec<u8>>,
+    /// Values to be compressed when they are written
+    /// (none by default)
+    pub compression: compression::Rules,
+    /// Encryption of values at rest with per-stream keys,
+    /// disabled if `None` (see [`enable_encryption`](#method.enable_encryption))
+    pub encryption: Option<encryption::Encryption>,
+    /// Directory `$SYSTEM/BACKUP` writes backups into,
+    /// the instruction is disabled if `None`
+    pub backup_dir: Option<PathBuf>,
+    /// Whether stored procedures can be defined (`$SYSTEM/DEFINE`)
+    /// and undefined (`$SYSTEM/UNDEFINE`), disabled by default
+    pub allow_define: bool,
+    /// Cached definitions of stored procedures
+    pub procedures: Procedures,
+    keyspaces: Mutex<HashMap<String, Arc<lmdb::Database<'a>>>>,
+    transactions: Arc<Transactions>,
+    map_resizes: AtomicUsize,
 }
 
 impl<'a> Storage<'a> {
@@ -65,37 +358,313 @@ impl<'a> Storage<'a> {
         if !env.flags().unwrap().contains(lmdb::open::NOTLS) {
             panic!("env should have NOTLS enabled");
         }
+        let db = Arc::new(default_keyspace(env).expect("can't open database"));
+        let mut keyspaces = HashMap::new();
+        keyspaces.insert(String::from(DEFAULT_KEYSPACE), db.clone());
+        // opening a keyspace requires a write transaction of its own, which can't be done
+        // within another write transaction, so known keyspaces are opened upfront
+        // (the registry itself is created once the first keyspace is opened)
+        if let Ok(registry) = lmdb::Database::open(env, Some(KEYSPACES_KEYSPACE), &lmdb::DatabaseOptions::defaults()) {
+            for name in keyspace_names(env, &registry).unwrap_or_else(|_| Vec::new()) {
+                if let Ok(db) = lmdb::Database::open(env, Some(&name), &lmdb::DatabaseOptions::defaults()) {
+                    keyspaces.insert(name, Arc::new(db));
+                }
+            }
+            keyspaces.insert(String::from(KEYSPACES_KEYSPACE), Arc::new(registry));
+        }
         Storage {
             env: env,
-            db: lmdb::Database::open(env, None, &lmdb::DatabaseOptions::new(lmdb::db::CREATE))
-                .expect("can't open database"),
-            write: Arc::new(AtomicBool::new(false)),
+            db: db,
+            write: Arc::new(WriteLock::new()),
+            max_write_wait: None,
+            group_commit: None,
+            map_size_step: None,
+            cdc_topic: None,
+            compression: compression::Rules::default(),
+            encryption: None,
+            backup_dir: None,
+            allow_define: false,
+            procedures: Procedures::new(),
+            keyspaces: Mutex::new(keyspaces),
+            transactions: Arc::new(Transactions::new()),
+            map_resizes: AtomicUsize::new(0),
         }
     }
 
+    /// Enables encryption of values at rest. Should be called before the storage
+    /// is used, since it creates the keyspace streams are recorded in (if necessary),
+    /// which can't be done within a write transaction later on.
+    pub fn enable_encryption(&mut self, encryption: encryption::Encryption) -> Result<(), lmdb::Error> {
+        let result = match self.keyspace(STREAMS_KEYSPACE, true) {
+            Some(result) => result,
+            None => panic!("encryption should be enabled before the storage is used"),
+        };
+        try!(result);
+        self.encryption = Some(encryption);
+        Ok(())
+    }
+
+    /// Returns a write transaction, or `None` if the write lock is taken
+    /// or there are writers waiting for it
     pub fn write(&self) -> Option<Result<WriteTransactionContainer<'a>, lmdb::Error>> {
-        match self.write.compare_and_swap(false, true, Ordering::SeqCst) {
-            false => {
-                match lmdb::WriteTransaction::new(self.env) {
-                    Ok(txn) => Some(Ok(WriteTransactionContainer(Some(txn), self.write.clone()))),
-                    Err(err) => Some(Err(err))
-                }
+        match self.write.try_acquire() {
+            true => Some(self.write_transaction()),
+            false => None
+        }
+    }
+
+    /// Returns a write transaction, or `None` if the writer had to be put into
+    /// the write lock queue. In the latter case, `wake` will be called once it's the
+    /// writer's turn, after which `write_or_enqueue` should be called again.
+    ///
+    /// A writer that no longer wants to wait should be removed from the queue
+    /// with [`cancel_write`](#method.cancel_write).
+    pub fn write_or_enqueue(&self, writer: WriterId, wake: Box<Fn() + Send>)
+                            -> Option<Result<WriteTransactionContainer<'a>, lmdb::Error>> {
+        match self.write.acquire_or_enqueue(writer, wake) {
+            true => Some(self.write_transaction()),
+            false => None
+        }
+    }
+
+    /// Removes the writer from the write lock queue
+    pub fn cancel_write(&self, writer: WriterId) {
+        self.write.cancel(writer);
+    }
+
+    fn write_transaction(&self) -> Result<WriteTransactionContainer<'a>, lmdb::Error> {
+        self.transactions.begin();
+        match lmdb::WriteTransaction::new(self.env) {
+            Ok(txn) => Ok(WriteTransactionContainer(Some(txn), Some(self.write.clone()),
+                                                    Some(self.transactions.clone()))),
+            Err(err) => {
+                self.transactions.end();
+                self.write.release();
+                Err(err)
+            }
+        }
+    }
+
+    /// Returns a named keyspace (LMDB named database), opening it if necessary.
+    ///
+    /// If the keyspace doesn't exist and `create` is set, it will be created. Since
+    /// opening a keyspace requires a write transaction of its own, `None` will be returned
+    /// if a write transaction is currently in progress or awaited, which includes the
+    /// caller's own write transaction.
+    ///
+    /// If the keyspace doesn't exist and `create` is not set, `lmdb::error::NOTFOUND`
+    /// will be returned.
+    pub fn keyspace(&self, name: &str, create: bool)
+                    -> Option<Result<Arc<lmdb::Database<'a>>, lmdb::Error>> {
+        if let Some(db) = self.opened_keyspace(name) {
+            return Some(Ok(db));
+        }
+        // opening a database begins a write transaction, which would have to wait
+        // for any other writer, so it is only done with the write lock held
+        // (and never with the keyspaces locked)
+        match self.write.try_acquire() {
+            true => {
+                let result = self.open_keyspace(name, create);
+                self.write.release();
+                Some(result)
             },
-            true => None
+            false => None
+        }
+    }
+
+    // Should only be called with the write lock held
+    fn open_keyspace(&self, name: &str, create: bool) -> Result<Arc<lmdb::Database<'a>>, lmdb::Error> {
+        // it might have been opened while the write lock was awaited
+        if let Some(db) = self.opened_keyspace(name) {
+            return Ok(db);
         }
+        self.transactions.begin();
+        let result = match lmdb::Database::open(self.env, Some(name), &lmdb::DatabaseOptions::defaults()) {
+            Err(lmdb::Error::Code(code)) if code == lmdb::error::NOTFOUND && create =>
+                lmdb::Database::open(self.env, Some(name), &lmdb::DatabaseOptions::new(lmdb::db::CREATE)),
+            result => result,
+        };
+        let result = result.and_then(|db| self.register_keyspace(name).map(|_| db));
+        self.transactions.end();
+        let db = Arc::new(try!(result));
+        self.keyspaces.lock().unwrap().insert(String::from(name), db.clone());
+        Ok(db)
+    }
+
+    // Records the name of the keyspace, so that it gets opened upfront next time.
+    // Should only be called with the write lock held.
+    fn register_keyspace(&self, name: &str) -> Result<(), lmdb::Error> {
+        let registry = match self.opened_keyspace(KEYSPACES_KEYSPACE) {
+            Some(registry) => registry,
+            None => {
+                let registry = Arc::new(try!(lmdb::Database::open(self.env, Some(KEYSPACES_KEYSPACE),
+                                                                  &lmdb::DatabaseOptions::new(lmdb::db::CREATE))));
+                self.keyspaces.lock().unwrap().insert(String::from(KEYSPACES_KEYSPACE), registry.clone());
+                registry
+            }
+        };
+        let txn = try!(lmdb::WriteTransaction::new(self.env));
+        let result = txn.access().put(&registry, name.as_bytes(), &b""[..], lmdb::put::NOOVERWRITE);
+        match result {
+            Ok(()) => txn.commit(),
+            Err(lmdb::Error::Code(code)) if code == lmdb::error::KEYEXIST => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Returns a named keyspace if it has already been opened
+    pub fn opened_keyspace(&self, name: &str) -> Option<Arc<lmdb::Database<'a>>> {
+        self.keyspaces.lock().unwrap().get(name).cloned()
     }
 
-    pub fn read(&self) -> Option<Result<lmdb::ReadTransaction<'a>, lmdb::Error>> {
+    pub fn read(&self) -> Option<Result<ReadTransactionContainer<'a>, lmdb::Error>> {
+        self.transactions.begin();
         match lmdb::ReadTransaction::new(self.env) {
-            Ok(txn) => Some(Ok(txn)),
+            Ok(txn) => Some(Ok(ReadTransactionContainer(Some(txn), self.transactions.clone()))),
             // MDB_READERS_FULL
-            Err(lmdb::Error::Code(-30790)) => None,
-            Err(err) => Some(Err(err))
+            Err(lmdb::Error::Code(-30790)) => {
+                self.transactions.end();
+                None
+            },
+            Err(err) => {
+                self.transactions.end();
+                Some(Err(err))
+            }
+        }
+    }
+
+    /// Requests the map to be grown by `map_size_step` as soon as there
+    /// are no active transactions. Returns `false` if map growth is disabled.
+    pub fn request_map_growth(&self) -> bool {
+        match self.map_size_step {
+            Some(_) => {
+                self.transactions.0.lock().unwrap().grow = true;
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Returns `true` if the map is about to be grown, in which case callers
+    /// that don't hold any transactions should not start new ones
+    /// until it has been done.
+    ///
+    /// If there are no active transactions, the map is grown right away.
+    pub fn map_growth_pending(&self) -> bool {
+        let mut state = self.transactions.0.lock().unwrap();
+        if !state.grow {
+            return false;
+        }
+        if state.active > 0 {
+            return true;
+        }
+        state.grow = false;
+        let step = self.map_size_step.unwrap_or(0);
+        // the state lock is held, so no transactions can be started while resizing
+        let result = self.env.info().and_then(|info| {
+            let size = info.mapsize + step;
+            unsafe { self.env.set_mapsize(size) }.map(|_| size)
+        });
+        match result {
+            Ok(size) => {
+                self.map_resizes.fetch_add(1, Ordering::SeqCst);
+                info!("Database map is full, map size grown to {}Mb", size / 1024 / 1024);
+            },
+            Err(err) => error!("Can't grow database map size: {}", err),
+        }
+        false
+    }
+
+    /// Returns the number of times the map has been grown
+    pub fn map_resizes(&self) -> usize {
+        self.map_resizes.load(Ordering::SeqCst)
+    }
+}
+
+// Names of keyspaces recorded in the registry
+fn keyspace_names(env: &lmdb::Environment, registry: &lmdb::Database) -> Result<Vec<String>, lmdb::Error> {
+    let txn = try!(lmdb::ReadTransaction::new(env));
+    let mut cursor = try!(txn.cursor(registry));
+    let access = txn.access();
+    let mut names = Vec::new();
+    let mut item = try!(cursor.first::<[u8], [u8]>(&access).to_opt());
+    while let Some((name, _)) = item {
+        names.push(String::from_utf8_lossy(name).into_owned());
+        item = try!(cursor.next::<[u8], [u8]>(&access).to_opt());
+    }
+    Ok(names)
+}
+
+// Number of pairs moved out of the main database per transaction
+const MIGRATION_BATCH_SIZE: usize = 1000;
+
+/// Opens the default keyspace, creating it if necessary
+///
+/// Pairs found in LMDB's main database (where the default keyspace used to be kept)
+/// are moved into it, leaving only the records of named databases behind.
+pub fn default_keyspace(env: &lmdb::Environment) -> Result<lmdb::Database, lmdb::Error> {
+    let db = try!(lmdb::Database::open(env, Some(DEFAULT_KEYSPACE), &lmdb::DatabaseOptions::new(lmdb::db::CREATE)));
+    let mut named: Vec<Vec<u8>> = [DEFAULT_KEYSPACE, KEYSPACES_KEYSPACE, INDEXES_KEYSPACE,
+                                   PROCEDURES_KEYSPACE, STREAMS_KEYSPACE].iter()
+        .map(|name| Vec::from(name.as_bytes())).collect();
+    {
+        let registry = lmdb::Database::open(env, Some(KEYSPACES_KEYSPACE), &lmdb::DatabaseOptions::defaults());
+        if let Ok(registry) = registry {
+            named.extend(try!(keyspace_names(env, &registry)).into_iter().map(|name| name.into_bytes()));
+        }
+    }
+    let main = try!(lmdb::Database::open(env, None, &lmdb::DatabaseOptions::defaults()));
+    loop {
+        let batch = {
+            let txn = try!(lmdb::ReadTransaction::new(env));
+            let mut cursor = try!(txn.cursor(&main));
+            let access = txn.access();
+            let mut batch = Vec::new();
+            let mut item = try!(cursor.first::<[u8], [u8]>(&access).to_opt());
+            while let Some((key, value)) = item {
+                if batch.len() == MIGRATION_BATCH_SIZE {
+                    break;
+                }
+                if !named.iter().any(|name| name.as_slice() == key) {
+                    batch.push((Vec::from(key), Vec::from(value)));
+                }
+                item = try!(cursor.next::<[u8], [u8]>(&access).to_opt());
+            }
+            batch
+        };
+        if batch.is_empty() {
+            return Ok(db);
+        }
+        let txn = try!(lmdb::WriteTransaction::new(env));
+        // a named database that isn't registered (created by `import`, for example)
+        // can only be recognized by failing to delete its record
+        let mut unregistered = None;
+        {
+            let mut access = txn.access();
+            for &(ref key, ref value) in batch.iter() {
+                match access.del_key(&main, &key[..]) {
+                    Ok(()) => try!(access.put(&db, &key[..], &value[..], lmdb::put::Flags::empty())),
+                    Err(lmdb::Error::Code(code)) if code == lmdb::error::INCOMPATIBLE => {
+                        unregistered = Some(key.clone());
+                        break;
+                    },
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        match unregistered {
+            // the transaction is aborted and the batch is collected again
+            Some(name) => named.push(name),
+            None => try!(txn.commit()),
         }
     }
 }
 
-pub fn create_environment(storage_path: String, map_size: Option<i64>, maxreaders: Option<u32>) -> lmdb::Environment {
+/// Default maximum number of keyspaces (LMDB named databases)
+pub const DEFAULT_MAXDBS: u32 = 128;
+
+pub fn create_environment(storage_path: String, map_size: Option<i64>, maxreaders: Option<u32>,
+                          maxdbs: Option<u32>) -> lmdb::Environment {
     unsafe {
         let mut env_builder = lmdb::EnvBuilder::new().expect("can't create env builder");
 
@@ -134,12 +703,72 @@ pub fn create_environment(storage_path: String, map_size: Option<i64>, maxreader
         if let Some(max) = maxreaders {
             let _ = env_builder.set_maxreaders(max);
         }
+        env_builder.set_maxdbs(maxdbs.unwrap_or(DEFAULT_MAXDBS)).expect("can't set maxdbs");
 
         env_builder.open(storage_path.as_str(), lmdb::open::NOTLS, 0o600)
             .expect("can't open env")
     }
 }
 
+/// Name of the file in the storage directory that holds
+/// non-volatile memory (such as the last HLC timestamp)
+pub const NVMEM_FILE: &'static str = "nvmem.dat";
+
+#[derive(Debug)]
+pub enum BackupError {
+    Storage(lmdb::Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &BackupError::Storage(ref err) => err.fmt(f),
+            &BackupError::Io(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl StdError for BackupError {
+    fn description(&self) -> &str {
+        match self {
+            &BackupError::Storage(ref err) => err.description(),
+            &BackupError::Io(ref err) => err.description(),
+        }
+    }
+}
+
+/// Copies a consistent snapshot of the environment into `target` directory,
+/// which will be created if necessary, optionally compacting it. Readers and
+/// writers can continue using the environment in the meantime.
+///
+/// Non-volatile memory file (if present) is copied after the snapshot was taken,
+/// so that the clock of a node restored from the backup won't go backwards
+/// relative to the timestamps in the snapshot.
+///
+/// The key store is not copied: keeping it apart from the backups is what makes
+/// shredding a stream erase its values from the backups too. A backup restored
+/// next to a key store that is missing some of its streams' keys is reported
+/// by [`fsck`](../fsck/index.html).
+pub fn backup(env: &lmdb::Environment, target: &str, compact: bool) -> Result<(), BackupError> {
+    try!(fs::create_dir_all(target).map_err(BackupError::Io));
+    let flags = if compact { lmdb::copy::COMPACT } else { lmdb::copy::Flags::empty() };
+    try!(env.copy(target, flags).map_err(BackupError::Storage));
+    let source = try!(env.path().map_err(BackupError::Storage));
+    let mut nvmem = PathBuf::from(source.to_string_lossy().into_owned());
+    nvmem.push(NVMEM_FILE);
+    if nvmem.exists() {
+        let mut target_nvmem = PathBuf::from(target);
+        target_nvmem.push(NVMEM_FILE);
+        let mut source_file = try!(fs::File::open(nvmem).map_err(BackupError::Io));
+        let mut target_file = try!(fs::OpenOptions::new().write(true).create_new(true)
+            .open(target_nvmem).map_err(BackupError::Io));
+        try!(io::copy(&mut source_file, &mut target_file).map_err(BackupError::Io));
+        try!(target_file.sync_all().map_err(BackupError::Io));
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 #[allow(unused_variables, unused_must_use, unused_mut)]
 mod tests {
@@ -157,10 +786,9 @@ mod tests {
         let path = dir.path().to_str().unwrap();
         fs::create_dir_all(path).expect("can't create directory");
         let env = unsafe {
-            lmdb::EnvBuilder::new()
-                .expect("can't create env builder")
-                .open(path, lmdb::open::NOTLS, 0o600)
-                .expect("can't open env")
+            let mut builder = lmdb::EnvBuilder::new().expect("can't create env builder");
+            builder.set_maxdbs(storage::DEFAULT_MAXDBS).expect("can't set maxdbs");
+            builder.open(path, lmdb::open::NOTLS, 0o600).expect("can't open env")
         };
         let maxreaders = env.maxreaders().unwrap();
 
@@ -198,10 +826,9 @@ mod tests {
         let path = dir.path().to_str().unwrap();
         fs::create_dir_all(path).expect("can't create directory");
         let env = unsafe {
-            lmdb::EnvBuilder::new()
-                .expect("can't create env builder")
-                .open(path, lmdb::open::NOTLS, 0o600)
-                .expect("can't open env")
+            let mut builder = lmdb::EnvBuilder::new().expect("can't create env builder");
+            builder.set_maxdbs(storage::DEFAULT_MAXDBS).expect("can't set maxdbs");
+            builder.open(path, lmdb::open::NOTLS, 0o600).expect("can't open env")
         };
 
         let storage = Arc::new(storage::Storage::new(&env));
@@ -270,4 +897,207 @@ mod tests {
         });
     }
 
+    #[test]
+    pub fn backup() {
+        use std::io::{Read, Write};
+        let dir = TempDir::new("pumpkindb").unwrap();
+        let path = dir.path().to_str().unwrap();
+        let env = unsafe {
+            let mut builder = lmdb::EnvBuilder::new().expect("can't create env builder");
+            builder.set_maxdbs(storage::DEFAULT_MAXDBS).expect("can't set maxdbs");
+            builder.open(path, lmdb::open::NOTLS, 0o600).expect("can't open env")
+        };
+        let mut nvmem = fs::File::create(dir.path().join(storage::NVMEM_FILE)).unwrap();
+        nvmem.write_all(&[1; 20]).unwrap();
+
+        let db = storage::Storage::new(&env);
+        let txn = db.write().unwrap().unwrap();
+        {
+            let mut access = txn.access();
+            access.put(&db.db, "key".as_bytes(), "value".as_bytes(), lmdb::put::Flags::empty()).unwrap();
+        }
+        txn.commit().unwrap();
+
+        let backup_dir = TempDir::new("pumpkindb").unwrap();
+        let backup_path = backup_dir.path().join("backup");
+        storage::backup(&env, backup_path.to_str().unwrap(), true).unwrap();
+        // a backup can't overwrite an existing one
+        assert!(storage::backup(&env, backup_path.to_str().unwrap(), true).is_err());
+
+        let backup_env = unsafe {
+            let mut builder = lmdb::EnvBuilder::new().expect("can't create env builder");
+            builder.set_maxdbs(storage::DEFAULT_MAXDBS).expect("can't set maxdbs");
+            builder.open(backup_path.to_str().unwrap(), lmdb::open::NOTLS, 0o600).expect("can't open env")
+        };
+        let backup_db = storage::Storage::new(&backup_env);
+        let txn = backup_db.read().unwrap().unwrap();
+        assert_eq!(txn.access().get::<[u8], [u8]>(&backup_db.db, "key".as_bytes()).unwrap(),
+                   "value".as_bytes());
+
+        let mut backup_nvmem = Vec::new();
+        fs::File::open(backup_path.join(storage::NVMEM_FILE)).unwrap()
+            .read_to_end(&mut backup_nvmem).unwrap();
+        assert_eq!(backup_nvmem, vec![1; 20]);
+    }
+
+    #[test]
+    pub fn write_queue() {
+        let dir = TempDir::new("pumpkindb").unwrap();
+        let path = dir.path().to_str().unwrap();
+        fs::create_dir_all(path).expect("can't create directory");
+        let env = unsafe {
+            let mut builder = lmdb::EnvBuilder::new().expect("can't create env builder");
+            builder.set_maxdbs(storage::DEFAULT_MAXDBS).expect("can't set maxdbs");
+            builder.open(path, lmdb::open::NOTLS, 0o600).expect("can't open env")
+        };
+
+        let storage = storage::Storage::new(&env);
+        let (sender, receiver) = mpsc::channel();
+
+        let w1 = storage.write();
+        assert!(w1.is_some());
+
+        let (writer2, writer3) = (storage::WriterId::new(), storage::WriterId::new());
+        let sender2 = sender.clone();
+        assert!(storage.write_or_enqueue(writer2, Box::new(move || { let _ = sender2.send(2); })).is_none());
+        let sender3 = sender.clone();
+        assert!(storage.write_or_enqueue(writer3, Box::new(move || { let _ = sender3.send(3); })).is_none());
+        // waiting writers can't be overtaken
+        assert!(storage.write().is_none());
+
+        drop(w1);
+        // the lock is handed over to the first writer in the queue
+        assert_eq!(receiver.try_recv().unwrap(), 2);
+        let sender3 = sender.clone();
+        assert!(storage.write_or_enqueue(writer3, Box::new(move || { let _ = sender3.send(3); })).is_none());
+        let sender2 = sender.clone();
+        let w2 = storage.write_or_enqueue(writer2, Box::new(move || { let _ = sender2.send(2); }));
+        assert!(w2.is_some());
+        assert!(receiver.try_recv().is_err());
+
+        drop(w2);
+        assert_eq!(receiver.try_recv().unwrap(), 3);
+        // cancelling releases the lock handed over to the writer
+        storage.cancel_write(writer3);
+        assert!(storage.write().is_some());
+    }
+
+    #[test]
+    pub fn keyspaces() {
+        let dir = TempDir::new("pumpkindb").unwrap();
+        let path = dir.path().to_str().unwrap();
+        let env = unsafe {
+            let mut builder = lmdb::EnvBuilder::new().expect("can't create env builder");
+            builder.set_maxdbs(storage::DEFAULT_MAXDBS).expect("can't set maxdbs");
+            builder.open(path, lmdb::open::NOTLS, 0o600).expect("can't open env")
+        };
+        {
+            let db = storage::Storage::new(&env);
+            // keyspaces can't be opened while a write transaction is in progress
+            let txn = db.write().unwrap().unwrap();
+            assert!(db.keyspace("test", true).is_none());
+            drop(txn);
+            assert!(db.keyspace("test", false).unwrap().is_err());
+            assert!(db.keyspace("test", true).unwrap().is_ok());
+            assert!(db.opened_keyspace("test").is_some());
+        }
+        // known keyspaces are opened upfront
+        let db = storage::Storage::new(&env);
+        assert!(db.opened_keyspace("test").is_some());
+        assert!(db.opened_keyspace("unknown").is_none());
+    }
+
+    #[test]
+    pub fn default_keyspace() {
+        let dir = TempDir::new("pumpkindb").unwrap();
+        let path = dir.path().to_str().unwrap();
+        let env = unsafe {
+            let mut builder = lmdb::EnvBuilder::new().expect("can't create env builder");
+            builder.set_maxdbs(storage::DEFAULT_MAXDBS).expect("can't set maxdbs");
+            builder.open(path, lmdb::open::NOTLS, 0o600).expect("can't open env")
+        };
+        // pairs kept in the main database by earlier versions
+        {
+            let main = lmdb::Database::open(&env, None, &lmdb::DatabaseOptions::defaults()).unwrap();
+            let unregistered = lmdb::Database::open(&env, Some("imported"),
+                                                    &lmdb::DatabaseOptions::new(lmdb::db::CREATE)).unwrap();
+            let txn = lmdb::WriteTransaction::new(&env).unwrap();
+            {
+                let mut access = txn.access();
+                access.put(&main, "a".as_bytes(), "1".as_bytes(), lmdb::put::Flags::empty()).unwrap();
+                access.put(&main, "z".as_bytes(), "2".as_bytes(), lmdb::put::Flags::empty()).unwrap();
+            }
+            txn.commit().unwrap();
+        }
+        {
+            let db = storage::Storage::new(&env);
+            assert!(db.keyspace("test", true).unwrap().is_ok());
+            let txn = db.read().unwrap().unwrap();
+            let access = txn.access();
+            assert_eq!(access.get::<[u8], [u8]>(&db.db, "a".as_bytes()).unwrap(), "1".as_bytes());
+            assert_eq!(access.get::<[u8], [u8]>(&db.db, "z".as_bytes()).unwrap(), "2".as_bytes());
+            // names of keyspaces are not visible in the default keyspace
+            let mut cursor = txn.cursor(&*db.db).unwrap();
+            assert_eq!(cursor.first::<[u8], [u8]>(&access).unwrap().0, "a".as_bytes());
+            assert_eq!(cursor.next::<[u8], [u8]>(&access).unwrap().0, "z".as_bytes());
+            assert!(cursor.next::<[u8], [u8]>(&access).is_err());
+        }
+        let db = storage::Storage::new(&env);
+        // and they can be used as keys
+        let txn = db.write().unwrap().unwrap();
+        {
+            let mut access = txn.access();
+            access.put(&db.db, "test".as_bytes(), "3".as_bytes(), lmdb::put::Flags::empty()).unwrap();
+            access.put(&db.db, "imported".as_bytes(), "4".as_bytes(), lmdb::put::Flags::empty()).unwrap();
+        }
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    pub fn map_growth() {
+        let dir = TempDir::new("pumpkindb").unwrap();
+        let path = dir.path().to_str().unwrap();
+        let env = unsafe {
+            let mut builder = lmdb::EnvBuilder::new().expect("can't create env builder");
+            builder.set_mapsize(1024 * 1024).expect("can't set mapsize");
+            builder.set_maxdbs(storage::DEFAULT_MAXDBS).expect("can't set maxdbs");
+            builder.open(path, lmdb::open::NOTLS, 0o600).expect("can't open env")
+        };
+        let mut db = storage::Storage::new(&env);
+
+        let fill = |db: &storage::Storage| -> Result<(), lmdb::Error> {
+            let txn = db.write().unwrap().unwrap();
+            {
+                let mut access = txn.access();
+                for i in 0..1024u32 {
+                    let key = format!("key{}", i);
+                    try!(access.put(&db.db, key.as_bytes(), &[0u8; 1024][..], lmdb::put::Flags::empty()));
+                }
+            }
+            txn.commit()
+        };
+
+        match fill(&db) {
+            Err(lmdb::Error::Code(code)) if code == lmdb::error::MAP_FULL => (),
+            other => panic!("expected MAP_FULL, got {:?}", other),
+        }
+
+        // growth is disabled by default
+        assert!(!db.request_map_growth());
+        assert!(!db.map_growth_pending());
+
+        db.map_size_step = Some(4 * 1024 * 1024);
+        assert!(db.request_map_growth());
+
+        // the map can't be grown while there are active transactions
+        let txn = db.read().unwrap().unwrap();
+        assert!(db.map_growth_pending());
+        assert_eq!(db.map_resizes(), 0);
+        drop(txn);
+
+        assert!(!db.map_growth_pending());
+        assert_eq!(db.map_resizes(), 1);
+        assert!(fill(&db).is_ok());
+    }
+
 }
\ No newline at end of file
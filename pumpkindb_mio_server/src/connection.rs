@@ -8,6 +8,7 @@ use std::io;
 use std::io::prelude::*;
 use std::io::{Error, ErrorKind};
 use std::rc::Rc;
+use std::collections::VecDeque;
 
 use byteorder::{ByteOrder, BigEndian};
 
@@ -26,8 +27,8 @@ pub struct Connection {
     // set of events we are interested in
     interest: Ready,
 
-    // messages waiting to be sent out
-    send_queue: Vec<Rc<Vec<u8>>>,
+    // messages waiting to be sent out (in the order they were queued)
+    send_queue: VecDeque<Rc<Vec<u8>>>,
 
     // track whether a connection needs to be (re)registered
     is_idle: bool,
@@ -49,7 +50,7 @@ impl Connection {
             sock: sock,
             token: token,
             interest: Ready::hup(),
-            send_queue: Vec::new(),
+            send_queue: VecDeque::new(),
             is_idle: true,
             is_reset: false,
             read_continuation: None,
@@ -150,12 +151,12 @@ impl Connection {
     pub fn writable(&mut self) -> io::Result<()> {
 
         self.send_queue
-            .pop()
+            .pop_front()
             .ok_or(Error::new(ErrorKind::Other, "Could not pop send queue"))
             .and_then(|buf| {
                 match self.write_message_length(&buf) {
                     Ok(None) => {
-                        self.send_queue.push(buf);
+                        self.send_queue.push_front(buf);
                         return Ok(());
                     }
                     Ok(Some(())) => {
@@ -173,7 +174,7 @@ impl Connection {
                     }
                     Err(e) => {
                         if e.kind() == ErrorKind::WouldBlock {
-                            self.send_queue.push(buf);
+                            self.send_queue.push_front(buf);
                             self.write_continuation = true;
                             Ok(())
                         } else {
@@ -212,7 +213,7 @@ impl Connection {
     }
 
     pub fn send_message(&mut self, message: Rc<Vec<u8>>) -> io::Result<()> {
-        self.send_queue.push(message);
+        self.send_queue.push_back(message);
 
         if !self.interest.is_writable() {
             self.interest.insert(Ready::writable());
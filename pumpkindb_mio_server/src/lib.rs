@@ -31,9 +31,10 @@ use mio::channel as mio_chan;
 use pumpkindb_engine::script;
 
 pub fn run(port: i64,
-           senders: Vec<script::Sender<script::RequestMessage>>,
+           senders: script::SchedulerPools<script::SchedulerSender>,
            relay_sender: mio_chan::Sender<server::RelayedPublishedMessage>,
-           relay_receiver: mio_chan::Receiver<server::RelayedPublishedMessage>) {
+           relay_receiver: mio_chan::Receiver<server::RelayedPublishedMessage>,
+           allow_high_priority: bool) {
     let addr = format!("0.0.0.0:{}", port).parse().unwrap();
 
     info!("Listening on {}", addr);
@@ -42,7 +43,8 @@ pub fn run(port: i64,
 
     let mut poll = Poll::new().expect("Failed to initialize polling");
 
-    let mut server = server::Server::new(sock, relay_sender, relay_receiver, senders);
+    let mut server = server::Server::new(sock, relay_sender, relay_receiver, senders,
+                                         allow_high_priority);
     server.run(&mut poll).expect("Failed to run server");
 
 }
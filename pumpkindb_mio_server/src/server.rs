@@ -7,9 +7,10 @@
 use std::io;
 use std::rc::Rc;
 use std::sync::mpsc;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 
 use slab;
+use byteorder::{ByteOrder, BigEndian};
 use mio::channel as mio_chan;
 use mio::*;
 use mio::tcp::*;
@@ -19,7 +20,8 @@ use super::connection::Connection;
 type Slab<T> = slab::Slab<T, Token>;
 
 use pumpkindb_engine::messaging;
-use pumpkindb_engine::script::{EnvId, Sender, RequestMessage, ResponseMessage, SchedulerHandle};
+use pumpkindb_engine::script::{EnvId, Sender, Receiver, SchedulerSender, SchedulerPools, ResponseMessage, SchedulerHandle,
+                                Priority};
 
 use uuid::Uuid;
 
@@ -43,9 +45,65 @@ impl messaging::PublishedMessageCallback for RelayedPublishedMessageSender  {
     }
 }
 
+// Scripts received over a connection are numbered sequentially,
+// starting from 1, so that the client can cancel them (`CANCEL`)
+#[derive(Default)]
+struct Requests {
+    counter: u64,
+    running: BTreeMap<u64, EnvId>,
+    // whether the client has sent frames (and gets frames back)
+    framed: bool,
+}
+
+// Messages that start with a tag PumpkinScript reserves (so that
+// they can't be mistaken for scripts) are frames, followed by their
+// kind (see doc/WIRE_PROTOCOL.md)
+const FRAME: u8 = 124;
+
+// Frames sent by clients
+const FRAME_REQUEST: u8 = 1;
+const FRAME_CANCEL: u8 = 2;
+const FRAME_PRIORITY: u8 = 3;
+
+// Frames sent to clients
+const FRAME_MESSAGE: u8 = 0;
+const FRAME_DONE: u8 = 1;
+const FRAME_ACCEPTED: u8 = 2;
+
+fn frame(kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(2 + payload.len());
+    frame.push(FRAME);
+    frame.push(kind);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+// `DONE` frame payload: request number, whether
+// the script has failed and its peak memory usage
+fn done(number: u64, failed: bool, memory: usize) -> [u8; 17] {
+    let mut payload = [0u8; 17];
+    BigEndian::write_u64(&mut payload[0..8], number);
+    payload[8] = if failed { 1 } else { 0 };
+    BigEndian::write_u64(&mut payload[9..17], memory as u64);
+    payload
+}
+
+// Priority class of a `PRIORITY` frame. High priority is granted
+// only if allowed, normal priority is used otherwise.
+fn class_priority(class: u8, allow_high: bool) -> Priority {
+    match class {
+        0 => Priority::Low,
+        2 if allow_high => Priority::High,
+        _ => Priority::Normal,
+    }
+}
+
 pub struct Server {
-    senders: Vec<Sender<RequestMessage>>,
+    senders: SchedulerPools<SchedulerSender>,
+    // whether clients can ask for high priority
+    allow_high_priority: bool,
     response_sender: Sender<ResponseMessage>,
+    response_receiver: Receiver<ResponseMessage>,
     relay_sender: mio_chan::Sender<RelayedPublishedMessage>,
     relay_receiver: mio_chan::Receiver<RelayedPublishedMessage>,
     sock: TcpListener,
@@ -53,6 +111,8 @@ pub struct Server {
     conns: Slab<Connection>,
     session_token: BTreeMap<Vec<u8>, Token>,
     token_session: BTreeMap<Token, Vec<u8>>,
+    requests: BTreeMap<Token, Requests>,
+    running: HashMap<EnvId, (Token, u64)>,
     events: Events,
 }
 
@@ -60,20 +120,25 @@ impl Server {
     pub fn new(sock: TcpListener,
                relay_sender: mio_chan::Sender<RelayedPublishedMessage>,
                relay_receiver: mio_chan::Receiver<RelayedPublishedMessage>,
-               senders: Vec<Sender<RequestMessage>>)
+               senders: SchedulerPools<SchedulerSender>,
+               allow_high_priority: bool)
                -> Server {
-        let (response_sender, _) = mpsc::channel();
+        let (response_sender, response_receiver) = mpsc::channel();
 
         Server {
             sock: sock,
             senders: senders,
+            allow_high_priority: allow_high_priority,
             response_sender: response_sender,
+            response_receiver: response_receiver,
             relay_sender: relay_sender,
             relay_receiver: relay_receiver,
             token: Token(10_000_000),
             conns: Slab::with_capacity(128),
             session_token: BTreeMap::new(),
             token_session: BTreeMap::new(),
+            requests: BTreeMap::new(),
+            running: HashMap::new(),
             events: Events::with_capacity(1024),
         }
     }
@@ -111,6 +176,30 @@ impl Server {
     }
 
     fn tick(&mut self, poll: &mut Poll) {
+        // forget requests that have finished, letting
+        // clients that use frames know about it
+        while let Ok(response) = self.response_receiver.try_recv() {
+            let (id, failed, memory) = match response {
+                ResponseMessage::EnvTerminated(id, _, _, memory) => (id, false, memory),
+                ResponseMessage::EnvFailed(id, _, _, _, memory) => (id, true, memory),
+            };
+            if let Some((token, number)) = self.running.remove(&id) {
+                let framed = match self.requests.get_mut(&token) {
+                    Some(requests) => {
+                        requests.running.remove(&number);
+                        requests.framed
+                    },
+                    None => false,
+                };
+                if framed {
+                    if let Some(conn) = self.conns.get_mut(token) {
+                        let _ = conn.send_message(Rc::new(frame(FRAME_DONE, &done(number, failed, memory))));
+                        conn.mark_idle();
+                    }
+                }
+            }
+        }
+
         let mut reset_tokens = Vec::new();
 
         for c in self.conns.iter_mut() {
@@ -130,6 +219,13 @@ impl Server {
             if let Some(session) = self.token_session.remove(&token) {
                 let _ = self.session_token.remove(&session);
             }
+            // nobody is going to receive the results anymore
+            if let Some(requests) = self.requests.remove(&token) {
+                for (_, id) in requests.running {
+                    self.running.remove(&id);
+                    self.senders.cancel(id);
+                }
+            }
         }
     }
 
@@ -144,6 +240,8 @@ impl Server {
                 Some(target) => target.clone(),
                 None => return
             };
+            let framed = self.requests.get(&target).map_or(false, |requests| requests.framed);
+            let msg = if framed { frame(FRAME_MESSAGE, &msg) } else { msg };
             let conn = self.find_connection_by_token(target);
             let _ = conn.send_message(Rc::new(msg));
             conn.mark_idle();
@@ -222,20 +320,60 @@ impl Server {
 
     fn readable(&mut self, token: Token) -> io::Result<()> {
         while let Some(message) = self.find_connection_by_token(token).readable()? {
+            let framed = message.first() == Some(&FRAME);
+            let (priority, program) = if framed {
+                match (message.get(1), message.get(2)) {
+                    (Some(&FRAME_REQUEST), _) => (Priority::Normal, Vec::from(&message[2..])),
+                    (Some(&FRAME_PRIORITY), Some(&class)) =>
+                        (class_priority(class, self.allow_high_priority), Vec::from(&message[3..])),
+                    (Some(&FRAME_CANCEL), _) if message.len() == 10 => {
+                        self.cancel(token, BigEndian::read_u64(&message[2..10]));
+                        continue;
+                    },
+                    // unknown (or malformed) frames are ignored
+                    _ => continue,
+                }
+            } else {
+                (Priority::Normal, message)
+            };
             let id = EnvId::new();
+            let (number, framed) = {
+                let requests = self.requests.entry(token).or_insert_with(Requests::default);
+                requests.framed = requests.framed || framed;
+                requests.counter += 1;
+                requests.running.insert(requests.counter, id);
+                (requests.counter, requests.framed)
+            };
+            self.running.insert(id, (token, number));
+            if framed {
+                let mut payload = [0u8; 8];
+                BigEndian::write_u64(&mut payload, number);
+                let _ = self.find_connection_by_token(token).send_message(Rc::new(frame(FRAME_ACCEPTED, &payload)));
+            }
             let session = self.token_session.get(&token).unwrap();
-            let _ = self.senders.schedule_env(id,
-                                                 message,
-                                                 self.response_sender.clone(),
-                                                 Box::new(RelayedPublishedMessageSender {
-                                                     identifier: session.to_vec(),
-                                                     sender: self.relay_sender.clone(),
-                                                 }));
+            let _ = self.senders.schedule_env_with_priority(id,
+                                                            program,
+                                                            priority,
+                                                            self.response_sender.clone(),
+                                                            Box::new(RelayedPublishedMessageSender {
+                                                                identifier: session.to_vec(),
+                                                                sender: self.relay_sender.clone(),
+                                                            }));
         }
 
         Ok(())
     }
 
+    // Cancels a running script, which will fail (and
+    // be forgotten) like any other script
+    fn cancel(&mut self, token: Token, number: u64) {
+        let requests = self.requests.entry(token).or_insert_with(Requests::default);
+        requests.framed = true;
+        if let Some(id) = requests.running.get(&number) {
+            self.senders.cancel(*id);
+        }
+    }
+
     fn find_connection_by_token(&mut self, token: Token) -> &mut Connection {
         &mut self.conns[token]
     }
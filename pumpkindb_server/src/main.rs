@@ -18,17 +18,19 @@ extern crate mio;
 
 extern crate pumpkindb_mio_server as server;
 
-use pumpkindb_engine::{script, storage, timestamp, lmdb};
+use pumpkindb_engine::{script, storage, compression, encryption, fsck, dump, timestamp, lmdb};
 use pumpkindb_engine::script::dispatcher;
 
-use clap::{App, Arg};
+use clap::{App, Arg, SubCommand};
 
 use std::thread;
 
 use std::fs;
 use std::fs::OpenOptions;
+use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
 use mio::channel as mio_chan;
 
@@ -46,12 +48,103 @@ lazy_static! {
           ::std::process::exit(1);
        }
     }
-    storage::create_environment(storage_path, map_size, maxreaders)
+    let maxdbs = config::get_int("storage.maxdbs").and_then(|v| Some(v as u32));
+    if let Some(max) = maxdbs {
+       if max < 1 {
+          error!("storage.maxdbs can't be less than 1");
+          ::std::process::exit(1);
+       }
+    }
+    storage::create_environment(storage_path, map_size, maxreaders, maxdbs)
  };
 }
 
 use pumpkindb_engine::nvmem::{MmapedFile, MmapedRegion};
 
+/// Returns the path of the directory to keep stream keys in
+fn key_store_path() -> PathBuf {
+    match config::get_str("storage.key_store") {
+        Some(path) => PathBuf::from(path.into_owned()),
+        None => PathBuf::from(config::get_str("storage.path").unwrap().into_owned()).join("keys"),
+    }
+}
+
+/// Splits a comma-separated configuration value, skipping empty items
+fn comma_separated(value: &str) -> Vec<Vec<u8>> {
+    value.split(',').map(|item| item.trim()).filter(|item| item.len() > 0)
+        .map(|item| Vec::from(item.as_bytes())).collect()
+}
+
+/// Returns the configured rules of transparent compression
+fn compression_rules() -> compression::Rules {
+    let mut rules = compression::Rules::default();
+    if let Some(keyspaces) = config::get_str("storage.compress_keyspaces") {
+        rules.keyspaces = comma_separated(&keyspaces);
+    }
+    if let Some(prefixes) = config::get_str("storage.compress_prefixes") {
+        rules.prefixes = comma_separated(&prefixes);
+    }
+    rules
+}
+
+/// Returns the configured encryption of `keys`' streams, or `None`
+/// if there are no streams configured
+fn encryption_config(keys: encryption::KeyStore) -> Option<encryption::Encryption> {
+    let prefixes = match config::get_str("storage.encrypt_prefixes") {
+        Some(prefixes) => comma_separated(&prefixes),
+        None => return None,
+    };
+    if prefixes.is_empty() {
+        error!("storage.encrypt_prefixes can't be empty");
+        ::std::process::exit(1);
+    }
+    let mut encryption = encryption::Encryption::new(keys);
+    encryption.prefixes = prefixes;
+    if let Some(delimiter) = config::get_str("storage.stream_delimiter") {
+        if delimiter.len() != 1 {
+            error!("storage.stream_delimiter should be a single byte");
+            ::std::process::exit(1);
+        }
+        encryption.delimiter = Some(delimiter.as_bytes()[0]);
+    }
+    Some(encryption)
+}
+
+/// Parses a key given on the command line, `0x`-prefixed
+/// keys are hexadecimal
+fn key_argument(value: &str) -> Vec<u8> {
+    if value.starts_with("0x") && value.len() % 2 == 0 {
+        let hex = &value[2..];
+        let bytes: Result<Vec<u8>, _> = (0..hex.len() / 2)
+            .map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)).collect();
+        if let Ok(bytes) = bytes {
+            return bytes;
+        }
+    }
+    Vec::from(value.as_bytes())
+}
+
+/// Opens the keyspace named on the command line, or the default one
+fn keyspace_argument(name: Option<&str>, create: bool) -> lmdb::Database<'static> {
+    let options = if create {
+        lmdb::DatabaseOptions::new(lmdb::db::CREATE)
+    } else {
+        lmdb::DatabaseOptions::defaults()
+    };
+    let db = match name {
+        // the default keyspace is stored in a named database, too
+        None => storage::default_keyspace(&ENVIRONMENT),
+        Some(_) => lmdb::Database::open(&ENVIRONMENT, name, &options),
+    };
+    match db {
+        Ok(db) => db,
+        Err(err) => {
+            println!("Keyspace can't be opened: {}", err);
+            ::std::process::exit(1);
+        }
+    }
+}
+
 pub fn main() {
     let args = App::new("PumpkinDB Server")
         .version(crate_version!())
@@ -65,18 +158,158 @@ pub fn main() {
             .short("c")
             .default_value("pumpkindb.toml")
             .takes_value(true))
+        .subcommand(SubCommand::with_name("backup")
+            .about("Copies a consistent snapshot of the database (can be used while the server is running)")
+            .arg(Arg::with_name("path")
+                .help("Target directory")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("compact")
+                .help("Omit free pages from the copy")
+                .long("compact")))
+        .subcommand(SubCommand::with_name("fsck")
+            .about("Checks the integrity of the database (the server should not be running)"))
+        .subcommand(SubCommand::with_name("export")
+            .about("Writes key/value pairs of a keyspace into a file (can be used while the server is running)")
+            .arg(Arg::with_name("path")
+                .help("Target file (- for standard output)")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("keyspace")
+                .help("Keyspace to export (the default one if not specified)")
+                .long("keyspace")
+                .takes_value(true))
+            .arg(Arg::with_name("prefix")
+                .help("Only export keys starting with this prefix (0x-prefixed if hexadecimal)")
+                .long("prefix")
+                .takes_value(true))
+            .arg(Arg::with_name("from")
+                .help("Only export keys greater than or equal to this one (0x-prefixed if hexadecimal)")
+                .long("from")
+                .takes_value(true))
+            .arg(Arg::with_name("to")
+                .help("Only export keys less than this one (0x-prefixed if hexadecimal)")
+                .long("to")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("import")
+            .about("Writes key/value pairs from an exported file into a keyspace, existing keys are not overwritten")
+            .arg(Arg::with_name("path")
+                .help("Source file (- for standard input)")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("keyspace")
+                .help("Keyspace to import into, created if necessary (the default one if not specified)")
+                .long("keyspace")
+                .takes_value(true))
+            .arg(Arg::with_name("batch")
+                .help("Number of pairs to write in one transaction")
+                .long("batch")
+                .takes_value(true)))
         .get_matches();
     let _ = config::merge(config::Environment::new("pumpkindb"));
     let _ = config::merge(config::File::new(args.value_of("config").unwrap(),
                                             config::FileFormat::Toml));
     let _ = config::set_default("server.port", 9981);
     let _ = config::set_default("storage.path", "pumpkin.db");
+
+    if let Some(backup) = args.subcommand_matches("backup") {
+        let path = backup.value_of("path").unwrap();
+        match storage::backup(&ENVIRONMENT, path, backup.is_present("compact")) {
+            Ok(()) => {
+                println!("Backup has been written to {}", path);
+                return;
+            },
+            Err(err) => {
+                println!("Backup failed: {}", err);
+                ::std::process::exit(1);
+            }
+        }
+    }
+
+    if args.subcommand_matches("fsck").is_some() {
+        let storage_path = PathBuf::from(config::get_str("storage.path").unwrap().into_owned());
+        let maxdbs = config::get_int("storage.maxdbs").and_then(|v| Some(v as u32));
+        // the key store is not created if it doesn't exist
+        let encryption = encryption_config(encryption::KeyStore::open_existing(key_store_path()));
+        let report = fsck::check(&storage_path, maxdbs, &compression_rules(), encryption.as_ref());
+        print!("{}", report);
+        if !report.problems.is_empty() {
+            ::std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(export) = args.subcommand_matches("export") {
+        let db = keyspace_argument(export.value_of("keyspace"), false);
+        let range = dump::Range {
+            prefix: export.value_of("prefix").map(key_argument),
+            from: export.value_of("from").map(key_argument),
+            to: export.value_of("to").map(key_argument),
+        };
+        let path = export.value_of("path").unwrap();
+        let mut writer: Box<Write> = if path == "-" {
+            Box::new(BufWriter::new(io::stdout()))
+        } else {
+            match fs::File::create(path) {
+                Ok(file) => Box::new(BufWriter::new(file)),
+                Err(err) => {
+                    println!("Export failed: {}", err);
+                    ::std::process::exit(1);
+                }
+            }
+        };
+        match dump::export(&ENVIRONMENT, &db, &range, &mut writer) {
+            Ok(count) => {
+                // standard output is occupied by the export itself
+                let _ = writeln!(io::stderr(), "Exported {} pairs", count);
+                return;
+            },
+            Err(err) => {
+                let _ = writeln!(io::stderr(), "Export failed: {}", err);
+                ::std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(import) = args.subcommand_matches("import") {
+        let batch_size = match import.value_of("batch").map(|batch| batch.parse::<usize>()) {
+            None => dump::DEFAULT_BATCH_SIZE,
+            Some(Ok(batch)) if batch > 0 => batch,
+            Some(_) => {
+                println!("Batch size should be a positive number");
+                ::std::process::exit(1);
+            }
+        };
+        let db = keyspace_argument(import.value_of("keyspace"), true);
+        let path = import.value_of("path").unwrap();
+        let mut reader: Box<Read> = if path == "-" {
+            Box::new(BufReader::new(io::stdin()))
+        } else {
+            match fs::File::open(path) {
+                Ok(file) => Box::new(BufReader::new(file)),
+                Err(err) => {
+                    println!("Import failed: {}", err);
+                    ::std::process::exit(1);
+                }
+            }
+        };
+        match dump::import(&ENVIRONMENT, &db, &mut reader, batch_size) {
+            Ok(report) => {
+                print!("{}", report);
+                return;
+            },
+            Err(err) => {
+                println!("Import failed: {}", err);
+                ::std::process::exit(1);
+            }
+        }
+    }
+
     let storage_path = config::get_str("storage.path").unwrap().into_owned();
     fs::create_dir_all(storage_path.as_str()).expect("can't create directory");
 
     let mut nvmem_pathbuf = PathBuf::from(storage_path);
-    nvmem_pathbuf.push("nvmem");
-    nvmem_pathbuf.set_extension("dat");
+    nvmem_pathbuf.push(storage::NVMEM_FILE);
     let mut nvmem = MmapedFile::new(nvmem_pathbuf, 20).unwrap();
     let nvmem_hlc = nvmem.claim(20).unwrap();
 
@@ -108,30 +341,136 @@ pub fn main() {
 
     info!("Starting up");
 
-    let mut senders = Vec::new();
 
     let (relay_sender, relay_receiver) = mio_chan::channel();
     let mut client_messaging = pumpkindb_engine::messaging::Simple::new();
     let publisher_accessor = client_messaging.accessor();
     let subscriber_accessor = client_messaging.accessor();
     let _ = thread::spawn(move || client_messaging.run());
-    let storage = Arc::new(storage::Storage::new(&ENVIRONMENT));
+    let mut storage = storage::Storage::new(&ENVIRONMENT);
+    if let Some(max_write_wait) = config::get_int("storage.max_write_wait") {
+        if max_write_wait < 0 {
+            error!("storage.max_write_wait can't be negative");
+            ::std::process::exit(1);
+        }
+        storage.max_write_wait = Some(Duration::from_millis(max_write_wait as u64));
+    }
+    if let Some(group_commit) = config::get_int("storage.group_commit") {
+        if group_commit < 1 {
+            error!("storage.group_commit can't be less than 1");
+            ::std::process::exit(1);
+        }
+        storage.group_commit = Some(group_commit as usize);
+    }
+    if let Some(mapsize_step) = config::get_int("storage.mapsize_step") {
+        if mapsize_step < 1 {
+            error!("storage.mapsize_step can't be less than 1");
+            ::std::process::exit(1);
+        }
+        storage.map_size_step = Some(mapsize_step as usize * 1024 * 1024);
+    }
+    if let Some(cdc_topic) = config::get_str("storage.cdc_topic") {
+        if cdc_topic.len() == 0 {
+            error!("storage.cdc_topic can't be empty");
+            ::std::process::exit(1);
+        }
+        storage.cdc_topic = Some(cdc_topic.into_owned().into_bytes());
+    }
+    if let Some(backup_dir) = config::get_str("storage.backup_dir") {
+        if backup_dir.len() == 0 {
+            error!("storage.backup_dir can't be empty");
+            ::std::process::exit(1);
+        }
+        storage.backup_dir = Some(PathBuf::from(backup_dir.into_owned()));
+    }
+    storage.allow_define = config::get_bool("server.allow_define").unwrap_or(false);
+    storage.compression = compression_rules();
+    // encryption is only enabled (and the key store only created) if there are streams
+    if config::get_str("storage.encrypt_prefixes").is_some() {
+        let keys = match encryption::KeyStore::open(key_store_path()) {
+            Ok(keys) => keys,
+            Err(err) => {
+                error!("can't open key store: {}", err);
+                ::std::process::exit(1);
+            }
+        };
+        let encryption = encryption_config(keys).unwrap();
+        if let Err(err) = storage.enable_encryption(encryption) {
+            error!("can't enable encryption: {}", err);
+            ::std::process::exit(1);
+        }
+    }
+    let storage = Arc::new(storage);
     let timestamp = Arc::new(timestamp::Timestamp::new(nvmem_hlc));
 
+    let mut limits = script::Limits::default();
+    if let Some(max_instructions) = config::get_int("scheduler.max_instructions") {
+        if max_instructions < 1 {
+            error!("scheduler.max_instructions can't be less than 1");
+            ::std::process::exit(1);
+        }
+        limits.max_instructions = Some(max_instructions as u64);
+    }
+    if let Some(max_duration) = config::get_int("scheduler.max_duration") {
+        if max_duration < 1 {
+            error!("scheduler.max_duration can't be less than 1");
+            ::std::process::exit(1);
+        }
+        limits.max_duration = Some(Duration::from_millis(max_duration as u64));
+    }
+    if let Some(max_memory) = config::get_int("scheduler.max_memory") {
+        if max_memory < 1 {
+            error!("scheduler.max_memory can't be less than 1");
+            ::std::process::exit(1);
+        }
+        limits.max_memory = Some(max_memory as usize * 1024 * 1024);
+    }
+
     let cpus = num_cpus::get();
+    let mut pool_sizes = Vec::new();
+    for &(priority, key) in [(script::Priority::High, "scheduler.high_priority_pool"),
+                             (script::Priority::Low, "scheduler.low_priority_pool")].iter() {
+        if let Some(size) = config::get_int(key) {
+            if size < 0 {
+                error!("{} can't be negative", key);
+                ::std::process::exit(1);
+            }
+            pool_sizes.push((priority, size as usize));
+        }
+    }
+    let reserved = pool_sizes.iter().map(|&(_, size)| size).sum::<usize>();
+    if reserved >= cpus {
+        error!("scheduler pools need to leave at least one of {} schedulers for normal priority", cpus);
+        ::std::process::exit(1);
+    }
+    pool_sizes.push((script::Priority::Normal, cpus - reserved));
+
     info!("Starting {} schedulers", cpus);
-    for i in 0..cpus {
-        debug!("Starting scheduler on core {}.", i);
+    let mut pools = script::SchedulerPools::new(Vec::new());
+    for (priority, size) in pool_sizes {
+        let mut schedulers = Vec::new();
+        for _ in 0..size {
+            debug!("Starting scheduler for {:?} priority.", priority);
 
-        let (mut scheduler, sender) =
-            script::Scheduler::new(
-                dispatcher::StandardDispatcher::new(storage.clone(),
-                                                    publisher_accessor.clone(), subscriber_accessor.clone(),
-                                                    timestamp.clone()));
-        thread::spawn(move || scheduler.run());
-        senders.push(sender);
+            let (mut scheduler, _) =
+                script::Scheduler::new(
+                    dispatcher::StandardDispatcher::new(storage.clone(),
+                                                        publisher_accessor.clone(), subscriber_accessor.clone(),
+                                                        timestamp.clone()));
+            scheduler.limits = limits;
+            schedulers.push(scheduler);
+        }
+        // idle schedulers steal work from busy ones within the same pool
+        let peers: Vec<_> = schedulers.iter().map(|scheduler| scheduler.peer()).collect();
+        let senders = schedulers.iter().map(|scheduler| scheduler.sender()).collect();
+        for mut scheduler in schedulers {
+            scheduler.join(peers.clone());
+            thread::spawn(move || scheduler.run());
+        }
+        pools.insert(priority, senders);
     }
 
     server::run(config::get_int("server.port").unwrap(),
-                senders, relay_sender, relay_receiver);
+                pools, relay_sender, relay_receiver,
+                config::get_bool("server.allow_high_priority").unwrap_or(false));
 }
\ No newline at end of file
@@ -11,6 +11,7 @@ extern crate ansi_term;
 extern crate uuid;
 #[macro_use]
 extern crate clap;
+extern crate serde_json;
 
 extern crate pumpkinscript;
 extern crate pumpkindb_engine;
@@ -50,6 +51,18 @@ fn print_item(s: &mut String, data: &[u8]) {
     }
 }
 
+fn print_stats(s: &mut String, data: &[u8]) {
+    match serde_json::from_slice::<serde_json::Value>(data) {
+        Ok(serde_json::Value::Object(map)) => {
+            let _ = write!(s, "\n");
+            for (name, value) in map {
+                let _ = write!(s, "  {:<16}{}\n", name, value);
+            }
+        },
+        _ => print_item(s, data),
+    }
+}
+
 fn main() {
 
     let args = App::new("PumpkinDB Terminal")
@@ -87,16 +100,21 @@ fn main() {
         match rl.readline(current_prompt) {
             Ok(text) => {
                 let mut program = String::new();
+                let mut stats = false;
                 let text_str = text.as_str();
                 let text_bytes = text_str.as_bytes();
                 if text_bytes.len() >= 2 && text_bytes[0] == b'\\' {
                     if text_bytes[1] == b'h' {
                         println!("\nTo send an expression, end it with `.`");
                         println!("To trace a value in the script use TRACE instruction");
+                        println!("To see storage statistics, type \\stats");
                         println!("To quit, hit ^D");
                         println!("Further help online at http://pumpkindb.org/doc/");
                         println!("Missing a feature? Let us know at \
                                   https://github.com/PumpkinDB/PumpkinDB/issues/\n");
+                    } else if text_str == "\\stats" {
+                        program.push_str("$SYSTEM/STATS [$SYSTEM/DBSTATS] READ");
+                        stats = true;
                     }
                 } else if text_str.len() > 0 && text_bytes[text_str.len() - 1] == 46u8 {
                     let rest = str::from_utf8(&text.as_bytes()[..text_str.len() - 1]).unwrap();
@@ -112,7 +130,9 @@ fn main() {
                     current_prompt = "..> ";
                 }
                 if program.len() > 0 {
-                    rl.add_history_entry(format!("{}.", &program).as_str());
+                    if !stats {
+                        rl.add_history_entry(format!("{}.", &program).as_str());
+                    }
                     match pumpkinscript::parse(&program) {
                         Ok(compiled) => {
                             let uuid = Uuid::new_v4();
@@ -200,6 +220,8 @@ fn main() {
                                                             }
                                                             input = Vec::from(data);
                                                         }
+                                                    } else if stats {
+                                                        print_stats(&mut s, data);
                                                     } else {
                                                         print_item(&mut s, data);
                                                     }
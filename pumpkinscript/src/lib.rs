@@ -81,6 +81,8 @@ pub use packable::{Packable, Unpackable};
 
 pub mod encodables;
 
+pub mod tuple;
+
 pub use self::encodables::{Encodable, Instruction, InstructionRef, Closure, Receivable};
 
 use std::fmt;
@@ -0,0 +1,348 @@
+// Copyright (c) 2017, All Contributors (see CONTRIBUTORS file)
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! # Tuple encoding
+//!
+//! Order-preserving encoding of tuples (in the spirit of FoundationDB's tuple layer)
+//! suitable for composite keys: byte-wise (lexicographic) order of packed tuples
+//! matches the order of the tuples themselves, element by element.
+//!
+//! Every element is prefixed with a type code. Binaries and strings are terminated
+//! with `0x00` (with any `0x00` inside escaped as `0x00 0xFF`), so variable-length
+//! elements don't affect ordering of the elements that follow them. Sized numbers use
+//! the fixed-width order-preserving forms of [`Packable`](../trait.Packable.html),
+//! UUIDs and HLC timestamps are stored as is.
+//!
+//! Elements of different types are ordered by their type code.
+
+use std::str;
+
+use super::{Packable, Unpackable};
+
+const BINARY: u8 = 0x01;
+const STRING: u8 = 0x02;
+const U8: u8 = 0x10;
+const U16: u8 = 0x11;
+const U32: u8 = 0x12;
+const U64: u8 = 0x13;
+const I8: u8 = 0x14;
+const I16: u8 = 0x15;
+const I32: u8 = 0x16;
+const I64: u8 = 0x17;
+const F32: u8 = 0x20;
+const F64: u8 = 0x21;
+const UUID: u8 = 0x30;
+const HLC: u8 = 0x31;
+
+// Never used as a type code, so it sorts after any tuple extending a prefix
+const PREFIX_END: u8 = 0xFF;
+
+/// Tuple element
+#[derive(Debug, Clone, PartialEq)]
+pub enum Element {
+    Binary(Vec<u8>),
+    String(String),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    I8(i8),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    /// UUID (16 bytes)
+    Uuid([u8; 16]),
+    /// HLC timestamp (16 bytes, as produced by `HLC`)
+    Hlc([u8; 16]),
+}
+
+macro_rules! element_from {
+    ($type: ty, $variant: ident) => {
+        impl From<$type> for Element {
+            fn from(v: $type) -> Element {
+                Element::$variant(v)
+            }
+        }
+    }
+}
+
+element_from!(Vec<u8>, Binary);
+element_from!(String, String);
+element_from!(u8, U8);
+element_from!(u16, U16);
+element_from!(u32, U32);
+element_from!(u64, U64);
+element_from!(i8, I8);
+element_from!(i16, I16);
+element_from!(i32, I32);
+element_from!(i64, I64);
+element_from!(f32, F32);
+element_from!(f64, F64);
+
+impl<'a> From<&'a [u8]> for Element {
+    fn from(v: &'a [u8]) -> Element {
+        Element::Binary(Vec::from(v))
+    }
+}
+
+impl<'a> From<&'a str> for Element {
+    fn from(v: &'a str) -> Element {
+        Element::String(String::from(v))
+    }
+}
+
+fn array16(bytes: &[u8]) -> Option<[u8; 16]> {
+    if bytes.len() != 16 {
+        return None
+    }
+    let mut array = [0; 16];
+    array.copy_from_slice(bytes);
+    Some(array)
+}
+
+impl Element {
+    /// Name of the element's type, as used by `TUPLE/PACK` and `TUPLE/UNPACK`
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            &Element::Binary(_) => "binary",
+            &Element::String(_) => "string",
+            &Element::U8(_) => "u8",
+            &Element::U16(_) => "u16",
+            &Element::U32(_) => "u32",
+            &Element::U64(_) => "u64",
+            &Element::I8(_) => "i8",
+            &Element::I16(_) => "i16",
+            &Element::I32(_) => "i32",
+            &Element::I64(_) => "i64",
+            &Element::F32(_) => "f32",
+            &Element::F64(_) => "f64",
+            &Element::Uuid(_) => "uuid",
+            &Element::Hlc(_) => "hlc",
+        }
+    }
+
+    /// Element's value the way PumpkinScript represents it (for example,
+    /// `1u32` or `-1.5f64`)
+    pub fn value(&self) -> Vec<u8> {
+        match self {
+            &Element::Binary(ref v) => v.clone(),
+            &Element::String(ref v) => Vec::from(v.as_bytes()),
+            &Element::U8(v) => v.pack(),
+            &Element::U16(v) => v.pack(),
+            &Element::U32(v) => v.pack(),
+            &Element::U64(v) => v.pack(),
+            &Element::I8(v) => v.pack(),
+            &Element::I16(v) => v.pack(),
+            &Element::I32(v) => v.pack(),
+            &Element::I64(v) => v.pack(),
+            &Element::F32(v) => v.pack(),
+            &Element::F64(v) => v.pack(),
+            &Element::Uuid(ref v) | &Element::Hlc(ref v) => Vec::from(&v[..]),
+        }
+    }
+
+    /// Creates an element from a type name and a PumpkinScript value. Returns `None`
+    /// if the type is unknown or the value doesn't fit it.
+    pub fn from_value(type_name: &[u8], value: &[u8]) -> Option<Element> {
+        let size = match type_name {
+            b"binary" => return Some(Element::Binary(Vec::from(value))),
+            b"string" => return str::from_utf8(value).ok().map(|s| Element::String(String::from(s))),
+            b"u8" | b"i8" => 1,
+            b"u16" | b"i16" => 2,
+            b"u32" | b"i32" | b"f32" => 4,
+            b"u64" | b"i64" | b"f64" => 8,
+            b"uuid" | b"hlc" => 16,
+            _ => return None,
+        };
+        if value.len() != size {
+            return None
+        }
+        match type_name {
+            b"u8" => value.unpack().map(Element::U8),
+            b"u16" => value.unpack().map(Element::U16),
+            b"u32" => value.unpack().map(Element::U32),
+            b"u64" => value.unpack().map(Element::U64),
+            b"i8" => value.unpack().map(Element::I8),
+            b"i16" => value.unpack().map(Element::I16),
+            b"i32" => value.unpack().map(Element::I32),
+            b"i64" => value.unpack().map(Element::I64),
+            b"f32" => value.unpack().map(Element::F32),
+            b"f64" => value.unpack().map(Element::F64),
+            b"uuid" => array16(value).map(Element::Uuid),
+            _ => array16(value).map(Element::Hlc),
+        }
+    }
+
+    fn type_code(&self) -> u8 {
+        match self {
+            &Element::Binary(_) => BINARY,
+            &Element::String(_) => STRING,
+            &Element::U8(_) => U8,
+            &Element::U16(_) => U16,
+            &Element::U32(_) => U32,
+            &Element::U64(_) => U64,
+            &Element::I8(_) => I8,
+            &Element::I16(_) => I16,
+            &Element::I32(_) => I32,
+            &Element::I64(_) => I64,
+            &Element::F32(_) => F32,
+            &Element::F64(_) => F64,
+            &Element::Uuid(_) => UUID,
+            &Element::Hlc(_) => HLC,
+        }
+    }
+
+    fn pack_into(&self, vec: &mut Vec<u8>) {
+        vec.push(self.type_code());
+        let value = self.value();
+        match self {
+            &Element::Binary(_) | &Element::String(_) => {
+                for byte in value {
+                    vec.push(byte);
+                    if byte == 0x00 {
+                        vec.push(0xFF);
+                    }
+                }
+                vec.push(0x00);
+            },
+            _ => vec.extend_from_slice(&value),
+        }
+    }
+}
+
+/// Packs a tuple
+pub fn pack(tuple: &[Element]) -> Vec<u8> {
+    let mut vec = Vec::new();
+    for element in tuple {
+        element.pack_into(&mut vec);
+    }
+    vec
+}
+
+/// Unpacks a tuple. Returns `None` if `bytes` is not a valid packed tuple.
+pub fn unpack(bytes: &[u8]) -> Option<Vec<Element>> {
+    let mut tuple = Vec::new();
+    let mut input = bytes;
+    while input.len() > 0 {
+        let code = input[0];
+        input = &input[1..];
+        let (type_name, size): (&[u8], usize) = match code {
+            BINARY | STRING => {
+                let mut value = Vec::new();
+                loop {
+                    match (input.get(0), input.get(1)) {
+                        (Some(&0x00), Some(&0xFF)) => {
+                            value.push(0x00);
+                            input = &input[2..];
+                        },
+                        (Some(&0x00), _) => {
+                            input = &input[1..];
+                            break;
+                        },
+                        (Some(&byte), _) => {
+                            value.push(byte);
+                            input = &input[1..];
+                        },
+                        (None, _) => return None,
+                    }
+                }
+                let type_name: &[u8] = if code == BINARY { b"binary" } else { b"string" };
+                match Element::from_value(type_name, &value) {
+                    Some(element) => tuple.push(element),
+                    None => return None,
+                }
+                continue;
+            },
+            U8 => (b"u8", 1),
+            U16 => (b"u16", 2),
+            U32 => (b"u32", 4),
+            U64 => (b"u64", 8),
+            I8 => (b"i8", 1),
+            I16 => (b"i16", 2),
+            I32 => (b"i32", 4),
+            I64 => (b"i64", 8),
+            F32 => (b"f32", 4),
+            F64 => (b"f64", 8),
+            UUID => (b"uuid", 16),
+            HLC => (b"hlc", 16),
+            _ => return None,
+        };
+        if input.len() < size {
+            return None
+        }
+        match Element::from_value(type_name, &input[0..size]) {
+            Some(element) => tuple.push(element),
+            None => return None,
+        }
+        input = &input[size..];
+    }
+    Some(tuple)
+}
+
+/// Returns a range of keys (inclusive start and exclusive end) that covers
+/// all packed tuples starting with `prefix` (including `prefix` itself)
+pub fn prefix_range(prefix: &[Element]) -> (Vec<u8>, Vec<u8>) {
+    let start = pack(prefix);
+    let mut end = start.clone();
+    end.push(PREFIX_END);
+    (start, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let tuple = vec![Element::from("str\x00ing"), Element::from(vec![0x00, 0xFF, 0x01]),
+                         Element::from(1u8), Element::from(2u16), Element::from(3u32),
+                         Element::from(4u64), Element::from(-1i8), Element::from(-2i16),
+                         Element::from(-3i32), Element::from(-4i64), Element::from(-1.5f32),
+                         Element::from(2.5f64), Element::Uuid([1; 16]), Element::Hlc([2; 16])];
+        assert_eq!(unpack(&pack(&tuple)).unwrap(), tuple);
+    }
+
+    #[test]
+    fn ordering() {
+        let ordered = vec![
+            vec![Element::from("a")],
+            vec![Element::from("a"), Element::from(-1i32)],
+            vec![Element::from("a"), Element::from(1i32)],
+            vec![Element::from("a\x00")],
+            vec![Element::from("aa"), Element::from(-100i64)],
+            vec![Element::from("b")],
+            vec![Element::from(2u8), Element::from("a")],
+            vec![Element::from(10u8)],
+            vec![Element::from(-2.5f64)],
+            vec![Element::from(0.5f64)],
+        ];
+        for pair in ordered.windows(2) {
+            assert!(pack(&pair[0]) < pack(&pair[1]), "{:?} < {:?}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn prefix() {
+        let (start, end) = prefix_range(&[Element::from("a")]);
+        assert!(pack(&[Element::from("a")]) >= start);
+        assert!(pack(&[Element::from("a"), Element::from(1u64)]) < end);
+        assert!(pack(&[Element::from("a"), Element::Hlc([0xFF; 16])]) < end);
+        assert!(pack(&[Element::from("aa")]) >= end);
+        assert!(pack(&[Element::from("")]) < start);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(unpack(b"\x01abc").is_none());
+        assert!(unpack(b"\x12\x00\x00").is_none());
+        assert!(unpack(b"\xFE").is_none());
+        assert!(unpack(b"\x02\xFF\x00").is_none());
+        assert!(Element::from_value(b"u32", b"\x00").is_none());
+        assert!(Element::from_value(b"unknown", b"").is_none());
+    }
+}
@@ -29,20 +29,27 @@ use tempdir::TempDir;
 
 use pumpkindb_engine::script::{SchedulerHandle, ResponseMessage, EnvId, Env, Scheduler, dispatcher};
 use pumpkinscript::{textparser, binparser};
-use pumpkindb_engine::{messaging, storage, timestamp, nvmem};
+use pumpkindb_engine::{messaging, storage, encryption, timestamp, nvmem};
 
 fn eval(name: &[u8], script: &[u8], timestamp: Arc<timestamp::Timestamp<nvmem::MmapedRegion>>) {
     let dir = TempDir::new("pumpkindb").unwrap();
     let path = dir.path().to_str().unwrap();
     fs::create_dir_all(path).expect("can't create directory");
     let env = unsafe {
-        lmdb::EnvBuilder::new()
-            .expect("can't create env builder")
-            .open(path, lmdb::open::NOTLS, 0o600)
-            .expect("can't open env")
+        let mut builder = lmdb::EnvBuilder::new().expect("can't create env builder");
+        builder.set_maxdbs(storage::DEFAULT_MAXDBS).expect("can't set maxdbs");
+        builder.open(path, lmdb::open::NOTLS, 0o600).expect("can't open env")
     };
     let name = String::from(std::str::from_utf8(name).unwrap());
-    let db = Arc::new(storage::Storage::new(&env));
+    let mut storage = storage::Storage::new(&env);
+    storage.compression.prefixes = vec![Vec::from("compressed/")];
+    storage.allow_define = true;
+    let keys = encryption::KeyStore::open(dir.path().join("keys")).expect("can't open key store");
+    let mut encryption = encryption::Encryption::new(keys);
+    encryption.prefixes = vec![Vec::from("user/")];
+    encryption.delimiter = Some(b'/');
+    storage.enable_encryption(encryption).expect("can't enable encryption");
+    let db = Arc::new(storage);
     crossbeam::scope(|scope| {
         let mut simple = messaging::Simple::new();
         let simple_accessor = simple.accessor();
@@ -59,7 +66,7 @@ fn eval(name: &[u8], script: &[u8], timestamp: Arc<timestamp::Timestamp<nvmem::M
         sender.schedule_env(EnvId::new(), Vec::from(script), callback,
                                                         Box::new(sender0));
         match receiver.recv() {
-            Ok(ResponseMessage::EnvTerminated(_, stack, stack_size)) => {
+            Ok(ResponseMessage::EnvTerminated(_, stack, stack_size, _)) => {
                 sender.shutdown();
                 simple_accessor.shutdown();
                 let mut stack_ = Vec::with_capacity(stack.len());
@@ -74,7 +81,7 @@ fn eval(name: &[u8], script: &[u8], timestamp: Arc<timestamp::Timestamp<nvmem::M
                            &name);
                 println!(" * {}", &name);
             }
-            Ok(ResponseMessage::EnvFailed(_, err, _, _)) => {
+            Ok(ResponseMessage::EnvFailed(_, err, _, _, _)) => {
                 sender.shutdown();
                 simple_accessor.shutdown();
                 panic!("Error while executing {:?}: {:?}", &name, err)